@@ -0,0 +1,145 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use snafu::{ResultExt, Snafu};
+use tempfile::TempDir;
+
+use crate::volumes::VolumeInitializer;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Unable to open the bundle: {}\n{}", file, source))]
+    OpenBundle {
+        file: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Unable to create a temporary directory for the bundle.\n{}", source))]
+    BundleDir { source: std::io::Error },
+
+    #[snafu(display("Unable to extract the bundle: {}\n{}", file, source))]
+    ExtractBundle {
+        file: String,
+        source: crate::volumes::Error,
+    },
+
+    #[snafu(display("The bundle: {} does not contain a handel.yml at its root", file))]
+    MissingConfig { file: String },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+pub const BUNDLE_CONFIG_FILE: &str = "handel.yml";
+
+/// A zip archive holding a `handel.yml` at its root alongside the templates it refers to,
+/// extracted into a temporary directory which is removed when the bundle is dropped.
+pub struct Bundle {
+    dir: TempDir,
+}
+
+impl Bundle {
+    pub fn open(path: &str) -> Result<Bundle> {
+        let file = File::open(path).context(OpenBundle {
+            file: path.to_string(),
+        })?;
+
+        let dir = tempfile::tempdir().context(BundleDir)?;
+
+        let volume = VolumeInitializer {
+            name: "bundle".to_string(),
+            source: path.to_string(),
+            target: dir.path().to_string_lossy().to_string(),
+            include: None,
+            stream: false,
+            sha256: None,
+        };
+
+        crate::volumes::extract_zip(file, &volume, dir.path()).context(ExtractBundle {
+            file: path.to_string(),
+        })?;
+
+        let bundle = Bundle { dir };
+
+        if !bundle.config_file().is_file() {
+            return Err(Error::MissingConfig {
+                file: path.to_string(),
+            });
+        }
+
+        Ok(bundle)
+    }
+
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn config_file(&self) -> PathBuf {
+        self.dir.path().join(BUNDLE_CONFIG_FILE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compose::{ComposeOptions, DockerCompose};
+    use crate::config::HandelConfig;
+    use crate::templates::ComposeServiceMap;
+    use crate::warnings::Warnings;
+    use std::io::Write;
+
+    fn write_bundle(path: &Path) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        let options = zip::write::FileOptions::default();
+
+        let files = [
+            ("handel.yml", "template-folder-path: templates\nscenarios:\n  app:\n    - api\n"),
+            ("templates/api.yml", "image: example/api:1.0\ndepends_on:\n  - db\n"),
+            ("templates/db.yml", "image: mysql:5.7\n"),
+        ];
+
+        for (name, contents) in files {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+
+        zip.finish().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_generate_from_bundle() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("setup.zip");
+        write_bundle(&path);
+
+        let bundle = Bundle::open(path.to_str().unwrap()).unwrap();
+        let config = HandelConfig::new(&[bundle.config_file().to_str().unwrap()], &Warnings::default())
+            .unwrap()
+            .relative_to(bundle.dir());
+
+        let templates = ComposeServiceMap::new(config.template_dir(), None, &Warnings::default()).await.unwrap();
+        let services = config.build_service_list(&["app"], &templates).unwrap();
+
+        let result =
+            DockerCompose::generate(&services, &[], &[], &ComposeOptions::default()).unwrap();
+        let doc: serde_yaml::Value = serde_yaml::from_str(&result).unwrap();
+
+        assert_eq!("example/api:1.0", doc["services"]["api"]["image"].as_str().unwrap());
+        assert_eq!("mysql:5.7", doc["services"]["db"]["image"].as_str().unwrap());
+
+        let extracted = bundle.dir().to_path_buf();
+        drop(bundle);
+        assert!(!extracted.exists());
+    }
+
+    #[test]
+    fn test_bundle_without_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("empty.zip");
+        zip::ZipWriter::new(File::create(&path).unwrap()).finish().unwrap();
+
+        assert!(matches!(
+            Bundle::open(path.to_str().unwrap()),
+            Err(Error::MissingConfig { .. })
+        ));
+    }
+}
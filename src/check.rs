@@ -0,0 +1,235 @@
+use crate::compose::{ComposeOptions, DockerCompose};
+use crate::config::HandelConfig;
+use crate::images::{ContainerImages, ImageSourceKind, SinceBasis};
+use crate::reference::RunningServices;
+use crate::templates::{host_ports, port_conflicts, ComposeServiceMap};
+use crate::volumes::{VolumeOptions, Volumes};
+use crate::warnings::{Category, Warnings};
+
+/// Collects the outcome of each validation performed by `handel check`.
+#[derive(Debug, Default)]
+struct CheckReport {
+    failures: usize,
+}
+
+impl CheckReport {
+    fn record(&mut self, check: &str, problems: Vec<String>) {
+        if problems.is_empty() {
+            println!("OK\t{}", check);
+        } else {
+            self.failures += 1;
+            println!(
+                "{}\t{}\n\t\t{}",
+                crate::colors::conflict("FAIL"),
+                check,
+                problems.join("\n\t\t")
+            );
+        }
+    }
+
+    fn finish(self) -> bool {
+        match self.failures {
+            0 => println!("\nAll checks passed"),
+            n => println!("\n{} check(s) failed", n),
+        };
+
+        self.failures == 0
+    }
+}
+
+/// The options of a run which affect what is generated for a check.
+#[derive(Debug, Default)]
+pub struct CheckOptions<'a> {
+    pub env: &'a str,
+    pub since: &'a str,
+    pub images_from: Option<&'a str>,
+    pub strict: bool,
+    pub container_cli: Option<ImageSourceKind>,
+    /// The file of the output template, which is rendered but not written.
+    pub output_template: Option<&'a str>,
+}
+
+/// Runs the generation pipeline for the given scenario up to, but not including, writing any
+/// output.  Returns true only if every check passes.
+pub async fn run(
+    config: Result<HandelConfig, crate::config::Error>,
+    scenario: &str,
+    options: &CheckOptions<'_>,
+    warnings: &Warnings,
+) -> bool {
+    let CheckOptions { env, strict, container_cli, .. } = *options;
+    let mut report = CheckReport::default();
+
+    let config = match config {
+        Ok(c) => c,
+        Err(e) => {
+            report.record("configuration", vec![e.to_string()]);
+            return report.finish();
+        }
+    };
+    report.record("configuration", vec![]);
+
+    let (config, _image_templates) = match crate::oci::resolve_templates(config, container_cli).await {
+        Ok(c) => c,
+        Err(e) => {
+            report.record("templates", vec![e.to_string()]);
+            return report.finish();
+        }
+    };
+
+    let templates = match ComposeServiceMap::new(config.template_dir(), config.get_port_range(), warnings).await {
+        Ok(t) => t,
+        Err(e) => {
+            report.record("templates", vec![e.to_string()]);
+            return report.finish();
+        }
+    };
+    report.record("templates", vec![]);
+
+    let scenario = config.resolve_scenario_alias(scenario);
+
+    if !config.has_scenario(scenario) {
+        report.record(
+            "scenario",
+            vec![format!(
+                "Unknown scenario: {}, expected one of: {}",
+                scenario,
+                config.get_scenarios().join(", ")
+            )],
+        );
+        return report.finish();
+    }
+
+    let services = match config.build_service_list(&[scenario], &templates) {
+        Ok(s) => s,
+        Err(e) => {
+            report.record("scenario", vec![e.to_string()]);
+            return report.finish();
+        }
+    };
+    report.record("scenario", vec![]);
+
+    let missing = templates
+        .missing_dependencies(&services)
+        .iter()
+        .map(|(s, d)| format!("{} depends on unknown service {}", s.name(), d))
+        .collect::<Vec<_>>();
+    report.record("dependencies", missing);
+
+    let conflicts = port_conflicts(&host_ports(services.iter().copied()))
+        .iter()
+        .map(|(port, svcs)| format!("{}\t{}", port, svcs.join(", ")))
+        .collect::<Vec<_>>();
+    report.record("host ports", conflicts);
+
+    let mut volume_options = VolumeOptions::new(config.variables(), env, scenario, strict);
+    volume_options.warnings = warnings.clone();
+    let volumes = match Volumes::validate(config.volumes(), &volume_options) {
+        Ok(_) => vec![],
+        Err(e) => vec![e.to_string()],
+    };
+    report.record("volumes", volumes);
+
+    let unseeded = crate::volumes::unseeded_named_volumes(&services, config.volumes())
+        .iter()
+        .map(|(s, v)| format!("{} uses the named volume {} which has no volume-init entry", s.name(), v))
+        .collect::<Vec<_>>();
+
+    match strict {
+        true => report.record("named volumes", unseeded),
+        false => unseeded.iter().for_each(|u| warnings.warn(Category::Volume, u, None, None)),
+    }
+
+    report.record("compose", generate(&config, scenario, &services, options, warnings).await);
+
+    report.finish()
+}
+
+/// Builds the compose document as a run would, with the running and local image versions,
+/// rendering the output template if one is given.  Returns any problems found.
+async fn generate(
+    config: &HandelConfig,
+    scenario: &str,
+    services: &[&crate::templates::ComposeService],
+    options: &CheckOptions<'_>,
+    warnings: &Warnings,
+) -> Vec<String> {
+    let image_sources = match options.container_cli {
+        Some(cli) => vec![cli],
+        None => config.image_sources(),
+    };
+
+    let (running, images) = tokio::join!(
+        RunningServices::load(options.env, config.get_reference(), warnings),
+        ContainerImages::find(options.since, SinceBasis::Created, options.images_from, &image_sources, None, None)
+    );
+
+    // As for a run, versions which can't be read are warned about rather than failing.
+    let running = running.unwrap_or_else(|e| {
+        let message = format!("Unable to fetch running versions data for {}\n{}", options.env, e);
+        warnings.warn(Category::Reference, &message, None, None);
+        Vec::new()
+    });
+
+    let images = images.unwrap_or_else(|e| {
+        let message = format!("Unable to read local container images from docker.\n{}", e);
+        warnings.warn(Category::LocalImages, &message, None, None);
+        Vec::new()
+    });
+
+    let template = match options.output_template {
+        Some(file) => match crate::utils::read_file_contents(std::path::Path::new(file)) {
+            Ok(t) => Some(t),
+            Err(e) => return vec![format!("Unable to read the output template: {}\n{}", file, e)],
+        },
+        None => None,
+    };
+
+    let compose_options = ComposeOptions::from_config(config, config.collect_pins(&[scenario.to_string()]), warnings);
+
+    let result = DockerCompose::build(services, &running, &images, &compose_options).and_then(|compose| {
+        let yaml = compose.to_yaml()?;
+        match &template {
+            Some(t) => compose.render_template(t, &yaml, scenario, options.env).map(|_| ()),
+            None => Ok(()),
+        }
+    });
+
+    match result {
+        Ok(_) => vec![],
+        Err(e) => vec![e.to_string()],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_check_builds_compose() {
+        let dir = tempfile::tempdir().unwrap();
+        let templates = dir.path().join("templates");
+        std::fs::create_dir(&templates).unwrap();
+        std::fs::write(templates.join("api.yml"), "image: api:1\nenv-groups: [ common ]\n").unwrap();
+
+        let options = CheckOptions {
+            env: "test",
+            since: "1h",
+            images_from: Some("/dev/null"),
+            ..Default::default()
+        };
+        let config = |groups: &str| {
+            let file = dir.path().join("handel.yml");
+            std::fs::write(
+                &file,
+                format!("template-folder-path: {}\nscenarios:\n  app:\n    - api\n{}", templates.display(), groups),
+            )
+            .unwrap();
+            HandelConfig::new(&[&file.to_string_lossy()], &Warnings::default())
+        };
+
+        // The undefined env group is only found when the compose document is built.
+        assert!(!run(config(""), "app", &options, &Warnings::default()).await);
+        assert!(run(config("env-groups:\n  common:\n    LOG_LEVEL: debug\n"), "app", &options, &Warnings::default()).await);
+    }
+}
@@ -0,0 +1,89 @@
+use owo_colors::OwoColorize;
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+
+/// Controls whether warnings and conflicts are highlighted with ANSI colours.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
+static STDOUT: OnceLock<bool> = OnceLock::new();
+static STDERR: OnceLock<bool> = OnceLock::new();
+
+/// Enables colour output for the process.  In auto mode colours are only used on a stream which
+/// is a terminal, so stdout piped to a file has none while logs to stderr may, and only when the
+/// NO_COLOR variable is not set.
+pub fn init(mode: ColorMode) {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    let _ = STDOUT.set(should_color(mode, no_color, std::io::stdout().is_terminal()));
+    let _ = STDERR.set(should_color(mode, no_color, std::io::stderr().is_terminal()));
+}
+
+fn should_color(mode: ColorMode, no_color: bool, is_tty: bool) -> bool {
+    match mode {
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+        ColorMode::Auto => is_tty && !no_color,
+    }
+}
+
+/// Whether colours are enabled for the logs written to stderr.
+pub fn stderr_enabled() -> bool {
+    *STDERR.get().unwrap_or(&false)
+}
+
+fn stdout_enabled() -> bool {
+    *STDOUT.get().unwrap_or(&false)
+}
+
+/// Highlights a warning message printed to stdout in yellow when colours are enabled for it.
+pub fn warning(message: &str) -> String {
+    paint_warning(message, stdout_enabled())
+}
+
+/// Highlights a conflict or failure message printed to stdout in red when colours are enabled
+/// for it.
+pub fn conflict(message: &str) -> String {
+    paint_conflict(message, stdout_enabled())
+}
+
+/// Highlights a warning message in yellow if enabled, e.g. for the stream it is written to.
+pub fn paint_warning(message: &str, enabled: bool) -> String {
+    match enabled {
+        true => message.yellow().to_string(),
+        false => message.to_string(),
+    }
+}
+
+/// Highlights a conflict or failure message in red if enabled.
+pub fn paint_conflict(message: &str, enabled: bool) -> String {
+    match enabled {
+        true => message.red().to_string(),
+        false => message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_color_never_has_no_escapes() {
+        let enabled = should_color(ColorMode::Never, false, true);
+
+        assert!(!paint_warning("Warning - stale image", enabled).contains('\u{1b}'));
+        assert!(!paint_conflict("9092\tkafka, other", enabled).contains('\u{1b}'));
+    }
+
+    #[test]
+    fn test_color_modes() {
+        assert!(should_color(ColorMode::Always, true, false));
+        assert!(should_color(ColorMode::Auto, false, true));
+        assert!(!should_color(ColorMode::Auto, true, true));
+        assert!(!should_color(ColorMode::Auto, false, false));
+        assert!(paint_conflict("conflict", true).contains('\u{1b}'));
+    }
+}
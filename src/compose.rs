@@ -1,41 +1,547 @@
+use crate::config::HandelConfig;
 use crate::images::ContainerImage;
 use crate::reference::RunningService;
-use crate::templates::{ComposeService, ComposeServiceFragment};
-use serde::Serialize;
+use crate::templates::{
+    ComposeService, ComposeServiceFragment, DependsOn, Healthcheck, HealthcheckTest, ImageVersion,
+    Logging, PortMapping, ServiceDefaults,
+};
+use crate::warnings::{Category, Warnings};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
 use snafu::{ResultExt, Snafu};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display("There was a problem writing the docker-compose file.\n{}", source))]
     UnableToWrite { source: serde_yaml::Error },
+
+    #[snafu(display("There was a problem converting the compose document to JSON.\n{}", source))]
+    PatchDocument { source: serde_json::Error },
+
+    #[snafu(display("Unable to render the output template.\n{}", message))]
+    RenderTemplate { message: String },
+
+    #[snafu(display(
+        "There was a problem converting the compose document to JSON for the output template.\n{}",
+        source
+    ))]
+    TemplateDocument { source: serde_json::Error },
+
+    #[snafu(display("Unable to apply the patch to the compose document.\n{}", source))]
+    ApplyPatch { source: json_patch::PatchError },
+
+    #[snafu(display("There was a problem writing the resolution manifest.\n{}", source))]
+    UnableToWriteManifest { source: serde_json::Error },
+
+    #[snafu(display(
+        "Service {} references the {} {} which has no top-level declaration",
+        service,
+        kind,
+        name
+    ))]
+    UndeclaredObject {
+        service: String,
+        kind: String,
+        name: String,
+    },
+
+    #[snafu(display(
+        "The {} {} must specify either a file or external: true",
+        kind,
+        name
+    ))]
+    InvalidDeclaration { kind: String, name: String },
+
+    #[snafu(display("Service {} references an undefined env-group: {}", service, group))]
+    UndefinedEnvGroup { service: String, group: String },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// Rewrites the repository portion of an image, e.g. to point at a registry mirror.  Either
+/// a literal prefix or a regular expression may be matched against the repository.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum RegistryRewrite {
+    Prefix {
+        prefix: String,
+        replacement: String,
+    },
+    Regex {
+        #[serde(deserialize_with = "de_regex")]
+        regex: Regex,
+        replacement: String,
+    },
+}
+
+fn de_regex<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    Regex::new(&s).map_err(serde::de::Error::custom)
+}
+
+impl RegistryRewrite {
+    /// Rewrites the repository if it matches.  A prefix only matches whole path segments, so
+    /// `registry.example.com` doesn't match `registry.example.com.evil`.
+    fn apply(&self, repository: &str) -> Option<String> {
+        match self {
+            RegistryRewrite::Prefix {
+                prefix,
+                replacement,
+            } => repository
+                .strip_prefix(prefix.as_str())
+                .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+                .map(|rest| format!("{}{}", replacement, rest)),
+            RegistryRewrite::Regex { regex, replacement } => {
+                if regex.is_match(repository) {
+                    Some(regex.replace(repository, replacement.as_str()).to_string())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Normalises versions reported by the reference before they are used as an image tag.  Any
+/// configured steps are applied in order: strip-prefix, add-prefix then the regex replacement.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct VersionTransform {
+    strip_prefix: Option<String>,
+    add_prefix: Option<String>,
+    #[serde(default)]
+    #[serde(deserialize_with = "de_optional_regex")]
+    regex: Option<Regex>,
+    #[serde(default)]
+    replacement: String,
+}
+
+fn de_optional_regex<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    de_regex(deserializer).map(Some)
+}
+
+impl VersionTransform {
+    fn apply(&self, version: String) -> String {
+        let version = match &self.strip_prefix {
+            Some(p) => version
+                .strip_prefix(p.as_str())
+                .map(|v| v.to_string())
+                .unwrap_or(version),
+            None => version,
+        };
+
+        let version = match &self.add_prefix {
+            Some(p) => format!("{}{}", p, version),
+            None => version,
+        };
+
+        match &self.regex {
+            Some(r) => r.replace(&version, self.replacement.as_str()).to_string(),
+            None => version,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ComposeOptions {
+    pub registry_rewrites: Vec<RegistryRewrite>,
+    pub pins: HashMap<String, String>,
+    pub version_transform: Option<VersionTransform>,
+    pub default_version: Option<String>,
+    pub default_logging: Option<Logging>,
+    pub service_defaults: Option<ServiceDefaults>,
+    pub startup_order: Vec<String>,
+    pub env_groups: HashMap<String, HashMap<String, String>>,
+    pub secrets: BTreeMap<String, ObjectDeclaration>,
+    pub configs: BTreeMap<String, ObjectDeclaration>,
+    /// The top-level `version`, `DEFAULT_COMPOSE_VERSION` if not given and omitted entirely if
+    /// empty or `none`.
+    pub compose_version: Option<String>,
+    /// Versions taken from the latest git tag of each service.
+    pub git_tags: HashMap<String, String>,
+    /// Whether services without a healthcheck are given the default for their image, if any.
+    pub auto_healthcheck: bool,
+    /// Healthchecks by image name which extend or replace the built-in defaults.
+    pub healthchecks: HashMap<String, Healthcheck>,
+    /// The age beyond which a resolved local image is warned about as stale.
+    pub stale_warn_age: Option<chrono::Duration>,
+    /// Whether every map of the output is written in sorted key order.
+    pub deterministic: bool,
+    /// Collects the warnings raised while the services are resolved.
+    pub warnings: Warnings,
+}
+
+impl ComposeOptions {
+    /// The options taken from the configuration, leaving those given on the command line, e.g.
+    /// `--auto-healthcheck`, at their defaults.
+    pub fn from_config(config: &HandelConfig, pins: HashMap<String, String>, warnings: &Warnings) -> ComposeOptions {
+        ComposeOptions {
+            pins,
+            registry_rewrites: config.registry_rewrites().clone().unwrap_or_default(),
+            version_transform: config.version_transform().clone(),
+            default_version: config.default_version().clone(),
+            default_logging: config.default_logging().clone(),
+            service_defaults: config.service_defaults().clone(),
+            startup_order: config.startup_order().clone().unwrap_or_default(),
+            env_groups: config.env_groups().clone().unwrap_or_default(),
+            secrets: config.secrets().clone().unwrap_or_default(),
+            configs: config.configs().clone().unwrap_or_default(),
+            compose_version: config.compose_version().clone(),
+            healthchecks: config.healthchecks().clone().unwrap_or_default(),
+            deterministic: config.deterministic(),
+            warnings: warnings.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Parses a `service=version` pin given on the command line.
+pub fn parse_pin(input: &str) -> Option<(String, String)> {
+    match input.split_once('=') {
+        Some((service, version)) if !service.trim().is_empty() && !version.trim().is_empty() => {
+            Some((service.trim().to_string(), version.trim().to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// Whether an image created at the given time is older than the threshold.
+fn is_stale(created_at: DateTime<Utc>, now: DateTime<Utc>, threshold: chrono::Duration) -> bool {
+    now.signed_duration_since(created_at) > threshold
+}
+
+pub const DEFAULT_COMPOSE_VERSION: &str = "3";
+
+/// The built-in healthcheck for well known images, keyed by the image name without its
+/// repository, e.g. `postgres` of `docker.io/library/postgres:16`.
+fn default_healthcheck(image_name: &str) -> Option<Healthcheck> {
+    let test: &[&str] = match image_name {
+        "postgres" => &["CMD", "pg_isready"],
+        "redis" => &["CMD", "redis-cli", "ping"],
+        "mysql" => &["CMD", "mysqladmin", "ping", "-h", "localhost"],
+        "mongo" => &["CMD", "mongosh", "--quiet", "--eval", "db.adminCommand('ping')"],
+        "rabbitmq" => &["CMD", "rabbitmq-diagnostics", "-q", "ping"],
+        _ => return None,
+    };
+
+    Some(Healthcheck {
+        test: Some(HealthcheckTest::List(test.iter().map(|s| s.to_string()).collect())),
+        interval: Some("10s".to_string()),
+        timeout: Some("5s".to_string()),
+        retries: Some(5),
+        start_period: None,
+        disable: None,
+    })
+}
+
+/// A top-level `networks` entry, declared with the default (bridge) driver.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct NetworkDeclaration {}
+
+/// Declares each network referenced by the given services.
+fn referenced_networks<'a>(
+    services: impl Iterator<Item = &'a ComposeServiceFragment>,
+) -> BTreeMap<String, NetworkDeclaration> {
+    services
+        .flat_map(|f| f.networks.iter().flatten())
+        .map(|n| (n.clone(), NetworkDeclaration::default()))
+        .collect()
+}
+
+/// A top-level `secrets` or `configs` entry, either read from a file or referring to one which
+/// was created outside of compose, e.g. with `docker secret create`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ObjectDeclaration {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub external: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// Selects the declarations referenced by the given services, erroring if a service refers to
+/// an undeclared entry or a referenced entry is neither file based nor external.
+fn referenced_declarations(
+    services: &HashMap<String, ComposeServiceFragment>,
+    declared: &BTreeMap<String, ObjectDeclaration>,
+    kind: &str,
+    references: fn(&ComposeServiceFragment) -> &Option<Vec<String>>,
+) -> Result<BTreeMap<String, ObjectDeclaration>> {
+    let mut names = services.keys().collect::<Vec<_>>();
+    names.sort();
+
+    let mut referenced = BTreeMap::new();
+
+    for service in names {
+        for name in references(&services[service]).iter().flatten() {
+            let declaration = declared.get(name).ok_or(Error::UndeclaredObject {
+                service: service.to_string(),
+                kind: kind.to_string(),
+                name: name.to_string(),
+            })?;
+
+            let external = declaration.external.unwrap_or(false);
+            if external == declaration.file.is_some() {
+                return Err(Error::InvalidDeclaration {
+                    kind: kind.to_string(),
+                    name: name.to_string(),
+                });
+            }
+
+            referenced.insert(name.to_string(), declaration.clone());
+        }
+    }
+
+    Ok(referenced)
+}
+
+/// Merges the environment groups referenced by each service into its environment, in the order
+/// they are listed.  Values defined by the service itself take precedence over the groups.
+fn expand_env_groups(
+    services: &mut HashMap<String, ComposeServiceFragment>,
+    groups: &HashMap<String, HashMap<String, String>>,
+) -> Result<()> {
+    let mut names = services.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+
+    for name in names {
+        let fragment = services.get_mut(&name).expect("Service must be present");
+
+        let referenced = match fragment.env_groups.take() {
+            Some(g) if !g.is_empty() => g,
+            _ => continue,
+        };
+
+        let mut environment = HashMap::new();
+        for g in referenced {
+            let group = groups.get(&g).ok_or(Error::UndefinedEnvGroup {
+                service: name.to_string(),
+                group: g.to_string(),
+            })?;
+            environment.extend(group.clone());
+        }
+
+        if let Some(e) = fragment.environment.take() {
+            environment.extend(e);
+        }
+
+        fragment.environment = Some(environment);
+    }
+
+    Ok(())
+}
+
+/// Makes each service in the startup order which is present depend on the previous present
+/// service, in addition to any dependencies declared by its template.
+fn apply_startup_order(services: &mut HashMap<String, ComposeServiceFragment>, order: &[String]) {
+    let present = order
+        .iter()
+        .filter(|s| services.contains_key(*s))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for pair in present.windows(2) {
+        if let Some(fragment) = services.get_mut(&pair[1]) {
+            fragment
+                .depends_on
+                .get_or_insert_with(|| DependsOn::List(Vec::new()))
+                .add(&pair[0]);
+        }
+    }
+}
+
+/// Returns the first dependency cycle found between the given services, if any.
+fn find_dependency_cycle(services: &HashMap<String, ComposeServiceFragment>) -> Option<Vec<String>> {
+    fn visit(
+        name: &str,
+        services: &HashMap<String, ComposeServiceFragment>,
+        path: &mut Vec<String>,
+        done: &mut HashSet<String>,
+    ) -> Option<Vec<String>> {
+        if let Some(pos) = path.iter().position(|p| p == name) {
+            let mut cycle = path[pos..].to_vec();
+            cycle.push(name.to_string());
+            return Some(cycle);
+        }
+
+        if done.contains(name) {
+            return None;
+        }
+
+        path.push(name.to_string());
+        let deps = services
+            .get(name)
+            .and_then(|f| f.depends_on.as_ref())
+            .map(DependsOn::names)
+            .unwrap_or_default();
+
+        for d in deps.iter().filter(|d| services.contains_key(*d)) {
+            if let Some(cycle) = visit(d, services, path, done) {
+                return Some(cycle);
+            }
+        }
+
+        path.pop();
+        done.insert(name.to_string());
+        None
+    }
+
+    let mut names = services.keys().collect::<Vec<_>>();
+    names.sort();
+
+    let mut done = HashSet::new();
+    names
+        .into_iter()
+        .find_map(|n| visit(n, services, &mut Vec::new(), &mut done))
+}
+
+/// Applies the first matching rewrite to the repository portion of the given image, leaving
+/// the service name and tag untouched.  Returns None if no rewrite applies.
+fn rewrite_image(image: &str, rewrites: &[RegistryRewrite]) -> Option<String> {
+    let image_version = ImageVersion::new(image).ok()?;
+    let repository = image_version.get_repository()?;
+
+    rewrites
+        .iter()
+        .find_map(|r| r.apply(&repository))
+        .map(|updated| image_version.with_repository(Some(updated)).get())
+}
+
+/// Where the version used for a service's image tag was taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provenance {
+    Pinned,
+    Local,
+    #[serde(rename = "git-tag")]
+    GitTag,
+    Reference,
+    Template,
+    Default,
+    Unresolved,
+}
+
+impl Provenance {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Provenance::Pinned => "pinned",
+            Provenance::Local => "local",
+            Provenance::GitTag => "git-tag",
+            Provenance::Reference => "reference",
+            Provenance::Template => "template",
+            Provenance::Default => "default",
+            Provenance::Unresolved => "unresolved",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServiceResolution {
+    pub image: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub provenance: Provenance,
+}
+
+/// Bumped whenever a field of the manifest is renamed, removed or changes meaning.
+pub const MANIFEST_SCHEMA_VERSION: u32 = 1;
+
+/// A durable record of how each service of a run was resolved, written by `--manifest`.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResolutionManifest {
+    pub schema_version: u32,
+    pub scenario: String,
+    pub env: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    pub services: BTreeMap<String, ServiceResolution>,
+}
+
+impl ResolutionManifest {
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context(UnableToWriteManifest)
+    }
+}
+
+/// The ports of a single service, as written to the development override file.
+#[derive(Debug, Serialize)]
+struct PublishedPorts {
+    ports: Vec<PortMapping>,
+}
+
+/// A `docker-compose.override.yml` holding only the host ports removed from the main file.
+#[derive(Debug, Serialize)]
+struct PortsOverride {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    services: BTreeMap<String, PublishedPorts>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct DockerCompose {
-    version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
     services: HashMap<String, ComposeServiceFragment>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    networks: BTreeMap<String, NetworkDeclaration>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    secrets: BTreeMap<String, ObjectDeclaration>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    configs: BTreeMap<String, ObjectDeclaration>,
+    #[serde(skip)]
+    resolutions: BTreeMap<String, ServiceResolution>,
+    #[serde(skip)]
+    deterministic: bool,
 }
 
 impl DockerCompose {
+    #[cfg(test)]
     pub fn generate(
         svcs: &[&ComposeService],
         running: &[RunningService],
         local: &[ContainerImage],
+        options: &ComposeOptions,
     ) -> Result<String> {
+        DockerCompose::build(svcs, running, local, options)?.to_yaml()
+    }
+
+    pub fn build(
+        svcs: &[&ComposeService],
+        running: &[RunningService],
+        local: &[ContainerImage],
+        options: &ComposeOptions,
+    ) -> Result<DockerCompose> {
 
         let mut svc_versions = Vec::<String>::new();
+        let mut rewritten = Vec::<String>::new();
 
         let running_svc_lookup =
             running
                 .iter()
-                .fold(HashMap::<String, &RunningService>::new(), |mut acc, s| {
-                    acc.insert(s.name(), s);
+                .fold(HashMap::<String, String>::new(), |mut acc, s| {
+                    let version = match &options.version_transform {
+                        Some(t) => t.apply(s.version()),
+                        None => s.version(),
+                    };
+                    acc.insert(s.name(), version);
                     acc
                 });
 
+        let running_provenance = running
+            .iter()
+            .map(|s| (s.name(), s.provenance()))
+            .collect::<HashMap<_, _>>();
+
         let container_lookup =
             local
                 .iter()
@@ -44,7 +550,9 @@ impl DockerCompose {
                     acc
                 });
 
-        let versioned = svcs.iter()
+        let mut resolutions = BTreeMap::new();
+
+        let mut versioned = svcs.iter()
             .fold(HashMap::<String,ComposeServiceFragment>::new(), |mut acc, s|{
 
                 let repo = s.image();
@@ -52,8 +560,9 @@ impl DockerCompose {
                 let image_version = s.fragment().get_version();
 
                 if image_version.is_none() {
-                    eprintln!("Warning - cannot extract image information from template for \
+                    let message = format!("Cannot extract image information from template for \
                     service: {:?}", &service_name);
+                    options.warnings.warn(Category::Template, &message, s.file(), None);
                     return acc;
                 }
 
@@ -61,10 +570,40 @@ impl DockerCompose {
 
                 let image_name = image_version.get_name();
 
-                let version = container_lookup.get(&repo).map(|i|i.version())
-                    .or_else(||running_svc_lookup.get(&service_name).map(|r|r.version()))
-                    .or_else(||running_svc_lookup.get(&image_name).map(|r|r.version()))
-                    .or_else(||image_version.get_version());
+                let resolved = options.pins.get(&service_name).map(|v|(v.clone(), Provenance::Pinned))
+                    // A local image's digest identifies it more precisely than its tag.
+                    .or_else(||container_lookup.get(&repo).map(|i|(i.digest().unwrap_or_else(|| i.version()), Provenance::Local)))
+                    .or_else(||options.git_tags.get(&service_name).map(|v|(v.clone(), Provenance::GitTag)))
+                    .or_else(||running_svc_lookup.get(&service_name).map(|v|(v.clone(), Provenance::Reference)))
+                    .or_else(||running_svc_lookup.get(&image_name).map(|v|(v.clone(), Provenance::Reference)))
+                    .or_else(||image_version.get_version().map(|v|(v, Provenance::Template)));
+
+                let stale_image = match (resolved.as_ref().map(|(_, p)| p), options.stale_warn_age) {
+                    (Some(Provenance::Local), Some(threshold)) => container_lookup.get(&repo)
+                        .filter(|i| is_stale(i.created_at(), Utc::now(), threshold)),
+                    _ => None,
+                };
+
+                if let Some(image) = stale_image {
+                    let message = format!("The local image {}:{} of service: {} was created at {}, \
+                    which is older than the stale warning age", &repo, image.version(), &service_name,
+                    image.created_at().format("%Y-%m-%d %H:%M:%S %Z"));
+                    options.warnings.warn(Category::LocalImages, &message, s.file(), None);
+                }
+
+                let fallback = s.fragment().default_version.as_ref()
+                    .or(options.default_version.as_ref());
+
+                let (version, provenance) = match (resolved, fallback) {
+                    (Some((v, p)), _) => (Some(v), p),
+                    (None, Some(f)) => {
+                        let message = format!("No version could be resolved for service: {}, \
+                        using the default version: {}", &service_name, f);
+                        options.warnings.warn(Category::DefaultVersion, &message, s.file(), None);
+                        (Some(f.clone()), Provenance::Default)
+                    }
+                    (None, None) => (None, Provenance::Unresolved),
+                };
 
                 let image_parts : Vec<&str> = repo.splitn(2, '/' ).collect();
                 let plain_repo = match image_parts.len() {
@@ -74,14 +613,57 @@ impl DockerCompose {
 
 
                 let svc_name = if let Some(v) = &version {
-                    format!("{} -> {}:{}", &service_name, &plain_repo, &v.clone())
+                    let separator = if crate::templates::is_digest(v) { '@' } else { ':' };
+                    format!("{} -> {}{}{}", &service_name, &plain_repo, separator, &v.clone())
                 } else {
                     format!("{} -> {}", &service_name, &plain_repo)
                 };
 
                 svc_versions.push(svc_name.to_owned());
 
-                let fragment = s.fragment_using_version(version);
+                let mut fragment = s.fragment_using_version(version.clone());
+
+                if let Some(image) = rewrite_image(&fragment.image, &options.registry_rewrites) {
+                    rewritten.push(format!("{} -> {}", &fragment.image, &image));
+                    fragment.image = image;
+                }
+
+                if let Some(defaults) = &options.service_defaults {
+                    defaults.apply_to(&mut fragment);
+                }
+
+                if fragment.logging.is_none() {
+                    fragment.logging = options.default_logging.clone();
+                }
+
+                if options.auto_healthcheck && fragment.healthcheck.is_none() {
+                    let name = image_name.rsplit('/').next().unwrap_or(&image_name);
+                    fragment.healthcheck = options.healthchecks.get(name)
+                        .cloned()
+                        .or_else(|| default_healthcheck(name));
+                }
+
+                // The captured provenance of a reference version, e.g. its git commit, is recorded
+                // as x-handel labels prefixed by reference-.
+                let reference_provenance = match provenance {
+                    Provenance::Reference => running_provenance.get(&service_name)
+                        .or_else(|| running_provenance.get(&image_name))
+                        .filter(|p| !p.is_empty()),
+                    _ => None,
+                };
+
+                if let Some(p) = reference_provenance {
+                    let labels = fragment.handel_labels.get_or_insert_with(HashMap::new);
+                    for (k, v) in p {
+                        labels.insert(format!("reference-{}", k), v.clone());
+                    }
+                }
+
+                resolutions.insert(service_name.clone(), ServiceResolution {
+                    image: fragment.image.clone(),
+                    version,
+                    provenance,
+                });
 
                 acc.insert(service_name, fragment);
 
@@ -94,12 +676,271 @@ impl DockerCompose {
             svc_versions.join("\n\t")
         );
 
-        let compose = DockerCompose {
-            version: String::from("3"),
+        if !rewritten.is_empty() {
+            println!("\nRewritten image repositories:\n\t{}", rewritten.join("\n\t"));
+        }
+
+        expand_env_groups(&mut versioned, &options.env_groups)?;
+
+        if !options.startup_order.is_empty() {
+            apply_startup_order(&mut versioned, &options.startup_order);
+
+            if let Some(cycle) = find_dependency_cycle(&versioned) {
+                let message = format!(
+                    "The startup-order combined with declared dependencies creates a cycle: {}",
+                    cycle.join(" -> ")
+                );
+                options.warnings.warn(Category::DependencyCycle, &message, None, None);
+            }
+        }
+
+        let secrets = referenced_declarations(&versioned, &options.secrets, "secret", |f| &f.secrets)?;
+        let configs = referenced_declarations(&versioned, &options.configs, "config", |f| &f.configs)?;
+
+        let version = match options.compose_version.as_deref() {
+            None => Some(DEFAULT_COMPOSE_VERSION.to_string()),
+            Some("") | Some("none") => None,
+            Some(v) => Some(v.to_string()),
+        };
+
+        let networks = referenced_networks(versioned.values());
+
+        Ok(DockerCompose {
+            version,
+            networks,
             services: versioned,
+            secrets,
+            configs,
+            resolutions,
+            deterministic: options.deterministic,
+        })
+    }
+
+    pub fn services(&self) -> &HashMap<String, ComposeServiceFragment> {
+        &self.services
+    }
+
+    /// Inserts a `# resolved from <provenance>` comment above the `image:` line of each service
+    /// within the serialised document, as serde_yaml can't write comments.  Top-level comments,
+    /// e.g. a header, are passed through untouched.
+    pub fn annotate_sources(&self, yaml: &str) -> String {
+        let mut annotated = String::with_capacity(yaml.len());
+        let mut in_services = false;
+        let mut service = None;
+
+        for line in yaml.lines() {
+            let trimmed = line.trim_start();
+            let indent = line.len() - trimmed.len();
+
+            if indent == 0 && !trimmed.is_empty() && !trimmed.starts_with('#') {
+                in_services = trimmed == "services:";
+                service = None;
+            } else if in_services && indent == 2 && trimmed.ends_with(':') {
+                let name = trimmed.trim_end_matches(':').trim_matches(|c| c == '\'' || c == '"');
+                service = self.resolutions.get(name);
+            } else if in_services && indent == 4 && trimmed.starts_with("image:") {
+                if let Some(r) = service.take() {
+                    annotated.push_str(&line[..indent]);
+                    annotated.push_str("# resolved from ");
+                    annotated.push_str(r.provenance.as_str());
+                    annotated.push('\n');
+                }
+            }
+
+            annotated.push_str(line);
+            annotated.push('\n');
+        }
+
+        annotated
+    }
+
+    /// Describes where each service's version was resolved from, for the `--manifest` output.
+    pub fn manifest(&self, scenario: &str, env: &str, timestamp: Option<String>) -> ResolutionManifest {
+        ResolutionManifest {
+            schema_version: MANIFEST_SCHEMA_VERSION,
+            scenario: scenario.to_string(),
+            env: env.to_string(),
+            timestamp,
+            services: self.resolutions.clone(),
+        }
+    }
+
+    /// Serialises the document, passing it through JSON when deterministic so that the keys of
+    /// every map, e.g. the services and their environment, are written in sorted order.
+    pub fn to_yaml(&self) -> Result<String> {
+        match self.deterministic {
+            true => self.to_yaml_patched(&json_patch::Patch(Vec::new())),
+            false => serde_yaml::to_string(self).context(UnableToWrite),
+        }
+    }
+
+    /// Serialises the document after applying the given JSON Patch (RFC 6902) to it, as an
+    /// escape hatch for edits which handel can't otherwise express.  Note that map keys are
+    /// written in sorted order as the document passes through JSON.
+    pub fn to_yaml_patched(&self, patch: &json_patch::Patch) -> Result<String> {
+        let mut document = serde_json::to_value(self).context(PatchDocument)?;
+
+        json_patch::patch(&mut document, &patch.0).context(ApplyPatch)?;
+
+        serde_yaml::to_string(&document).context(UnableToWrite)
+    }
+
+    /// Renders the output through a tera template rather than writing the YAML directly.  The
+    /// template is given the document as `compose`, how each service was resolved as `services`
+    /// (image, version and provenance), the `scenario`, the `env` and the YAML which would
+    /// otherwise have been written as `yaml`.
+    pub fn render_template(&self, template: &str, yaml: &str, scenario: &str, env: &str) -> Result<String> {
+        let mut context = tera::Context::new();
+        context.insert("compose", &serde_json::to_value(self).context(TemplateDocument)?);
+        context.insert("services", &self.resolutions);
+        context.insert("scenario", scenario);
+        context.insert("env", env);
+        context.insert("yaml", yaml);
+
+        tera::Tera::one_off(template, &context, false).map_err(|e| {
+            // The cause, e.g. an undefined variable, is only given by the error's sources.
+            let mut message = e.to_string();
+            let mut source = std::error::Error::source(&e);
+            while let Some(s) = source {
+                message.push_str(&format!("\n{}", s));
+                source = s.source();
+            }
+            Error::RenderTemplate { message }
+        })
+    }
+
+    /// Removes the ports from every service, returning them as a compose override document so
+    /// that they are only published when both files are used, e.g. by a local `docker compose up`.
+    pub fn take_ports_override(&mut self) -> Result<String> {
+        let services = self
+            .services
+            .iter_mut()
+            .filter_map(|(name, fragment)| {
+                fragment
+                    .ports
+                    .take()
+                    .map(|ports| (name.clone(), PublishedPorts { ports }))
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let document = PortsOverride {
+            version: self.version.clone(),
+            services,
+        };
+
+        serde_yaml::to_string(&document).context(UnableToWrite)
+    }
+
+    /// Replaces the image of every service with a `${<SERVICE>_IMAGE}` variable, returning the
+    /// `.env` file which defines them so that the versions are kept in one place.  The variable
+    /// is the service name upper-cased with anything other than letters and digits replaced by
+    /// `_`, services whose names clash being numbered in name order, e.g. `API_2_IMAGE`.
+    pub fn take_image_env(&mut self) -> String {
+        let mut names = self.services.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+
+        let mut used = HashSet::new();
+        let mut env = String::new();
+
+        for name in names {
+            let mut base = name
+                .chars()
+                .map(|c| match c.is_ascii_alphanumeric() {
+                    true => c.to_ascii_uppercase(),
+                    false => '_',
+                })
+                .collect::<String>();
+            if base.starts_with(|c: char| c.is_ascii_digit()) {
+                base.insert(0, '_');
+            }
+
+            let mut variable = format!("{}_IMAGE", base);
+            let mut suffix = 2;
+            while !used.insert(variable.clone()) {
+                variable = format!("{}_{}_IMAGE", base, suffix);
+                suffix += 1;
+            }
+
+            if let Some(fragment) = self.services.get_mut(&name) {
+                let image = std::mem::replace(&mut fragment.image, format!("${{{}}}", variable));
+                env.push_str(&format!("{}={}\n", variable, image));
+            }
+        }
+
+        env
+    }
+
+    /// Merges the generated image variables into an existing `.env` file, replacing each of its
+    /// `*_IMAGE` variables but keeping any other line, e.g. the user's own interpolation
+    /// variables or comments.
+    pub fn merge_image_env(existing: &str, generated: &str) -> String {
+        let is_image_variable = |line: &str| {
+            let line = line.trim_start();
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            line.split_once('=')
+                .is_some_and(|(key, _)| key.trim_end().ends_with("_IMAGE"))
         };
 
-        serde_yaml::to_string(&compose).context(UnableToWrite)
+        let mut merged = existing
+            .lines()
+            .filter(|l| !is_image_variable(l))
+            .map(|l| format!("{}\n", l))
+            .collect::<String>();
+        merged.push_str(generated);
+        merged
+    }
+
+    /// Produces a standalone compose document for each service, along with a file name derived
+    /// from the service name which is unique even on case-insensitive file systems.
+    pub fn split(&self) -> Result<Vec<(String, String)>> {
+        let mut names = self.services.keys().collect::<Vec<_>>();
+        names.sort();
+
+        let mut used = HashSet::new();
+        let mut documents = Vec::new();
+
+        for name in names {
+            let base = name
+                .chars()
+                .map(|c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                    true => c,
+                    false => '_',
+                })
+                .collect::<String>();
+
+            let mut file_name = format!("{}.yml", base);
+            let mut suffix = 2;
+            while !used.insert(file_name.to_lowercase()) {
+                file_name = format!("{}-{}.yml", base, suffix);
+                suffix += 1;
+            }
+
+            let fragment = &self.services[name];
+            let select = |declared: &BTreeMap<String, ObjectDeclaration>, refs: &Option<Vec<String>>| {
+                declared
+                    .iter()
+                    .filter(|(k, _)| refs.iter().flatten().any(|r| r == *k))
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect::<BTreeMap<_, _>>()
+            };
+
+            let mut services = HashMap::new();
+            services.insert(name.clone(), fragment.clone());
+
+            let document = DockerCompose {
+                version: self.version.clone(),
+                networks: referenced_networks(std::iter::once(fragment)),
+                services,
+                secrets: select(&self.secrets, &fragment.secrets),
+                configs: select(&self.configs, &fragment.configs),
+                resolutions: BTreeMap::new(),
+                deterministic: self.deterministic,
+            };
+
+            documents.push((file_name, document.to_yaml()?));
+        }
+
+        Ok(documents)
     }
 }
 
@@ -121,7 +962,7 @@ image: 12121212121.dkr.ecr.us-east-1.amazonaws.com/contentrepo:1.0.400
         let running = [RunningService::new("contentrepo", "1.0.425")];
         let local = [];
 
-        let result = DockerCompose::generate(&svcs, &running, &local);
+        let result = DockerCompose::generate(&svcs, &running, &local, &ComposeOptions::default());
 
         let expected = r#"version: '3'
 services:
@@ -132,4 +973,755 @@ services:
         assert!(result.is_ok());
         assert_eq!(expected, result.unwrap());
     }
+
+    #[test]
+    fn test_registry_rewrite_prefix() {
+        let t = r#"
+- prefix: registry.example.com
+  replacement: mirror.internal
+"#;
+        let rewrites: Vec<RegistryRewrite> = serde_yaml::from_str(t).unwrap();
+
+        assert_eq!(
+            Some("mirror.internal/api:1.2.3".to_string()),
+            rewrite_image("registry.example.com/api:1.2.3", &rewrites)
+        );
+        assert_eq!(None, rewrite_image("other.example.com/api:1.2.3", &rewrites));
+        assert_eq!(None, rewrite_image("memcached:1.6.7", &rewrites));
+        assert_eq!(None, rewrite_image("registry.example.com.evil/x:1.0", &rewrites));
+
+        let rewrite = &rewrites[0];
+        assert_eq!(Some("mirror.internal".to_string()), rewrite.apply("registry.example.com"));
+        assert_eq!(Some("mirror.internal/team".to_string()), rewrite.apply("registry.example.com/team"));
+        assert_eq!(None, rewrite.apply("registry.example.com.evil/team"));
+    }
+
+    #[test]
+    fn test_registry_rewrite_regex() {
+        let t = r#"
+- regex: '^(\d+)\.dkr\.ecr\.([a-z0-9-]+)\.amazonaws\.com$'
+  replacement: mirror.internal
+"#;
+        let rewrites: Vec<RegistryRewrite> = serde_yaml::from_str(t).unwrap();
+        let frag: ComposeServiceFragment = serde_yaml::from_str(
+            "image: 12121212121.dkr.ecr.us-east-1.amazonaws.com/contentrepo:1.0.400",
+        )
+        .unwrap();
+
+        let svcs = [&ComposeService::new("content-repo",
+         "12121212121.dkr.ecr.us-east-1.amazonaws.com/contentrepo", &frag)];
+
+        let options = ComposeOptions {
+            registry_rewrites: rewrites,
+            ..Default::default()
+        };
+
+        let result = DockerCompose::generate(&svcs, &[], &[], &options).unwrap();
+
+        let expected = r#"version: '3'
+services:
+  content-repo:
+    image: mirror.internal/contentrepo:1.0.400
+"#;
+
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn test_version_transform_strip_prefix() {
+        let frag: ComposeServiceFragment = serde_yaml::from_str(
+            "image: 12121212121.dkr.ecr.us-east-1.amazonaws.com/contentrepo:1.0.400",
+        )
+        .unwrap();
+
+        let svcs = [&ComposeService::new("content-repo",
+         "12121212121.dkr.ecr.us-east-1.amazonaws.com/contentrepo", &frag)];
+
+        let running = [RunningService::new("contentrepo", "v1.0.425")];
+
+        let options = ComposeOptions {
+            version_transform: Some(serde_yaml::from_str("strip-prefix: v").unwrap()),
+            ..Default::default()
+        };
+
+        let result = DockerCompose::generate(&svcs, &running, &[], &options).unwrap();
+
+        let expected = r#"version: '3'
+services:
+  content-repo:
+    image: 12121212121.dkr.ecr.us-east-1.amazonaws.com/contentrepo:1.0.425
+"#;
+
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn test_default_version_fallback() {
+        let global: ComposeServiceFragment = serde_yaml::from_str("image: example/api").unwrap();
+        let own: ComposeServiceFragment =
+            serde_yaml::from_str("image: example/worker\ndefault-version: develop").unwrap();
+
+        let svcs = [
+            &ComposeService::new("api", "example/api", &global),
+            &ComposeService::new("worker", "example/worker", &own),
+        ];
+
+        let options = ComposeOptions {
+            default_version: Some("latest".to_string()),
+            ..Default::default()
+        };
+
+        let compose = DockerCompose::build(&svcs, &[], &[], &options).unwrap();
+        let doc: serde_yaml::Value = serde_yaml::from_str(&compose.to_yaml().unwrap()).unwrap();
+
+        assert_eq!("example/api:latest", doc["services"]["api"]["image"].as_str().unwrap());
+        assert_eq!("example/worker:develop", doc["services"]["worker"]["image"].as_str().unwrap());
+        assert!(doc["services"]["worker"].get("default-version").is_none());
+
+        let manifest = compose.manifest("app", "test", None);
+        assert_eq!(Provenance::Default, manifest.services["api"].provenance);
+    }
+
+    #[test]
+    fn test_default_logging() {
+        let plain: ComposeServiceFragment = serde_yaml::from_str("image: example/api:1.0").unwrap();
+        let own: ComposeServiceFragment =
+            serde_yaml::from_str("image: example/worker:1.0\nlogging:\n  driver: fluentd").unwrap();
+
+        let svcs = [
+            &ComposeService::new("api", "example/api", &plain),
+            &ComposeService::new("worker", "example/worker", &own),
+        ];
+
+        let options = ComposeOptions {
+            default_logging: Some(serde_yaml::from_str("driver: json-file").unwrap()),
+            ..Default::default()
+        };
+
+        let result = DockerCompose::generate(&svcs, &[], &[], &options).unwrap();
+        let doc: serde_yaml::Value = serde_yaml::from_str(&result).unwrap();
+
+        assert_eq!("json-file", doc["services"]["api"]["logging"]["driver"].as_str().unwrap());
+        assert_eq!("fluentd", doc["services"]["worker"]["logging"]["driver"].as_str().unwrap());
+    }
+
+    fn declarations(yaml: &str) -> BTreeMap<String, ObjectDeclaration> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn test_file_and_external_secrets() {
+        let api: ComposeServiceFragment = serde_yaml::from_str(
+            "image: example/api:1.0\nsecrets:\n  - db-password\n  - api-key\nconfigs:\n  - api-config",
+        )
+        .unwrap();
+
+        let svcs = [&ComposeService::new("api", "example/api", &api)];
+
+        let options = ComposeOptions {
+            secrets: declarations(
+                r#"
+db-password:
+  file: ./secrets/db-password.txt
+api-key:
+  external: true
+  name: prod-api-key
+unused:
+  external: true
+"#,
+            ),
+            configs: declarations("api-config:\n  external: true\n"),
+            ..Default::default()
+        };
+
+        let result = DockerCompose::generate(&svcs, &[], &[], &options).unwrap();
+
+        let expected = r#"version: '3'
+services:
+  api:
+    image: example/api:1.0
+    secrets:
+    - db-password
+    - api-key
+    configs:
+    - api-config
+secrets:
+  api-key:
+    external: true
+    name: prod-api-key
+  db-password:
+    file: ./secrets/db-password.txt
+configs:
+  api-config:
+    external: true
+"#;
+
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn test_undeclared_and_invalid_secrets() {
+        let api: ComposeServiceFragment =
+            serde_yaml::from_str("image: example/api:1.0\nsecrets:\n  - db-password").unwrap();
+        let svcs = [&ComposeService::new("api", "example/api", &api)];
+
+        let result = DockerCompose::generate(&svcs, &[], &[], &ComposeOptions::default());
+        assert!(matches!(result, Err(Error::UndeclaredObject { .. })));
+
+        let options = ComposeOptions {
+            secrets: declarations("db-password:\n  name: db-password\n"),
+            ..Default::default()
+        };
+        let result = DockerCompose::generate(&svcs, &[], &[], &options);
+        assert!(matches!(result, Err(Error::InvalidDeclaration { .. })));
+    }
+
+    #[test]
+    fn test_dev_ports_override() {
+        let api: ComposeServiceFragment =
+            serde_yaml::from_str("image: example/api:1.0\nports:\n  - 8080:80\n  - 5005").unwrap();
+        let db: ComposeServiceFragment = serde_yaml::from_str("image: mysql:5.7").unwrap();
+
+        let svcs = [
+            &ComposeService::new("api", "example/api", &api),
+            &ComposeService::new("db", "mysql", &db),
+        ];
+
+        let mut compose = DockerCompose::build(&svcs, &[], &[], &ComposeOptions::default()).unwrap();
+        let ports = compose.take_ports_override().unwrap();
+        let main = compose.to_yaml().unwrap();
+
+        assert!(!main.contains("ports"));
+        assert!(!main.contains("8080"));
+
+        let expected = r#"version: '3'
+services:
+  api:
+    ports:
+    - 8080:80
+    - '5005'
+"#;
+        assert_eq!(expected, ports);
+    }
+
+    #[test]
+    fn test_image_env() {
+        let api: ComposeServiceFragment = serde_yaml::from_str("image: example/api:1.0").unwrap();
+        let db: ComposeServiceFragment = serde_yaml::from_str("image: mysql:5.7").unwrap();
+
+        let svcs = [
+            &ComposeService::new("content-repo", "example/api", &api),
+            &ComposeService::new("content_repo", "example/api", &api),
+            &ComposeService::new("3db", "mysql", &db),
+        ];
+
+        let mut compose = DockerCompose::build(&svcs, &[], &[], &ComposeOptions::default()).unwrap();
+        let env = compose.take_image_env();
+
+        assert_eq!(
+            "_3DB_IMAGE=mysql:5.7\nCONTENT_REPO_IMAGE=example/api:1.0\nCONTENT_REPO_2_IMAGE=example/api:1.0\n",
+            env
+        );
+        assert_eq!("${CONTENT_REPO_IMAGE}", compose.services()["content-repo"].image);
+        assert_eq!("${CONTENT_REPO_2_IMAGE}", compose.services()["content_repo"].image);
+        assert_eq!("example/api:1.0", compose.manifest("app", "test", None).services["content-repo"].image);
+    }
+
+    #[test]
+    fn test_merge_image_env() {
+        let existing = "# local settings\nCOMPOSE_PROJECT_NAME=dev\nAPI_IMAGE=example/api:0.9\nexport OLD_IMAGE=example/old:1.0\nDB_PASSWORD=secret\n";
+
+        assert_eq!(
+            "# local settings\nCOMPOSE_PROJECT_NAME=dev\nDB_PASSWORD=secret\nAPI_IMAGE=example/api:1.0\n",
+            DockerCompose::merge_image_env(existing, "API_IMAGE=example/api:1.0\n")
+        );
+        assert_eq!("API_IMAGE=example/api:1.0\n", DockerCompose::merge_image_env("", "API_IMAGE=example/api:1.0\n"));
+    }
+
+    #[test]
+    fn test_pinned_version_precedence() {
+        let frag: ComposeServiceFragment =
+            serde_yaml::from_str("image: example/contentrepo:1.0.1").unwrap();
+        let svcs = [&ComposeService::new("contentrepo", "example/contentrepo", &frag)];
+        let running = [RunningService::new("contentrepo", "1.0.425")];
+
+        let mut options = ComposeOptions::default();
+        options.pins.insert("contentrepo".to_string(), "1.0.400".to_string());
+
+        let compose = DockerCompose::build(&svcs, &running, &[], &options).unwrap();
+        assert!(compose.to_yaml().unwrap().contains("image: example/contentrepo:1.0.400"));
+        assert_eq!(
+            Provenance::Pinned,
+            compose.manifest("frozen", "test", None).services["contentrepo"].provenance
+        );
+
+        let compose = DockerCompose::build(&svcs, &running, &[], &ComposeOptions::default()).unwrap();
+        assert!(compose.to_yaml().unwrap().contains("image: example/contentrepo:1.0.425"));
+    }
+
+    #[test]
+    fn test_service_defaults() {
+        let plain: ComposeServiceFragment =
+            serde_yaml::from_str("image: example/api:1.0\nenvironment:\n  LOG_LEVEL: debug").unwrap();
+        let own: ComposeServiceFragment =
+            serde_yaml::from_str("image: example/worker:1.0\nrestart: on-failure:3").unwrap();
+
+        let svcs = [
+            &ComposeService::new("api", "example/api", &plain),
+            &ComposeService::new("worker", "example/worker", &own),
+        ];
+
+        let options = ComposeOptions {
+            service_defaults: Some(
+                serde_yaml::from_str(
+                    "restart: unless-stopped\nenvironment:\n  LOG_LEVEL: info\n  TZ: UTC\n",
+                )
+                .unwrap(),
+            ),
+            ..Default::default()
+        };
+
+        let result = DockerCompose::generate(&svcs, &[], &[], &options).unwrap();
+        let doc: serde_yaml::Value = serde_yaml::from_str(&result).unwrap();
+
+        assert_eq!("unless-stopped", doc["services"]["api"]["restart"].as_str().unwrap());
+        assert_eq!("on-failure:3", doc["services"]["worker"]["restart"].as_str().unwrap());
+        assert_eq!("debug", doc["services"]["api"]["environment"]["LOG_LEVEL"].as_str().unwrap());
+        assert_eq!("UTC", doc["services"]["api"]["environment"]["TZ"].as_str().unwrap());
+        assert_eq!("info", doc["services"]["worker"]["environment"]["LOG_LEVEL"].as_str().unwrap());
+    }
+
+    #[test]
+    fn test_json_patch() {
+        let api: ComposeServiceFragment = serde_yaml::from_str("image: example/api:1.0").unwrap();
+        let svcs = [&ComposeService::new("api", "example/api", &api)];
+        let compose = DockerCompose::build(&svcs, &[], &[], &ComposeOptions::default()).unwrap();
+
+        let patch: json_patch::Patch = serde_json::from_str(
+            r#"[
+                {"op": "add", "path": "/services/api/shm_size", "value": "2gb"},
+                {"op": "replace", "path": "/services/api/image", "value": "example/api:2.0"}
+            ]"#,
+        )
+        .unwrap();
+
+        let expected = r#"services:
+  api:
+    image: example/api:2.0
+    shm_size: 2gb
+version: '3'
+"#;
+        assert_eq!(expected, compose.to_yaml_patched(&patch).unwrap());
+
+        let bad: json_patch::Patch = serde_json::from_str(
+            r#"[{"op": "replace", "path": "/services/db/image", "value": "mysql:8"}]"#,
+        )
+        .unwrap();
+        let err = compose.to_yaml_patched(&bad).unwrap_err();
+        assert!(err.to_string().contains("/services/db/image"));
+    }
+
+    #[test]
+    fn test_version_transform_forms() {
+        let t: VersionTransform = serde_yaml::from_str("add-prefix: release-").unwrap();
+        assert_eq!("release-1.2.3", t.apply("1.2.3".to_string()));
+
+        let t: VersionTransform = serde_yaml::from_str(r#"
+regex: '^v(\d+\.\d+)\.\d+$'
+replacement: '$1'
+"#).unwrap();
+        assert_eq!("1.2", t.apply("v1.2.3".to_string()));
+        assert_eq!("latest", t.apply("latest".to_string()));
+    }
+
+    fn depends_on(doc: &serde_yaml::Value, service: &str) -> Vec<String> {
+        doc["services"][service]["depends_on"]
+            .as_sequence()
+            .map(|s| s.iter().map(|d| d.as_str().unwrap().to_string()).collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_startup_order_only_present_services() {
+        let a: ComposeServiceFragment = serde_yaml::from_str("image: example/a:1").unwrap();
+        let c: ComposeServiceFragment =
+            serde_yaml::from_str("image: example/c:1\ndepends_on:\n  - d").unwrap();
+        let d: ComposeServiceFragment = serde_yaml::from_str("image: example/d:1").unwrap();
+
+        let a = ComposeService::new("a", "example/a", &a);
+        let c = ComposeService::new("c", "example/c", &c);
+        let d = ComposeService::new("d", "example/d", &d);
+
+        let options = ComposeOptions {
+            startup_order: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+            ..Default::default()
+        };
+
+        let result = DockerCompose::generate(&[&a, &c, &d], &[], &[], &options).unwrap();
+        let doc: serde_yaml::Value = serde_yaml::from_str(&result).unwrap();
+
+        assert!(depends_on(&doc, "a").is_empty());
+        assert_eq!(vec!["d", "a"], depends_on(&doc, "c"));
+        assert!(depends_on(&doc, "d").is_empty());
+        assert!(doc["services"]["b"].is_null());
+    }
+
+    #[test]
+    fn test_startup_order_cycle_detection() {
+        let a: ComposeServiceFragment =
+            serde_yaml::from_str("image: example/a:1\ndepends_on:\n  - b").unwrap();
+        let b: ComposeServiceFragment = serde_yaml::from_str("image: example/b:1").unwrap();
+
+        let mut services = HashMap::new();
+        services.insert("a".to_string(), a);
+        services.insert("b".to_string(), b);
+
+        assert_eq!(None, find_dependency_cycle(&services));
+
+        apply_startup_order(&mut services, &["a".to_string(), "b".to_string()]);
+
+        assert_eq!(
+            Some(vec!["a".to_string(), "b".to_string(), "a".to_string()]),
+            find_dependency_cycle(&services)
+        );
+    }
+
+    #[test]
+    fn test_env_groups_expansion() {
+        let t = r#"
+image: example/api:1
+env-groups:
+  - db
+  - cache
+environment:
+  DB_HOST: api-db
+"#;
+        let frag: ComposeServiceFragment = serde_yaml::from_str(t).unwrap();
+        let api = ComposeService::new("api", "example/api", &frag);
+
+        let groups: HashMap<String, HashMap<String, String>> = serde_yaml::from_str(r#"
+db:
+  DB_HOST: mysql
+  DB_PORT: "3306"
+cache:
+  CACHE_HOST: memcached
+  DB_PORT: "3307"
+"#).unwrap();
+
+        let options = ComposeOptions {
+            env_groups: groups,
+            ..Default::default()
+        };
+
+        let result = DockerCompose::generate(&[&api], &[], &[], &options).unwrap();
+        let doc: serde_yaml::Value = serde_yaml::from_str(&result).unwrap();
+        let env = &doc["services"]["api"]["environment"];
+
+        assert_eq!("api-db", env["DB_HOST"].as_str().unwrap());
+        assert_eq!("3307", env["DB_PORT"].as_str().unwrap());
+        assert_eq!("memcached", env["CACHE_HOST"].as_str().unwrap());
+        assert!(doc["services"]["api"]["env-groups"].is_null());
+    }
+
+    #[test]
+    fn test_undefined_env_group() {
+        let frag: ComposeServiceFragment =
+            serde_yaml::from_str("image: example/api:1\nenv-groups:\n  - nope").unwrap();
+        let api = ComposeService::new("api", "example/api", &frag);
+
+        let result = DockerCompose::generate(&[&api], &[], &[], &ComposeOptions::default());
+
+        assert!(matches!(result, Err(Error::UndefinedEnvGroup { .. })));
+    }
+
+    #[test]
+    fn test_split_per_service() {
+        let frag = |image: &str| -> ComposeServiceFragment {
+            serde_yaml::from_str(&format!("image: {}", image)).unwrap()
+        };
+
+        let svcs = [
+            ComposeService::new("api", "example/api", &frag("example/api:1")),
+            ComposeService::new("API", "example/api2", &frag("example/api2:1")),
+            ComposeService::new("db", "mysql", &frag("mysql:5.7")),
+        ];
+        let svcs = svcs.iter().collect::<Vec<_>>();
+
+        let compose = DockerCompose::build(&svcs, &[], &[], &ComposeOptions::default()).unwrap();
+        let documents = compose.split().unwrap();
+
+        assert_eq!(3, documents.len());
+        assert_eq!(
+            vec!["API.yml", "api-2.yml", "db.yml"],
+            documents.iter().map(|(f, _)| f.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!("version: '3'\nservices:\n  db:\n    image: mysql:5.7\n", documents[2].1);
+    }
+
+    #[test]
+    fn test_auto_healthcheck() {
+        let frag = |yaml: &str| -> ComposeServiceFragment { serde_yaml::from_str(yaml).unwrap() };
+        let postgres = frag("image: postgres:16");
+        let redis = frag("image: redis:7\nhealthcheck:\n  test: redis-cli -a secret ping");
+        let mysql = frag("image: mysql:8");
+        let api = frag("image: example/api:1.0");
+
+        let svcs = [
+            &ComposeService::new("db", "postgres:16", &postgres),
+            &ComposeService::new("cache", "redis:7", &redis),
+            &ComposeService::new("legacy", "mysql:8", &mysql),
+            &ComposeService::new("api", "example/api:1.0", &api),
+        ];
+
+        let custom = Healthcheck {
+            test: Some(HealthcheckTest::Command("mysqladmin ping -uroot".to_string())),
+            interval: None,
+            timeout: None,
+            retries: None,
+            start_period: None,
+            disable: None,
+        };
+        let options = ComposeOptions {
+            auto_healthcheck: true,
+            healthchecks: [("mysql".to_string(), custom.clone())].into_iter().collect(),
+            ..Default::default()
+        };
+
+        let compose = DockerCompose::build(&svcs, &[], &[], &options).unwrap();
+        let healthcheck = |s: &str| compose.services[s].healthcheck.clone();
+
+        assert_eq!(default_healthcheck("postgres"), healthcheck("db"));
+        assert_eq!(
+            Some(HealthcheckTest::List(vec!["CMD".to_string(), "pg_isready".to_string()])),
+            healthcheck("db").unwrap().test
+        );
+        assert_eq!(
+            Some(HealthcheckTest::Command("redis-cli -a secret ping".to_string())),
+            healthcheck("cache").unwrap().test
+        );
+        assert_eq!(Some(custom), healthcheck("legacy"));
+        assert_eq!(None, healthcheck("api"));
+
+        let compose = DockerCompose::build(&svcs, &[], &[], &ComposeOptions::default()).unwrap();
+        assert_eq!(None, compose.services["db"].healthcheck);
+    }
+
+    #[test]
+    fn test_top_level_networks() {
+        let api: ComposeServiceFragment =
+            serde_yaml::from_str("image: example/api:1.0\nnetworks: [ front, back ]").unwrap();
+        let db: ComposeServiceFragment =
+            serde_yaml::from_str("image: mysql:5.7\nnetworks: [ back ]").unwrap();
+
+        let svcs = [
+            &ComposeService::new("api", "example/api:1.0", &api),
+            &ComposeService::new("db", "mysql:5.7", &db),
+        ];
+
+        let compose = DockerCompose::build(&svcs, &[], &[], &ComposeOptions::default()).unwrap();
+        let yaml = compose.to_yaml().unwrap();
+        assert!(yaml.ends_with("networks:\n  back: {}\n  front: {}\n"), "{}", yaml);
+
+        let documents = compose.split().unwrap();
+        assert!(documents[1].1.ends_with("networks:\n  back: {}\n"), "{}", documents[1].1);
+    }
+
+    #[test]
+    fn test_reference_provenance_labels() {
+        let frag: ComposeServiceFragment = serde_yaml::from_str("image: example/api:1.0").unwrap();
+        let db: ComposeServiceFragment = serde_yaml::from_str("image: mysql:5.7").unwrap();
+
+        let svcs = [
+            &ComposeService::new("api", "example/api:1.0", &frag),
+            &ComposeService::new("db", "mysql:5.7", &db),
+        ];
+        let running = [RunningService::with_provenance(
+            "api",
+            "1.2.0",
+            &[("commit", "9f2c1e4"), ("built", "2024-03-01T10:00:00Z")],
+        )];
+
+        let compose = DockerCompose::build(&svcs, &running, &[], &ComposeOptions::default()).unwrap();
+        let labels = compose.services["api"].handel_labels.as_ref().unwrap();
+
+        assert_eq!("9f2c1e4", labels["reference-commit"]);
+        assert_eq!("2024-03-01T10:00:00Z", labels["reference-built"]);
+        assert!(compose.services["db"].handel_labels.is_none());
+        assert!(compose.to_yaml().unwrap().contains("reference-commit: 9f2c1e4"));
+    }
+
+    #[test]
+    fn test_resolution_manifest_round_trip() {
+        let frag: ComposeServiceFragment = serde_yaml::from_str("image: example/api:1.0").unwrap();
+        let db: ComposeServiceFragment = serde_yaml::from_str("image: mysql:5.7").unwrap();
+        let cache: ComposeServiceFragment = serde_yaml::from_str("image: memcached").unwrap();
+
+        let svcs = [
+            &ComposeService::new("api", "example/api:1.0", &frag),
+            &ComposeService::new("db", "mysql:5.7", &db),
+            &ComposeService::new("cache", "memcached", &cache),
+        ];
+        let running = [RunningService::new("api", "1.2.0")];
+
+        let compose = DockerCompose::build(&svcs, &running, &[], &ComposeOptions::default()).unwrap();
+        let manifest = compose.manifest("app", "test", None);
+
+        assert_eq!(Provenance::Reference, manifest.services["api"].provenance);
+        assert_eq!(Some("1.2.0".to_string()), manifest.services["api"].version);
+        assert_eq!(Provenance::Template, manifest.services["db"].provenance);
+        assert_eq!(Provenance::Unresolved, manifest.services["cache"].provenance);
+
+        let json = manifest.to_json().unwrap();
+        assert!(json.contains("\"schema-version\": 1"));
+        assert!(!json.contains("timestamp"));
+
+        let parsed: ResolutionManifest = serde_json::from_str(&json).unwrap();
+        assert_eq!(manifest, parsed);
+    }
+
+    #[test]
+    fn test_annotate_sources() {
+        let frag: ComposeServiceFragment = serde_yaml::from_str("image: example/api:1.0").unwrap();
+        let db: ComposeServiceFragment = serde_yaml::from_str("image: mysql:5.7").unwrap();
+
+        let svcs = [
+            &ComposeService::new("api", "example/api:1.0", &frag),
+            &ComposeService::new("db", "mysql:5.7", &db),
+        ];
+        let running = [RunningService::new("api", "1.2.0")];
+
+        let compose = DockerCompose::build(&svcs, &running, &[], &ComposeOptions::default()).unwrap();
+        let annotated = compose.annotate_sources(&compose.to_yaml().unwrap());
+        let lines = annotated.lines().collect::<Vec<_>>();
+
+        let api = lines.iter().position(|l| *l == "    image: example/api:1.2.0").unwrap();
+        assert_eq!("    # resolved from reference", lines[api - 1]);
+
+        let db = lines.iter().position(|l| *l == "    image: mysql:5.7").unwrap();
+        assert_eq!("    # resolved from template", lines[db - 1]);
+
+        assert_eq!(2, lines.iter().filter(|l| l.contains("# resolved from")).count());
+    }
+
+    #[test]
+    fn test_compose_version() {
+        let frag: ComposeServiceFragment = serde_yaml::from_str("image: mysql:5.7").unwrap();
+        let db = ComposeService::new("db", "mysql", &frag);
+
+        let generate = |compose_version: Option<&str>| {
+            let options = ComposeOptions {
+                compose_version: compose_version.map(|v| v.to_string()),
+                ..Default::default()
+            };
+            DockerCompose::generate(&[&db], &[], &[], &options).unwrap()
+        };
+
+        assert!(generate(None).starts_with("version: '3'\n"));
+        assert!(generate(Some("3.8")).starts_with("version: '3.8'\n"));
+        assert!(generate(Some("")).starts_with("services:\n"));
+        assert!(generate(Some("none")).starts_with("services:\n"));
+    }
+
+    #[test]
+    fn test_deterministic_output() {
+        let frag: ComposeServiceFragment = serde_yaml::from_str(
+            r#"
+image: example/api:1.0
+environment:
+  A: "1"
+  B: "2"
+  C: "3"
+  D: "4"
+  E: "5"
+  F: "6"
+labels:
+  com.example.a: a
+  com.example.b: b
+  com.example.c: c
+"#,
+        )
+        .unwrap();
+        let db: ComposeServiceFragment = serde_yaml::from_str("image: mysql:5.7").unwrap();
+
+        let generate = || {
+            let svcs = ["api", "cache", "db", "queue", "search", "web"]
+                .map(|n| ComposeService::new(n, "example/api", if n == "db" { &db } else { &frag }));
+            let svcs = svcs.iter().collect::<Vec<_>>();
+            let options = ComposeOptions {
+                deterministic: true,
+                ..Default::default()
+            };
+            DockerCompose::generate(&svcs, &[], &[], &options).unwrap()
+        };
+
+        let first = generate();
+        for _ in 0..10 {
+            assert_eq!(first, generate());
+        }
+        assert!(first.find("  api:").unwrap() < first.find("  web:").unwrap());
+    }
+
+    #[test]
+    fn test_render_template() {
+        let frag: ComposeServiceFragment = serde_yaml::from_str("image: mysql:5.7\nports:\n  - 3306:3306").unwrap();
+        let db = ComposeService::new("db", "mysql", &frag);
+        let compose = DockerCompose::build(&[&db], &[], &[], &ComposeOptions::default()).unwrap();
+
+        let template = r#"# {{ scenario }} ({{ env }})
+{% for name, s in services %}# {{ name }}: {{ s.version }} from {{ s.provenance }}
+{% endfor %}{% for name, svc in compose.services %}# {{ name }} publishes {{ svc.ports | length }} port(s)
+{% endfor %}{{ yaml }}"#;
+
+        let yaml = compose.to_yaml().unwrap();
+        let rendered = compose.render_template(template, &yaml, "data", "test").unwrap();
+
+        assert_eq!(
+            format!(
+                "# data (test)\n# db: 5.7 from template\n# db publishes 1 port(s)\n{}",
+                yaml
+            ),
+            rendered
+        );
+
+        let error = compose.render_template("{{ missing }}", &yaml, "data", "test").unwrap_err();
+        assert!(error.to_string().contains("missing"), "{}", error);
+    }
+
+    #[test]
+    fn test_is_stale() {
+        let now = Utc::now();
+        let threshold = chrono::Duration::days(7);
+
+        assert!(!is_stale(now - chrono::Duration::days(1), now, threshold));
+        assert!(!is_stale(now - threshold, now, threshold));
+        assert!(is_stale(now - chrono::Duration::days(8), now, threshold));
+        assert!(!is_stale(now + chrono::Duration::hours(1), now, threshold));
+    }
+
+    #[test]
+    fn test_cli_pin() {
+        assert_eq!(Some(("api".to_string(), "2.0.1".to_string())), parse_pin("api=2.0.1"));
+        for invalid in ["api", "api=", "=2.0.1", "api@2.0.1"] {
+            assert_eq!(None, parse_pin(invalid), "{}", invalid);
+        }
+
+        let frag: ComposeServiceFragment = serde_yaml::from_str("image: example/api:1.0.1").unwrap();
+        let svcs = [&ComposeService::new("api", "example/api", &frag)];
+        let running = [RunningService::new("api", "1.0.425")];
+        let local = [ContainerImage::from_json(
+            "api",
+            r#"{"CreatedAt":"2024-03-02 10:15:00 +0000 UTC","ID":"a1b2c3d4e5f6","Repository":"example/api","Size":"80MB","Tag":"1.0.500"}"#,
+        )];
+
+        // Without a pin the local image beats the running version.
+        let yaml = DockerCompose::generate(&svcs, &running, &local, &ComposeOptions::default()).unwrap();
+        assert!(yaml.contains("image: example/api:1.0.500"));
+
+        let mut options = ComposeOptions::default();
+        let (service, version) = parse_pin("api=debug-1234").unwrap();
+        options.pins.insert(service, version);
+
+        let yaml = DockerCompose::generate(&svcs, &running, &local, &options).unwrap();
+        assert!(yaml.contains("image: example/api:debug-1234"));
+    }
 }
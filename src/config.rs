@@ -1,13 +1,18 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::mem::swap;
 use std::path::Path;
 
+use log::*;
 use serde::{Deserialize, Deserializer};
 use regex::Regex;
 
+use crate::compose::{ObjectDeclaration, RegistryRewrite, VersionTransform};
+use crate::images::ImageSourceKind;
 use crate::reference::Reference;
-use crate::templates::{ComposeService, ComposeServiceMap};
+use crate::templates::{ComposeService, ComposeServiceMap, Healthcheck, Logging, ServiceDefaults};
+use crate::vcs::GitTagSource;
 use crate::volumes::VolumeInitializer;
+use crate::warnings::{Category, Warnings};
 
 use snafu::{ResultExt, Snafu};
 
@@ -74,6 +79,44 @@ pub enum Error {
         source: Box<Error>,
     },
 
+    #[snafu(display(r#"Unable to resolve always-include entry: {}\n{}"#, input, source))]
+    AlwaysInclude {
+        input: String,
+        #[snafu(source(from(Error, Box::new)))]
+        source: Box<Error>,
+    },
+
+    #[snafu(display(r#"Label selectors must have the form @key=value: {}"#, input))]
+    InvalidLabelSelector { input: String },
+
+    #[snafu(display(r#"No template has the label selected by: {}"#, input))]
+    NoLabelMatch { input: String },
+
+    #[snafu(display(r#"Cannot find template or scenario entries for: {}"#, names))]
+    UnknownServices { names: String },
+
+    #[snafu(display(r#"Invalid scenario expression, expecting scenarios separated by +, & or -: {}"#, input))]
+    InvalidScenarioExpression { input: String },
+
+    #[snafu(display(r#"Unknown scenario: {}"#, name))]
+    UnknownScenario { name: String },
+
+    #[snafu(display(r#"Unable to expand the environment variables of {}: {}\n{}"#, field, value, source))]
+    ExpandVariables {
+        field: String,
+        value: String,
+        source: shellexpand::LookupError<std::env::VarError>,
+    },
+
+    #[snafu(display(r#"The scenario {} includes an unknown scenario: {}"#, scenario, include))]
+    UnknownIncludedScenario { scenario: String, include: String },
+
+    #[snafu(display(r#"The scenario alias {} refers to an unknown scenario: {}"#, alias, scenario))]
+    UnknownAliasedScenario { alias: String, scenario: String },
+
+    #[snafu(display(r#"Cyclic dependency: {}"#, path.join(" -> ")))]
+    CyclicDependency { path: Vec<String> },
+
     #[snafu(display(
         r#"Unable to build scenario dependencies for the specified scenario\n{}"#,
         source
@@ -92,6 +135,33 @@ type Result<T, E = Error> = std::result::Result<T, E>;
 
 pub type ServiceList = Vec<String>;
 
+/// A service and the name of a service it depends on.
+pub type ServiceDependency = (String, String);
+
+/// The entries of a scenario, along with the other scenarios it builds on.
+#[derive(Debug, Default, Clone)]
+struct Scenario {
+    includes: Vec<String>,
+    services: ServiceList,
+}
+
+/// A scenario is either a plain list of entries or a map which may also include other scenarios.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ScenarioDefinition {
+    Services(ServiceList),
+    Composed(ComposedScenario),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ComposedScenario {
+    #[serde(default)]
+    includes: Vec<String>,
+    #[serde(default)]
+    services: ServiceList,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct HandelConfig {
@@ -103,18 +173,80 @@ pub struct HandelConfig {
 
     reference: Option<Reference>,
 
-    #[serde(deserialize_with = "de_scenarios")]
-    scenarios: HashMap<String, ServiceList>,
+    #[serde(default, deserialize_with = "de_scenarios")]
+    scenarios: HashMap<String, Scenario>,
+
+    import_scenarios: Option<String>,
+
+    scenario_aliases: Option<HashMap<String, String>>,
 
     volume_init: Option<Vec<VolumeInitializer>>,
+
+    volume_root: Option<String>,
+
+    registry_rewrites: Option<Vec<RegistryRewrite>>,
+
+    version_transform: Option<VersionTransform>,
+
+    default_version: Option<String>,
+
+    compose_version: Option<String>,
+
+    deterministic: Option<bool>,
+
+    default_logging: Option<Logging>,
+
+    git_tag: Option<GitTagSource>,
+
+    healthchecks: Option<HashMap<String, Healthcheck>>,
+
+    service_defaults: Option<ServiceDefaults>,
+
+    secrets: Option<BTreeMap<String, ObjectDeclaration>>,
+
+    configs: Option<BTreeMap<String, ObjectDeclaration>>,
+
+    startup_order: Option<Vec<String>>,
+
+    env_groups: Option<HashMap<String, HashMap<String, String>>>,
+
+    on_success: Option<Vec<String>>,
+
+    always_include: Option<Vec<String>>,
+
+    variables: Option<HashMap<String, String>>,
+
+    image_sources: Option<Vec<ImageSourceKind>>,
 }
 
-fn de_scenarios<'de, D>(deserializer: D) -> Result<HashMap<String, ServiceList>, D::Error>
+/// The only part of a file named by `import-scenarios` which is used.
+#[derive(Debug, Deserialize)]
+struct ImportedScenarios {
+    #[serde(default, deserialize_with = "de_scenarios")]
+    scenarios: HashMap<String, Scenario>,
+}
+
+fn de_scenarios<'de, D>(deserializer: D) -> Result<HashMap<String, Scenario>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let v = HashMap::<String, ServiceList>::deserialize(deserializer)?;
-    Ok(v)
+    let v = HashMap::<String, ScenarioDefinition>::deserialize(deserializer)?;
+
+    Ok(v.into_iter()
+        .map(|(name, definition)| {
+            let scenario = match definition {
+                ScenarioDefinition::Services(services) => Scenario {
+                    includes: Vec::new(),
+                    services,
+                },
+                ScenarioDefinition::Composed(c) => Scenario {
+                    includes: c.includes,
+                    services: c.services,
+                },
+            };
+            (name, scenario)
+        })
+        .collect())
 }
 
 fn de_port_range<'de, D>(deserializer: D) -> Result<Option<(u16,u16)>, D::Error>
@@ -140,20 +272,311 @@ fn de_port_range<'de, D>(deserializer: D) -> Result<Option<(u16,u16)>, D::Error>
     Ok(ports)
 }
 
+/// Parses a newline delimited list of service names, ignoring blank lines and `#` comments.
+pub fn parse_service_file(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.to_string())
+        .collect()
+}
+
+/// How an operand of a scenario expression is combined with the services resolved before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScenarioOp {
+    Union,
+    Intersection,
+    Difference,
+}
+
+impl ScenarioOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            ScenarioOp::Union => "+",
+            ScenarioOp::Intersection => "&",
+            ScenarioOp::Difference => "-",
+        }
+    }
+}
+
+/// Parses a scenario expression, e.g. `web + db - cache`, given as one or more arguments.  `+` is
+/// a union, `&` an intersection and `-` a difference, evaluated left to right.  Operators must be
+/// separated by whitespace as scenario names may contain `-`, and adjacent names are a union.
+pub fn parse_scenario_expression(args: &[&str]) -> Result<Vec<(ScenarioOp, String)>> {
+    let input = args.join(" ");
+    let invalid = || Error::InvalidScenarioExpression {
+        input: input.to_string(),
+    };
+
+    let mut terms = Vec::new();
+    let mut op = None;
+
+    for token in input.split_whitespace() {
+        let token_op = match token {
+            "+" => Some(ScenarioOp::Union),
+            "&" => Some(ScenarioOp::Intersection),
+            "-" => Some(ScenarioOp::Difference),
+            _ => None,
+        };
+
+        match (token_op, op) {
+            (Some(_), Some(_)) => return Err(invalid()),
+            (Some(_), None) if terms.is_empty() => return Err(invalid()),
+            (Some(o), None) => op = Some(o),
+            (None, o) => {
+                terms.push((o.unwrap_or(ScenarioOp::Union), token.to_string()));
+                op = None;
+            }
+        }
+    }
+
+    if terms.is_empty() || op.is_some() {
+        return Err(invalid());
+    }
+
+    Ok(terms)
+}
+
+/// Describes a parsed scenario expression compactly, e.g. `web+db-cache`, for messages and the
+/// HANDEL_SCENARIO variable.
+pub fn describe_scenario_expression(terms: &[(ScenarioOp, String)]) -> String {
+    terms
+        .iter()
+        .enumerate()
+        .map(|(i, (op, name))| match i {
+            0 => name.to_string(),
+            _ => format!("{}{}", op.symbol(), name),
+        })
+        .collect()
+}
+
+/// Splits a scenario entry into its name and any inline version pin, e.g. `contentrepo@1.0.400`.
+/// Label selectors, which start with an `@`, are never treated as pinned.
+pub fn split_pin(entry: &str) -> (&str, Option<&str>) {
+    match entry.split_once('@') {
+        Some((name, version)) if !name.is_empty() && !version.is_empty() => (name, Some(version)),
+        _ => (entry, None),
+    }
+}
+
 const EMPTY_SERVICE_LIST: &ServiceList = &Vec::<String>::new();
 
+/// Expands the environment variables of a path given in the config, e.g.
+/// `${PROJECT_ROOT}/templates`, failing rather than leaving an unset variable in place.
+fn expand_path(field: &str, value: &str) -> Result<String> {
+    shellexpand::env(value)
+        .map(|v| v.to_string())
+        .context(ExpandVariables {
+            field: field.to_string(),
+            value: value.to_string(),
+        })
+}
+
+/// Resolves the `import-scenarios` path of a config file against the file's directory.
+fn import_relative_to(file_name: &str, import: &str) -> Result<String> {
+    let dir = Path::new(file_name).parent().unwrap_or_else(|| Path::new(""));
+    let import = expand_path("import-scenarios", import)?;
+    Ok(dir.join(import).to_string_lossy().to_string())
+}
+
+/// Deep merges a later config document over an earlier one.  Scenarios are merged by name, a
+/// later scenario replacing an earlier one, volume-init entries are concatenated, other maps are
+/// merged recursively and anything else is overwritten.
+fn merge_config_values(base: &mut serde_yaml::Value, overlay: serde_yaml::Value, top_level: bool) {
+    use serde_yaml::Value;
+
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                let merged = match (key.as_str(), base.get_mut(&key)) {
+                    (Some("scenarios"), Some(Value::Mapping(scenarios))) if top_level => match value {
+                        Value::Mapping(overlay) => {
+                            scenarios.extend(overlay);
+                            continue;
+                        }
+                        other => other,
+                    },
+                    (Some("volume-init"), Some(Value::Sequence(volumes))) if top_level => match value {
+                        Value::Sequence(overlay) => {
+                            volumes.extend(overlay);
+                            continue;
+                        }
+                        other => other,
+                    },
+                    (_, Some(existing @ Value::Mapping(_))) if value.is_mapping() => {
+                        merge_config_values(existing, value, false);
+                        continue;
+                    }
+                    _ => value,
+                };
+
+                base.insert(key, merged);
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
 impl HandelConfig {
-    pub fn new(file_name: &str) -> Result<HandelConfig> {
-        let raw_file =
-            crate::utils::read_file_contents(Path::new(&file_name)).context(ReadConfig {
+    /// Reads the given config files, merging each over those before it, e.g. a team's overlay
+    /// over a shared base.
+    pub fn new(file_names: &[&str], warnings: &Warnings) -> Result<HandelConfig> {
+        let sources = file_names
+            .iter()
+            .map(|file_name| {
+                crate::utils::read_file_contents(Path::new(file_name))
+                    .context(ReadConfig {
+                        file: file_name.to_string(),
+                    })
+                    .map(|contents| (*file_name, contents))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut config = HandelConfig::from_sources(&sources)?;
+
+        // The import path has already been resolved against the directory of its file.
+        config.merge_imported_scenarios(Path::new(""))?;
+        config.check_scenario_aliases(warnings)?;
+
+        Ok(config)
+    }
+
+    /// Parses the contents of one or more config files, given along with their names.  The
+    /// environment variables of the template folder and import paths are expanded.
+    fn from_sources(sources: &[(&str, String)]) -> Result<HandelConfig> {
+        let mut config = HandelConfig::merge_sources(sources)?;
+        config.template_folder_path = expand_path("template-folder-path", &config.template_folder_path)?;
+        config.volume_root = config
+            .volume_root
+            .map(|r| expand_path("volume-root", &r))
+            .transpose()?;
+        Ok(config)
+    }
+
+    fn merge_sources(sources: &[(&str, String)]) -> Result<HandelConfig> {
+        if let [(file_name, contents)] = sources {
+            let mut config: HandelConfig = serde_yaml::from_str(contents).context(ParseConfig {
                 file: file_name.to_string(),
             })?;
+            config.import_scenarios = config
+                .import_scenarios
+                .map(|i| import_relative_to(file_name, &i))
+                .transpose()?;
+            return Ok(config);
+        }
+
+        let mut merged = serde_yaml::Value::Mapping(serde_yaml::Mapping::new());
 
-        let config: HandelConfig = serde_yaml::from_str(&raw_file).context(ParseConfig {
-            file: file_name.to_string(),
+        for (file_name, contents) in sources {
+            let mut document: serde_yaml::Value = serde_yaml::from_str(contents).context(ParseConfig {
+                file: file_name.to_string(),
+            })?;
+
+            if let Some(import) = document.get_mut("import-scenarios") {
+                if let Some(i) = import.as_str() {
+                    *import = serde_yaml::Value::String(import_relative_to(file_name, i)?);
+                }
+            }
+
+            merge_config_values(&mut merged, document, true);
+        }
+
+        serde_yaml::from_value(merged).context(ParseConfig {
+            file: sources.iter().map(|(f, _)| *f).collect::<Vec<_>>().join(" + "),
+        })
+    }
+
+    /// Checks that every scenario alias refers to a scenario, warning about any alias which
+    /// shadows a scenario of the same name as the scenario is always used in that case.
+    fn check_scenario_aliases(&self, warnings: &Warnings) -> Result<()> {
+        let mut aliases = self.scenario_aliases.iter().flatten().collect::<Vec<_>>();
+        aliases.sort();
+
+        for (alias, scenario) in aliases {
+            if !self.has_scenario(scenario) {
+                return Err(Error::UnknownAliasedScenario {
+                    alias: alias.clone(),
+                    scenario: scenario.clone(),
+                });
+            }
+
+            if self.has_scenario(alias) {
+                let message = format!(
+                    "The scenario alias {} is ignored as a scenario of that name exists",
+                    alias
+                );
+                warnings.warn(Category::Config, &message, None, None);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The scenario named directly or, failing that, by an alias.
+    pub fn resolve_scenario_alias<'a>(self: &'a HandelConfig, name: &'a str) -> &'a str {
+        match self.has_scenario(name) {
+            true => name,
+            false => self
+                .scenario_aliases
+                .as_ref()
+                .and_then(|a| a.get(name))
+                .map(|s| s.as_str())
+                .unwrap_or(name),
+        }
+    }
+
+    /// Adds the scenarios of the `import-scenarios` file, resolved against the given directory,
+    /// which aren't defined locally.  A local scenario of the same name replaces the imported one.
+    fn merge_imported_scenarios(&mut self, dir: &Path) -> Result<()> {
+        let file = match &self.import_scenarios {
+            Some(f) => dir.join(f),
+            None => return Ok(()),
+        };
+        let display = file.display().to_string();
+
+        let raw_file = crate::utils::read_file_contents(&file).context(ReadConfig {
+            file: display.clone(),
         })?;
 
-        Ok(config)
+        let imported: ImportedScenarios = serde_yaml::from_str(&raw_file).context(ParseConfig {
+            file: display.clone(),
+        })?;
+
+        for (name, services) in imported.scenarios {
+            match self.scenarios.contains_key(&name) {
+                true => debug!(
+                    "{} - the scenario {} imported from {} is overridden locally",
+                    module_path!(),
+                    &name,
+                    &display
+                ),
+                false => {
+                    self.scenarios.insert(name, services);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resolves a relative template folder against the given directory, e.g. that of a bundle.
+    pub fn relative_to(mut self, dir: &Path) -> HandelConfig {
+        let in_image = self.template_folder_path.starts_with(crate::oci::OCI_SCHEME);
+
+        if Path::new(&self.template_folder_path).is_relative() && !in_image {
+            self.template_folder_path = dir
+                .join(&self.template_folder_path)
+                .to_string_lossy()
+                .to_string();
+        }
+        self
+    }
+
+    /// Replaces the template folder path, e.g. with a copy of the templates held by an image.
+    pub fn with_template_dir(mut self, dir: &Path) -> HandelConfig {
+        self.template_folder_path = dir.to_string_lossy().to_string();
+        self
     }
 
     pub fn template_dir(self: &HandelConfig) -> &str {
@@ -172,6 +595,78 @@ impl HandelConfig {
         &self.volume_init
     }
 
+    pub fn volume_root(self: &HandelConfig) -> &Option<String> {
+        &self.volume_root
+    }
+
+    pub fn registry_rewrites(self: &HandelConfig) -> &Option<Vec<RegistryRewrite>> {
+        &self.registry_rewrites
+    }
+
+    pub fn version_transform(self: &HandelConfig) -> &Option<VersionTransform> {
+        &self.version_transform
+    }
+
+    pub fn default_version(self: &HandelConfig) -> &Option<String> {
+        &self.default_version
+    }
+
+    pub fn compose_version(self: &HandelConfig) -> &Option<String> {
+        &self.compose_version
+    }
+
+    /// Whether the output is normalised for reproducible diffs, false by default.
+    pub fn deterministic(self: &HandelConfig) -> bool {
+        self.deterministic.unwrap_or(false)
+    }
+
+    pub fn default_logging(self: &HandelConfig) -> &Option<Logging> {
+        &self.default_logging
+    }
+
+    pub fn git_tag(self: &HandelConfig) -> &Option<GitTagSource> {
+        &self.git_tag
+    }
+
+    pub fn healthchecks(self: &HandelConfig) -> &Option<HashMap<String, Healthcheck>> {
+        &self.healthchecks
+    }
+
+    pub fn service_defaults(self: &HandelConfig) -> &Option<ServiceDefaults> {
+        &self.service_defaults
+    }
+
+    pub fn secrets(self: &HandelConfig) -> &Option<BTreeMap<String, ObjectDeclaration>> {
+        &self.secrets
+    }
+
+    pub fn configs(self: &HandelConfig) -> &Option<BTreeMap<String, ObjectDeclaration>> {
+        &self.configs
+    }
+
+    pub fn startup_order(self: &HandelConfig) -> &Option<Vec<String>> {
+        &self.startup_order
+    }
+
+    pub fn env_groups(self: &HandelConfig) -> &Option<HashMap<String, HashMap<String, String>>> {
+        &self.env_groups
+    }
+
+    pub fn on_success(self: &HandelConfig) -> &Option<Vec<String>> {
+        &self.on_success
+    }
+
+    /// The tools queried for local images, docker unless configured otherwise.
+    pub fn image_sources(self: &HandelConfig) -> Vec<ImageSourceKind> {
+        self.image_sources
+            .clone()
+            .unwrap_or_else(|| vec![ImageSourceKind::Docker])
+    }
+
+    pub fn variables(self: &HandelConfig) -> &Option<HashMap<String, String>> {
+        &self.variables
+    }
+
     pub fn get_scenarios(self: &HandelConfig) -> Vec<String> {
         let mut scenarios = Vec::new();
 
@@ -185,24 +680,113 @@ impl HandelConfig {
     }
 
     pub fn scenario_services(self: &HandelConfig, scenario: &str) -> &ServiceList {
-        self.scenarios.get(scenario).unwrap_or(EMPTY_SERVICE_LIST)
+        self.scenarios.get(scenario).map_or(EMPTY_SERVICE_LIST, |s| &s.services)
+    }
+
+    /// The scenarios which the given scenario builds on, resolved before its own services.
+    pub fn scenario_includes(self: &HandelConfig, scenario: &str) -> &ServiceList {
+        self.scenarios.get(scenario).map_or(EMPTY_SERVICE_LIST, |s| &s.includes)
     }
 
     pub fn has_scenario(self: &HandelConfig, scenario: &str) -> bool {
         self.scenarios.contains_key(scenario)
     }
 
+    /// Resolves the union of the services of the given scenarios, a service referenced by more
+    /// than one of them being included once.
     pub fn build_service_list<'a>(
         self: &'a HandelConfig,
-        scenario: &str,
+        scenarios: &[&str],
         templates: &'a ComposeServiceMap,
     ) -> Result<Vec<&'a ComposeService>, Error> {
         let mut svcs: HashMap<String, &'a ComposeService> = HashMap::new();
 
-        self.build_services_recursive(scenario, &mut svcs, templates)
-            .context(ScenarioDeps {
-                scenario: scenario.to_string(),
-            })?;
+        for scenario in scenarios {
+            self.build_services_recursive(scenario, &mut svcs, templates, &mut Vec::new())
+                .context(ScenarioDeps {
+                    scenario: scenario.to_string(),
+                })?;
+        }
+
+        self.finish_service_list(svcs, templates)
+    }
+
+    /// Resolves a parsed scenario expression, combining the services of each scenario (and their
+    /// dependencies) with those resolved so far.  `always-include` entries are added afterwards,
+    /// so are never removed by a difference.
+    pub fn build_service_list_from_expression<'a>(
+        self: &'a HandelConfig,
+        terms: &[(ScenarioOp, String)],
+        templates: &'a ComposeServiceMap,
+    ) -> Result<Vec<&'a ComposeService>, Error> {
+        let mut svcs: HashMap<String, &'a ComposeService> = HashMap::new();
+
+        for (op, name) in terms {
+            if !self.has_scenario(name) {
+                return Err(Error::UnknownScenario {
+                    name: name.to_string(),
+                });
+            }
+
+            let mut operand = HashMap::new();
+            self.build_services_recursive(name, &mut operand, templates, &mut Vec::new())
+                .context(ScenarioDeps {
+                    scenario: name.to_string(),
+                })?;
+
+            match op {
+                ScenarioOp::Union => svcs.extend(operand),
+                ScenarioOp::Intersection => svcs.retain(|s, _| operand.contains_key(s)),
+                ScenarioOp::Difference => svcs.retain(|s, _| !operand.contains_key(s)),
+            }
+        }
+
+        self.finish_service_list(svcs, templates)
+    }
+
+    /// Resolves an ad-hoc list of service or scenario names, e.g. read from a `--service-file`,
+    /// in the same way as the entries of a scenario.  All unknown names are reported together.
+    pub fn build_service_list_from_names<'a>(
+        self: &'a HandelConfig,
+        names: &[String],
+        templates: &'a ComposeServiceMap,
+    ) -> Result<Vec<&'a ComposeService>, Error> {
+        let unknown = names
+            .iter()
+            .filter(|n| {
+                let (name, _) = split_pin(n);
+                !n.starts_with('@')
+                    && templates.get_service_fragment(name).is_none()
+                    && !self.has_scenario(name)
+            })
+            .map(|n| n.as_str())
+            .collect::<Vec<_>>();
+
+        if !unknown.is_empty() {
+            return Err(Error::UnknownServices {
+                names: unknown.join(", "),
+            });
+        }
+
+        let mut svcs: HashMap<String, &'a ComposeService> = HashMap::new();
+
+        for n in names {
+            self.build_services_recursive(n, &mut svcs, templates, &mut Vec::new())
+                .context(ServiceDeps { service: n.clone() })?;
+        }
+
+        self.finish_service_list(svcs, templates)
+    }
+
+    fn finish_service_list<'a>(
+        self: &'a HandelConfig,
+        mut svcs: HashMap<String, &'a ComposeService>,
+        templates: &'a ComposeServiceMap,
+    ) -> Result<Vec<&'a ComposeService>, Error> {
+        for s in self.always_include.iter().flatten() {
+            self.build_services_recursive(s, &mut svcs, templates, &mut Vec::new())
+                .context(AlwaysInclude { input: s.to_string() })?;
+        }
 
         let mut svcs_list = Vec::new();
 
@@ -215,36 +799,257 @@ impl HandelConfig {
         Ok(svcs_list)
     }
 
+    /// Restricts an already resolved service list to the given service names, optionally
+    /// pulling in the transitive dependencies of each named service.
+    pub fn filter_service_list<'a>(
+        self: &'a HandelConfig,
+        resolved: Vec<&'a ComposeService>,
+        services: &[&str],
+        include_depends: bool,
+        templates: &'a ComposeServiceMap,
+    ) -> Result<Vec<&'a ComposeService>, Error> {
+        let resolved_names = resolved.iter().map(|s| s.name()).collect::<Vec<_>>();
+        let mut svcs: HashMap<String, &'a ComposeService> = HashMap::new();
+
+        for s in services {
+            if !resolved_names.iter().any(|n| n == s) {
+                return Err(Error::NotFound {
+                    input: s.to_string(),
+                });
+            }
+
+            if include_depends {
+                self.build_services_recursive(s, &mut svcs, templates, &mut Vec::new())
+                    .context(ServiceDeps {
+                        service: s.to_string(),
+                    })?;
+            }
+        }
+
+        let mut svcs_list = resolved
+            .into_iter()
+            .filter(|r| svcs.contains_key(&r.name()) || services.contains(&r.name().as_str()))
+            .collect::<Vec<_>>();
+
+        svcs_list.sort();
+
+        Ok(svcs_list)
+    }
+
+    /// Removes the given services from an already resolved service list, returning the remaining
+    /// services, with any dependency upon a removed service dropped, along with each remaining
+    /// service which depended on a removed one and that dependency, so they can be warned about.
+    pub fn exclude_services(
+        self: &HandelConfig,
+        resolved: Vec<&ComposeService>,
+        excluded: &[&str],
+    ) -> Result<(Vec<ComposeService>, Vec<ServiceDependency>), Error> {
+        for e in excluded {
+            if !resolved.iter().any(|s| s.name() == *e) {
+                return Err(Error::NotFound {
+                    input: e.to_string(),
+                });
+            }
+        }
+
+        let remaining = resolved
+            .into_iter()
+            .filter(|s| !excluded.contains(&s.name().as_str()))
+            .collect::<Vec<_>>();
+
+        let dependents = remaining
+            .iter()
+            .flat_map(|s| {
+                s.get_dependencies()
+                    .into_iter()
+                    .filter(|d| excluded.contains(&d.as_str()))
+                    .map(move |d| (s.name(), d))
+            })
+            .collect();
+
+        let remaining = remaining
+            .into_iter()
+            .map(|s| s.without_dependencies(excluded))
+            .collect();
+
+        Ok((remaining, dependents))
+    }
+
+    /// Lists, sorted, every scenario (or `always-include`) entry which doesn't resolve to a
+    /// template, a scenario or, for a label selector, at least one template, along with the
+    /// scenario referencing it.  Included scenarios must be scenarios.
+    pub fn missing_references(self: &HandelConfig, templates: &ComposeServiceMap) -> Vec<(String, String)> {
+        let resolves = |entry: &str| match entry.strip_prefix('@') {
+            Some(selector) => selector
+                .split_once('=')
+                .is_some_and(|(key, value)| !templates.with_label(key, value).is_empty()),
+            None => {
+                let (name, _) = split_pin(entry);
+                self.has_scenario(name)
+                    || templates.get_service_fragment(name).is_some()
+                    || templates.is_disabled(name)
+            }
+        };
+
+        let scenarios = self.scenarios.iter().flat_map(|(name, scenario)| {
+            let includes = scenario
+                .includes
+                .iter()
+                .filter(|i| !self.has_scenario(i))
+                .map(move |i| (name.clone(), i.clone()));
+
+            let services = scenario
+                .services
+                .iter()
+                .filter(|s| !resolves(s))
+                .map(move |s| (name.clone(), s.clone()));
+
+            includes.chain(services).collect::<Vec<_>>()
+        });
+
+        let always_include = self
+            .always_include
+            .iter()
+            .flatten()
+            .filter(|s| !resolves(s))
+            .map(|s| ("always-include".to_string(), s.clone()));
+
+        let mut missing = scenarios.chain(always_include).collect::<Vec<_>>();
+        missing.sort();
+        missing
+    }
+
+    /// Collects the inline version pins of the given entries and, recursively, of the scenarios
+    /// they name along with the always-include entries.  The first pin for a service wins.
+    pub fn collect_pins(self: &HandelConfig, entries: &[String]) -> HashMap<String, String> {
+        let mut pins = HashMap::new();
+        let mut visited = HashSet::new();
+
+        let always_include = self.always_include.iter().flatten();
+
+        for e in entries.iter().chain(always_include) {
+            self.collect_pins_recursive(e, &mut pins, &mut visited);
+        }
+
+        pins
+    }
+
+    fn collect_pins_recursive(
+        self: &HandelConfig,
+        entry: &str,
+        pins: &mut HashMap<String, String>,
+        visited: &mut HashSet<String>,
+    ) {
+        let (name, version) = split_pin(entry);
+
+        if let Some(v) = version {
+            pins.entry(name.to_string()).or_insert_with(|| v.to_string());
+        } else if self.has_scenario(name) && visited.insert(name.to_string()) {
+            for s in self.scenario_includes(name).iter().chain(self.scenario_services(name)) {
+                self.collect_pins_recursive(s, pins, visited);
+            }
+        }
+    }
+
+    /// Adds the services of the given entry to `svcs`.  The path holds the entries currently
+    /// being resolved, so that an entry which (transitively) references itself is reported
+    /// rather than recursing forever.
     fn build_services_recursive<'a>(
         self: &HandelConfig,
         parent: &str,
         svcs: &mut HashMap<String, &'a ComposeService>,
         templates: &'a ComposeServiceMap,
+        path: &mut Vec<String>,
+    ) -> Result<()> {
+        let (parent, _) = split_pin(parent);
+
+        if let Some(e) = cyclic_dependency(path, parent) {
+            return Err(e);
+        }
+
+        path.push(parent.to_string());
+        let result = self.build_entry_services(parent, svcs, templates, path);
+        path.pop();
+
+        result
+    }
+
+    fn build_entry_services<'a>(
+        self: &HandelConfig,
+        parent: &str,
+        svcs: &mut HashMap<String, &'a ComposeService>,
+        templates: &'a ComposeServiceMap,
+        path: &mut Vec<String>,
     ) -> Result<()> {
+        if let Some(selector) = parent.strip_prefix('@') {
+            let (key, value) = selector.split_once('=').ok_or(Error::InvalidLabelSelector {
+                input: parent.to_string(),
+            })?;
+
+            let labelled = templates.with_label(key, value);
+
+            if labelled.is_empty() {
+                return Err(Error::NoLabelMatch {
+                    input: parent.to_string(),
+                });
+            }
+
+            for s in labelled {
+                if svcs.contains_key(&s.name()) {
+                    continue;
+                }
+
+                self.build_services_recursive(&s.name(), svcs, templates, path)
+                    .context(ServiceDeps { service: s.name() })?;
+            }
+
+            return Ok(());
+        }
+
         let fragment = templates.get_service_fragment(parent);
 
         if let Some(f) = fragment {
             svcs.insert(parent.to_string(), f);
             for d in f.get_dependencies() {
+                if let Some(e) = cyclic_dependency(path, &d) {
+                    return Err(e);
+                }
+
                 if !svcs.contains_key(&d) && templates.get_service_fragment(&d).is_some() {
                     svcs.insert(d.to_string(), templates.get_service_fragment(&d).unwrap());
-                    self.build_services_recursive(&d, svcs, templates)
+                    self.build_services_recursive(&d, svcs, templates, path)
                         .context(ServiceDeps { service: d.clone() })?;
                 }
             }
         } else if self.scenarios.contains_key(parent) {
+            for include in self.scenario_includes(parent) {
+                if !self.has_scenario(include) {
+                    return Err(Error::UnknownIncludedScenario {
+                        scenario: parent.to_string(),
+                        include: include.clone(),
+                    });
+                }
+
+                self.build_services_recursive(include, svcs, templates, path)
+                    .context(ScenarioDeps {
+                        scenario: include.clone(),
+                    })?;
+            }
+
             let services = self.scenario_services(parent);
 
             for s in services {
-                if svcs.contains_key(s) {
+                if svcs.contains_key(split_pin(s).0) {
                     continue;
                 }
 
-                self.build_services_recursive(s, svcs, templates)
+                self.build_services_recursive(s, svcs, templates, path)
                     .context(ScenarioDeps {
                         scenario: s.clone(),
                     })?;
             }
+        } else if templates.is_disabled(parent) {
+            info!("{} - skipping disabled service {}", module_path!(), parent);
         } else {
             return Err(Error::NotFound {
                 input: parent.to_string(),
@@ -255,11 +1060,593 @@ impl HandelConfig {
     }
 }
 
+/// Reports the chain from the entry's first appearance on the path back to the entry itself,
+/// e.g. `a -> b -> a`, if it is already being resolved.
+fn cyclic_dependency(path: &[String], entry: &str) -> Option<Error> {
+    path.iter().position(|p| p == entry).map(|start| {
+        let mut cycle = path[start..].to_vec();
+        cycle.push(entry.to_string());
+        Error::CyclicDependency { path: cycle }
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::templates::{ComposeServiceFragment, DependsOn};
     use serde_yaml;
 
+    /// A service read from the given fragment yaml, named after the template.
+    fn service(name: &str, yaml: &str) -> ComposeService {
+        let frag: ComposeServiceFragment = serde_yaml::from_str(yaml).unwrap();
+        ComposeService::new(name, &frag.image, &frag)
+    }
+
+    fn test_templates() -> ComposeServiceMap {
+        ComposeServiceMap::from_services(vec![
+            service("api", "image: example/api:1.0\ndepends_on:\n  - db"),
+            service("db", "image: mysql:5.7"),
+            service("cache", "image: memcached:1.6.7"),
+        ])
+    }
+
+    fn test_config() -> HandelConfig {
+        let t = r#"
+template-folder-path: .
+scenarios:
+  app:
+    - api
+    - cache
+"#;
+        serde_yaml::from_str(t).unwrap()
+    }
+
+    fn names(svcs: &[&ComposeService]) -> Vec<String> {
+        svcs.iter().map(|s| s.name()).collect()
+    }
+
+    #[test]
+    fn test_filter_services_literal() {
+        let templates = test_templates();
+        let config = test_config();
+        let resolved = config.build_service_list(&["app"], &templates).unwrap();
+        assert_eq!(vec!["api", "cache", "db"], names(&resolved));
+
+        let filtered = config
+            .filter_service_list(resolved, &["api"], false, &templates)
+            .unwrap();
+        assert_eq!(vec!["api"], names(&filtered));
+    }
+
+    #[test]
+    fn test_disabled_template_excluded() {
+        let templates = ComposeServiceMap::from_services(vec![
+            service("api", "image: example/api:1.0\ndepends_on:\n  - db"),
+            service("db", "image: mysql:5.7\nx-handel:\n  disabled: true"),
+            service("cache", "image: memcached:1.6.7"),
+        ]);
+
+        let t = r#"
+template-folder-path: .
+scenarios:
+  app:
+    - api
+    - db
+    - cache
+"#;
+        let config: HandelConfig = serde_yaml::from_str(t).unwrap();
+        let resolved = config.build_service_list(&["app"], &templates).unwrap();
+        assert_eq!(vec!["api", "cache"], names(&resolved));
+
+        let missing = templates.missing_dependencies(&resolved);
+        assert_eq!(1, missing.len());
+        assert_eq!("db", missing[0].1);
+    }
+
+    #[test]
+    fn test_multiple_scenarios() {
+        let templates = test_templates();
+        let t = r#"
+template-folder-path: .
+scenarios:
+  web:
+    - api
+  data:
+    - db
+    - cache
+"#;
+        let config: HandelConfig = serde_yaml::from_str(t).unwrap();
+
+        let resolved = config.build_service_list(&["web", "data"], &templates).unwrap();
+        assert_eq!(vec!["api", "cache", "db"], names(&resolved));
+    }
+
+    #[test]
+    fn test_cyclic_scenarios() {
+        let templates = test_templates();
+        let t = r#"
+template-folder-path: .
+scenarios:
+  a:
+    - cache
+    - b
+  b:
+    - a
+"#;
+        let config: HandelConfig = serde_yaml::from_str(t).unwrap();
+
+        let err = config.build_service_list(&["a"], &templates).unwrap_err();
+        assert!(err.to_string().contains("Cyclic dependency: a -> b -> a"), "{}", err);
+    }
+
+    #[test]
+    fn test_scenario_includes() {
+        let templates = test_templates();
+        let t = r#"
+template-folder-path: .
+scenarios:
+  data:
+    - db
+  backend:
+    includes: [ data ]
+    services:
+      - api
+  full:
+    includes:
+      - backend
+    services:
+      - cache@1.6.9
+  broken:
+    includes: [ api ]
+"#;
+        let config: HandelConfig = serde_yaml::from_str(t).unwrap();
+
+        assert_eq!(vec!["api", "db"], names(&config.build_service_list(&["backend"], &templates).unwrap()));
+        assert_eq!(
+            vec!["api", "cache", "db"],
+            names(&config.build_service_list(&["full"], &templates).unwrap())
+        );
+        assert_eq!(Some(&"1.6.9".to_string()), config.collect_pins(&["full".to_string()]).get("cache"));
+
+        let err = config.build_service_list(&["broken"], &templates).unwrap_err();
+        assert!(err.to_string().contains("includes an unknown scenario: api"), "{}", err);
+
+        let cyclic = "template-folder-path: .\nscenarios:\n  a:\n    includes: [ b ]\n  b:\n    includes: [ a ]\n";
+        let config: HandelConfig = serde_yaml::from_str(cyclic).unwrap();
+        let err = config.build_service_list(&["a"], &templates).unwrap_err();
+        assert!(err.to_string().contains("Cyclic dependency: a -> b -> a"), "{}", err);
+    }
+
+    #[test]
+    fn test_missing_references() {
+        let templates = test_templates();
+        let t = r#"
+template-folder-path: .
+always-include:
+  - cahce
+scenarios:
+  data:
+    - db
+    - '@tier=data'
+  app:
+    includes: [ data, api ]
+    services:
+      - data
+      - api@1.2.0
+      - apl
+"#;
+        let config: HandelConfig = serde_yaml::from_str(t).unwrap();
+
+        assert_eq!(
+            vec![
+                ("always-include".to_string(), "cahce".to_string()),
+                ("app".to_string(), "api".to_string()),
+                ("app".to_string(), "apl".to_string()),
+                ("data".to_string(), "@tier=data".to_string()),
+            ],
+            config.missing_references(&templates)
+        );
+        assert!(test_config().missing_references(&templates).is_empty());
+    }
+
+    #[test]
+    fn test_cyclic_service_dependencies() {
+        let templates = ComposeServiceMap::from_services(vec![
+            service("api", "image: example/api:1.0\ndepends_on:\n  - db"),
+            service("db", "image: mysql:5.7\ndepends_on:\n  - api"),
+        ]);
+
+        let err = test_config().build_service_list(&["app"], &templates).unwrap_err();
+        assert!(err.to_string().contains("Cyclic dependency: api -> db -> api"), "{}", err);
+    }
+
+    #[test]
+    fn test_import_scenarios() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("shared")).unwrap();
+        std::fs::write(
+            dir.path().join("shared/base-handel.yml"),
+            "template-folder-path: elsewhere\nscenarios:\n  data:\n    - db\n  web:\n    - api\n",
+        )
+        .unwrap();
+
+        let config_file = dir.path().join("handel.yml");
+        std::fs::write(
+            &config_file,
+            "template-folder-path: .\nimport-scenarios: shared/base-handel.yml\nscenarios:\n  web:\n    - cache\n",
+        )
+        .unwrap();
+
+        let config = HandelConfig::new(&[config_file.to_str().unwrap()], &Warnings::default()).unwrap();
+        let templates = test_templates();
+
+        assert_eq!(".", config.template_dir());
+        assert_eq!(vec!["db"], names(&config.build_service_list(&["data"], &templates).unwrap()));
+        assert_eq!(vec!["cache"], names(&config.build_service_list(&["web"], &templates).unwrap()));
+    }
+
+    #[test]
+    fn test_merge_configs() {
+        let base = r#"
+template-folder-path: ./templates
+port-range: 9000-9100
+scenarios:
+  data:
+    - db
+  app:
+    - api
+volume-init:
+  - name: base
+    source: base.zip
+    target: ./data/base
+"#;
+        let overlay = r#"
+template-folder-path: ./team/templates
+scenarios:
+  app:
+    - api
+    - cache
+  search:
+    - cache
+volume-init:
+  - name: team
+    source: team.zip
+    target: ./data/team
+"#;
+        let config = HandelConfig::from_sources(&[
+            ("handel.yml", base.to_string()),
+            ("team/handel.yml", overlay.to_string()),
+        ])
+        .unwrap();
+        let templates = test_templates();
+
+        assert_eq!("./team/templates", config.template_dir());
+        assert_eq!(Some((9000, 9100)), config.get_port_range());
+        assert_eq!(vec!["app", "data", "search"], config.get_scenarios());
+        assert_eq!(vec!["api", "cache", "db"], names(&config.build_service_list(&["app"], &templates).unwrap()));
+        assert_eq!(
+            vec!["base", "team"],
+            config.volumes().iter().flatten().map(|v| v.name.as_str()).collect::<Vec<_>>()
+        );
+
+        let err = HandelConfig::from_sources(&[
+            ("handel.yml", base.to_string()),
+            ("team/handel.yml", "scenarios: [".to_string()),
+        ])
+        .unwrap_err();
+        assert!(err.to_string().contains("team/handel.yml"), "{}", err);
+    }
+
+    #[test]
+    fn test_expand_config_variables() {
+        std::env::set_var("HANDEL_TEST_PROJECT_ROOT", "/srv/project");
+
+        let config = HandelConfig::from_sources(&[(
+            "handel.yml",
+            "template-folder-path: ${HANDEL_TEST_PROJECT_ROOT}/templates\nimport-scenarios: $HANDEL_TEST_PROJECT_ROOT/base.yml\n"
+                .to_string(),
+        )])
+        .unwrap();
+        assert_eq!("/srv/project/templates", config.template_dir());
+        assert_eq!(Some("/srv/project/base.yml"), config.import_scenarios.as_deref());
+
+        let err = HandelConfig::from_sources(&[(
+            "handel.yml",
+            "template-folder-path: ${HANDEL_TEST_UNSET_ROOT}/templates\n".to_string(),
+        )])
+        .unwrap_err();
+        assert!(matches!(err, Error::ExpandVariables { .. }));
+        assert!(err.to_string().contains("HANDEL_TEST_UNSET_ROOT"), "{}", err);
+    }
+
+    fn expression_config() -> HandelConfig {
+        let t = r#"
+template-folder-path: .
+always-include:
+  - cache
+scenarios:
+  web:
+    - api
+  data:
+    - db
+  caching:
+    - cache
+"#;
+        serde_yaml::from_str(t).unwrap()
+    }
+
+    #[test]
+    fn test_scenario_aliases() {
+        let mut config = expression_config();
+        config.scenario_aliases = Some(HashMap::from([
+            ("w".to_string(), "web".to_string()),
+            ("data".to_string(), "web".to_string()),
+        ]));
+        assert!(config.check_scenario_aliases(&Warnings::default()).is_ok());
+
+        assert_eq!("web", config.resolve_scenario_alias("w"));
+        assert_eq!("web", config.resolve_scenario_alias("web"));
+        assert_eq!("data", config.resolve_scenario_alias("data"));
+        assert_eq!("unknown", config.resolve_scenario_alias("unknown"));
+
+        config.scenario_aliases = Some(HashMap::from([("x".to_string(), "missing".to_string())]));
+        assert!(matches!(
+            config.check_scenario_aliases(&Warnings::default()),
+            Err(Error::UnknownAliasedScenario { .. })
+        ));
+    }
+
+    fn evaluate(config: &HandelConfig, expression: &str) -> Vec<String> {
+        let templates = test_templates();
+        let terms = parse_scenario_expression(&[expression]).unwrap();
+        names(&config.build_service_list_from_expression(&terms, &templates).unwrap())
+    }
+
+    #[test]
+    fn test_scenario_expression_union() {
+        let config = expression_config();
+        assert_eq!(vec!["api", "cache", "db"], evaluate(&config, "web + data"));
+        assert_eq!(vec!["api", "cache", "db"], evaluate(&config, "web data"));
+    }
+
+    #[test]
+    fn test_scenario_expression_intersection() {
+        let config = expression_config();
+        // web includes db as a dependency of api.
+        assert_eq!(vec!["cache", "db"], evaluate(&config, "web & data"));
+        assert_eq!(vec!["cache"], evaluate(&config, "data & caching"));
+    }
+
+    #[test]
+    fn test_scenario_expression_difference() {
+        let config = expression_config();
+        assert_eq!(vec!["api", "cache"], evaluate(&config, "web - data"));
+        // Evaluated left to right, always-include entries are added at the end.
+        assert_eq!(vec!["api", "cache", "db"], evaluate(&config, "web - data + data - caching"));
+    }
+
+    #[test]
+    fn test_scenario_expression_errors() {
+        let templates = test_templates();
+        let config = expression_config();
+
+        for invalid in ["", "+ web", "web -", "web + - data"] {
+            assert!(
+                matches!(
+                    parse_scenario_expression(&[invalid]),
+                    Err(Error::InvalidScenarioExpression { .. })
+                ),
+                "{}",
+                invalid
+            );
+        }
+
+        let terms = parse_scenario_expression(&["web", "-", "nope"]).unwrap();
+        assert_eq!("web-nope", describe_scenario_expression(&terms));
+        assert!(matches!(
+            config.build_service_list_from_expression(&terms, &templates),
+            Err(Error::UnknownScenario { .. })
+        ));
+    }
+
+    #[test]
+    fn test_unused_templates() {
+        let templates = test_templates();
+        let config = test_config();
+        let resolved = config.build_service_list(&["app"], &templates).unwrap();
+        assert!(templates.unused_templates(&resolved).is_empty());
+
+        let filtered = config
+            .filter_service_list(resolved, &["cache"], false, &templates)
+            .unwrap();
+        assert_eq!(vec!["api", "db"], templates.unused_templates(&filtered));
+    }
+
+    #[test]
+    fn test_exclude_services() {
+        let templates = test_templates();
+        let config = test_config();
+        let resolved = config.build_service_list(&["app"], &templates).unwrap();
+
+        let (remaining, dependents) = config.exclude_services(resolved.clone(), &["cache"]).unwrap();
+        assert_eq!(vec!["api", "db"], names(&remaining.iter().collect::<Vec<_>>()));
+        assert!(dependents.is_empty());
+        assert_eq!(vec!["db"], remaining[0].get_dependencies());
+
+        let (remaining, dependents) = config.exclude_services(resolved.clone(), &["db"]).unwrap();
+        assert_eq!(vec!["api", "cache"], names(&remaining.iter().collect::<Vec<_>>()));
+        assert_eq!(vec![("api".to_string(), "db".to_string())], dependents);
+        assert!(remaining[0].fragment().depends_on.is_none());
+
+        assert!(matches!(
+            config.exclude_services(resolved, &["nope"]),
+            Err(Error::NotFound { .. })
+        ));
+
+        // The map form of depends_on keeps its remaining entries and their conditions.
+        let worker = service(
+            "worker",
+            "image: example/worker:1.0\ndepends_on:\n  db:\n    condition: service_healthy\n  cache:\n    condition: service_started",
+        );
+
+        let db = templates.get_service_fragment("db").unwrap();
+        let (remaining, _) = config.exclude_services(vec![&worker, db], &["db"]).unwrap();
+        assert_eq!(vec!["cache"], remaining[0].get_dependencies());
+        assert!(matches!(remaining[0].fragment().depends_on, Some(DependsOn::Map(_))));
+    }
+
+    #[test]
+    fn test_filter_services_include_depends() {
+        let templates = test_templates();
+        let config = test_config();
+        let resolved = config.build_service_list(&["app"], &templates).unwrap();
+
+        let filtered = config
+            .filter_service_list(resolved, &["api"], true, &templates)
+            .unwrap();
+        assert_eq!(vec!["api", "db"], names(&filtered));
+    }
+
+    #[test]
+    fn test_always_include() {
+        let templates = test_templates();
+        let t = r#"
+template-folder-path: .
+always-include:
+  - api
+scenarios:
+  app:
+    - cache
+"#;
+        let config: HandelConfig = serde_yaml::from_str(t).unwrap();
+
+        let resolved = config.build_service_list(&["app"], &templates).unwrap();
+        assert_eq!(vec!["api", "cache", "db"], names(&resolved));
+    }
+
+    #[test]
+    fn test_always_include_unknown() {
+        let templates = test_templates();
+        let t = r#"
+template-folder-path: .
+always-include:
+  - nope
+scenarios:
+  app:
+    - cache
+"#;
+        let config: HandelConfig = serde_yaml::from_str(t).unwrap();
+
+        assert!(matches!(
+            config.build_service_list(&["app"], &templates),
+            Err(Error::AlwaysInclude { .. })
+        ));
+    }
+
+    #[test]
+    fn test_inline_pins() {
+        let templates = test_templates();
+        let config: HandelConfig = serde_yaml::from_str(
+            "template-folder-path: .\nscenarios:\n  frozen:\n    - api@1.0.400\n    - cache\n  app:\n    - api\n  nested:\n    - frozen\n",
+        )
+        .unwrap();
+
+        let frozen = config.build_service_list(&["frozen"], &templates).unwrap();
+        assert_eq!(vec!["api", "cache", "db"], names(&frozen));
+
+        let pins = config.collect_pins(&["frozen".to_string()]);
+        assert_eq!(Some(&"1.0.400".to_string()), pins.get("api"));
+        assert_eq!(1, pins.len());
+
+        assert_eq!(pins, config.collect_pins(&["nested".to_string()]));
+
+        let app = config.build_service_list(&["app"], &templates).unwrap();
+        assert_eq!(vec!["api", "db"], names(&app));
+        assert!(config.collect_pins(&["app".to_string()]).is_empty());
+    }
+
+    #[test]
+    fn test_split_pin() {
+        assert_eq!(("contentrepo", Some("1.0.400")), split_pin("contentrepo@1.0.400"));
+        assert_eq!(("contentrepo", None), split_pin("contentrepo"));
+        assert_eq!(("@tier=infra", None), split_pin("@tier=infra"));
+    }
+
+    fn labelled_templates() -> ComposeServiceMap {
+        ComposeServiceMap::from_services(vec![
+            service("api", "image: example/api:1.0\ndepends_on:\n  - db\nx-handel:\n  tier: app"),
+            service("db", "image: mysql:5.7"),
+            service("kafka", "image: kafka:2.8\nx-handel:\n  tier: infra"),
+            service("redis", "image: redis:6\nx-handel:\n  tier: infra\n  team: core"),
+        ])
+    }
+
+    #[test]
+    fn test_label_selection() {
+        let templates = labelled_templates();
+        let config: HandelConfig = serde_yaml::from_str(
+            "template-folder-path: .\nscenarios:\n  infra:\n    - '@tier=infra'\n  app:\n    - '@tier=app'\n",
+        )
+        .unwrap();
+
+        let infra = config.build_service_list(&["infra"], &templates).unwrap();
+        assert_eq!(vec!["kafka", "redis"], names(&infra));
+
+        let app = config.build_service_list(&["app"], &templates).unwrap();
+        assert_eq!(vec!["api", "db"], names(&app));
+    }
+
+    #[test]
+    fn test_label_selection_errors() {
+        let templates = labelled_templates();
+        let config: HandelConfig = serde_yaml::from_str(
+            "template-folder-path: .\nscenarios:\n  none:\n    - '@tier=edge'\n  bad:\n    - '@tier'\n",
+        )
+        .unwrap();
+
+        let err = config.build_service_list(&["none"], &templates).unwrap_err();
+        assert!(err.to_string().contains("No template has the label"));
+
+        let err = config.build_service_list(&["bad"], &templates).unwrap_err();
+        assert!(err.to_string().contains("@key=value"));
+    }
+
+    #[test]
+    fn test_service_file() {
+        let templates = test_templates();
+        let config = test_config();
+        let names_list = parse_service_file("# generated\napi\n\n  cache  \n");
+
+        let svcs = config
+            .build_service_list_from_names(&names_list, &templates)
+            .unwrap();
+
+        assert_eq!(vec!["api", "cache", "db"], names(&svcs));
+    }
+
+    #[test]
+    fn test_service_file_unknown() {
+        let templates = test_templates();
+        let config = test_config();
+        let names_list = parse_service_file("api\nnope\nmissing\n");
+
+        match config.build_service_list_from_names(&names_list, &templates) {
+            Err(Error::UnknownServices { names }) => assert_eq!("nope, missing", names),
+            other => panic!("Expected UnknownServices, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_filter_services_unknown() {
+        let templates = test_templates();
+        let config = test_config();
+        let resolved = config.build_service_list(&["app"], &templates).unwrap();
+
+        assert!(config
+            .filter_service_list(resolved, &["nope"], false, &templates)
+            .is_err());
+    }
+
     #[test]
     fn test_config_port_range_ok() {
         let t = r#"
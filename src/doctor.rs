@@ -0,0 +1,363 @@
+use std::path::Path;
+
+use crate::config::HandelConfig;
+use crate::hooks;
+use crate::images::ImageSourceKind;
+
+/// Collects the outcome of each prerequisite checked by `handel doctor`.
+#[derive(Debug, Default)]
+struct DoctorReport {
+    failures: usize,
+    warnings: usize,
+    /// The outcome, e.g. OK, of each check in the order they were made.
+    outcomes: Vec<(&'static str, String)>,
+}
+
+impl DoctorReport {
+    fn ok(&mut self, check: &str, detail: &str) {
+        self.outcomes.push(("OK", check.to_string()));
+        println!("OK\t{}\t{}", check, detail);
+    }
+
+    fn warn(&mut self, check: &str, detail: &str, hint: &str) {
+        self.warnings += 1;
+        self.outcomes.push(("WARN", check.to_string()));
+        println!(
+            "{}\t{}\t{}\n\t\t{}",
+            crate::colors::warning("WARN"),
+            check,
+            detail,
+            hint
+        );
+    }
+
+    fn fail(&mut self, check: &str, detail: &str, hint: &str) {
+        self.failures += 1;
+        self.outcomes.push(("FAIL", check.to_string()));
+        println!(
+            "{}\t{}\t{}\n\t\t{}",
+            crate::colors::conflict("FAIL"),
+            check,
+            detail,
+            hint
+        );
+    }
+
+    fn finish(self) -> bool {
+        println!(
+            "\n{} failure(s), {} warning(s)",
+            self.failures, self.warnings
+        );
+
+        self.failures == 0
+    }
+}
+
+/// Runs `<program> --version`, returning the first line of its output if it succeeded.
+async fn program_version(program: &str) -> Option<String> {
+    let spec = vec![program.to_string(), "--version".to_string()];
+
+    match hooks::run_captured(&spec).await {
+        Ok(Some(output)) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .next()
+            .map(|l| l.trim().to_string()),
+        _ => None,
+    }
+}
+
+fn aws_credentials_available() -> bool {
+    let from_env = ["AWS_ACCESS_KEY_ID", "AWS_PROFILE", "AWS_WEB_IDENTITY_TOKEN_FILE"]
+        .iter()
+        .any(|v| std::env::var(v).is_ok());
+
+    let from_file = home::home_dir()
+        .map(|h| h.join(".aws/credentials").is_file() || h.join(".aws/config").is_file())
+        .unwrap_or(false);
+
+    from_env || from_file
+}
+
+fn dir_writable(dir: &Path) -> bool {
+    tempfile::NamedTempFile::new_in(dir).is_ok()
+}
+
+/// Checks that the compose file can be written.  A missing output directory is created by the
+/// run, so it is the nearest directory which exists that must be writable.
+fn check_output(report: &mut DoctorReport, output: &Path) {
+    let dir = match output.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+
+    let existing = dir
+        .ancestors()
+        .map(|d| match d.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => d,
+        })
+        .find(|d| d.exists())
+        .unwrap_or(dir);
+
+    match existing.is_dir() && dir_writable(existing) {
+        true => report.ok("output", &format!("{} is writable", dir.display())),
+        false => report.fail(
+            "output",
+            &format!("{} is not writable", dir.display()),
+            "Give an --output path in a directory where the compose file can be written.",
+        ),
+    }
+}
+
+fn check_reference_file(report: &mut DoctorReport, path: &Path) {
+    match path.is_file() {
+        true => report.ok("reference", &path.display().to_string()),
+        false => report.warn(
+            "reference",
+            &format!("the reference file {} doesn't exist", path.display()),
+            "Check the reference url, versions will not be copied.",
+        ),
+    }
+}
+
+async fn check_reference_url(report: &mut DoctorReport, reference: &crate::reference::Reference, url: &str) {
+    let headers = match reference.headers() {
+        Ok(h) => h,
+        Err(e) => {
+            report.fail(
+                "reference headers",
+                &e.to_string(),
+                "Set the environment variables used by the reference headers, e.g. via --env-file.",
+            );
+            Vec::new()
+        }
+    };
+
+    let client = match reference.client_builder().build() {
+        Ok(c) => c,
+        Err(e) => {
+            report.fail("reference", &e.to_string(), "Check the timeouts configured for the reference.");
+            return;
+        }
+    };
+
+    let response = headers
+        .iter()
+        .fold(client.head(url), |request, (name, value)| {
+            request.header(name, value)
+        })
+        .send()
+        .await;
+
+    match response {
+        Ok(r) if r.status().is_success() => report.ok("reference", &format!("{} ({})", url, r.status())),
+        Ok(r) => report.warn(
+            "reference",
+            &format!("{} responded with {}", url, r.status()),
+            "Check the reference url and any credentials in its headers, versions will not be copied.",
+        ),
+        Err(e) => report.warn(
+            "reference",
+            &format!("{} is unreachable: {}", url, e),
+            "Check network access (e.g. VPN) to the reference url, versions will not be copied.",
+        ),
+    }
+}
+
+/// Checks the tools, credentials and access which a run of handel with the given
+/// configuration, container CLI and output path relies upon.  Returns false if any hard
+/// prerequisite is missing.
+pub async fn run(
+    config: Result<HandelConfig, crate::config::Error>,
+    env: &str,
+    container_cli: Option<ImageSourceKind>,
+    output: &Path,
+) -> bool {
+    report(config, env, container_cli, output).await.finish()
+}
+
+async fn report(
+    config: Result<HandelConfig, crate::config::Error>,
+    env: &str,
+    container_cli: Option<ImageSourceKind>,
+    output: &Path,
+) -> DoctorReport {
+    let mut report = DoctorReport::default();
+
+    // The container CLI given on the command line replaces the configured image sources.
+    let tools = match (container_cli, &config) {
+        (Some(cli), _) => vec![cli],
+        (None, Ok(c)) => c.image_sources(),
+        (None, Err(_)) => vec![ImageSourceKind::Docker],
+    };
+
+    for tool in tools {
+        let program = tool.program();
+        match program_version(program).await {
+            Some(v) => report.ok(program, &v),
+            None => report.fail(
+                program,
+                &format!("{} could not be run", program),
+                &format!(
+                    "Install {} and ensure it is in the PATH and usable by this user, or choose \
+                    another with image-sources or --container-cli.",
+                    program
+                ),
+            ),
+        }
+    }
+
+    check_output(&mut report, output);
+
+    let config = match config {
+        Ok(c) => {
+            report.ok("configuration", "loaded");
+            c
+        }
+        Err(e) => {
+            report.fail(
+                "configuration",
+                &e.to_string(),
+                "Check the path given with -c (default handel.yml) and its YAML syntax.",
+            );
+            return report;
+        }
+    };
+
+    if let Some(reference) = config.get_reference() {
+        if let Some(filter) = reference.jq_filter() {
+            match crate::reference::compile_filter(filter) {
+                Ok(_) => report.ok("jq filter", "compiles"),
+                Err(e) => report.fail(
+                    "jq filter",
+                    &e.to_string(),
+                    "Check the jq-filter of the reference, it is run by handel's embedded jq engine.",
+                ),
+            }
+        }
+
+        let target = reference
+            .url_for(env)
+            .and_then(|url| Ok((reference.file_for(env)?, url)));
+
+        match target {
+            Ok((Some(path), _)) => check_reference_file(&mut report, &path),
+            Ok((None, url)) => check_reference_url(&mut report, reference, &url).await,
+            Err(e) => report.fail(
+                "reference url",
+                &e.to_string(),
+                "Set the environment variables used by the reference url, e.g. via --env-file.",
+            ),
+        }
+    }
+
+    let uses_s3 = config
+        .volumes()
+        .iter()
+        .flatten()
+        .any(|v| v.source.starts_with("s3://"));
+
+    if uses_s3 {
+        match aws_credentials_available() {
+            true => report.ok("aws credentials", "found"),
+            false => report.warn(
+                "aws credentials",
+                "no AWS credentials were found, but volumes are sourced from S3",
+                "Configure the AWS CLI (aws configure / aws sso login) or set AWS_PROFILE.",
+            ),
+        }
+    }
+
+    match (crate::oci::OciReference::parse(config.template_dir()), std::fs::read_dir(config.template_dir())) {
+        (Ok(Some(r)), _) => report.ok(
+            "template dir",
+            &format!(
+                "{} within image {}, copied out with {} when run",
+                r.path,
+                r.image,
+                crate::oci::template_cli(&config, container_cli).program()
+            ),
+        ),
+        (Err(e), _) => report.fail(
+            "template dir",
+            &e.to_string(),
+            "Give the image and the path within it, e.g. oci://example/templates:1.0#/templates.",
+        ),
+        (Ok(None), Ok(_)) => report.ok("template dir", config.template_dir()),
+        (Ok(None), Err(e)) => report.fail(
+            "template dir",
+            &format!("{} is not readable: {}", config.template_dir(), e),
+            "Check template-folder-path in the configuration and the directory permissions.",
+        ),
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_error() -> Result<HandelConfig, crate::config::Error> {
+        Err(crate::config::Error::NotFound {
+            input: "handel.yml".to_string(),
+        })
+    }
+
+    fn checks(report: &DoctorReport) -> Vec<&str> {
+        report.outcomes.iter().map(|(_, c)| c.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_doctor_checks_container_cli() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("docker-compose.yml");
+
+        let report = report(config_error(), "test", Some(ImageSourceKind::Podman), &output).await;
+
+        assert_eq!(vec!["podman", "output", "configuration"], checks(&report));
+        assert!(report.outcomes.contains(&("FAIL", "configuration".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_doctor_checks_output_dir() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // A missing output directory is created by the run, so its parent must be writable.
+        let nested = dir.path().join("nested/dir/docker-compose.yml");
+        let nested_report = report(config_error(), "test", None, &nested).await;
+        assert!(nested_report.outcomes.contains(&("OK", "output".to_string())));
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let file_report = report(config_error(), "test", None, &file.join("docker-compose.yml")).await;
+        assert!(file_report.outcomes.contains(&("FAIL", "output".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_doctor_warns_on_reference_status() {
+        use std::io::{Read, Write};
+
+        // Answers each request with the next of the given statuses.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/versions.json", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for status in ["404 Not Found", "200 OK"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let _ = stream.read(&mut [0; 1024]);
+                let _ = write!(stream, "HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+            }
+        });
+
+        let reference: crate::reference::Reference =
+            serde_yaml::from_str(&format!("url: {}\nread-timeout: 5s", url)).unwrap();
+
+        let mut missing = DoctorReport::default();
+        check_reference_url(&mut missing, &reference, &url).await;
+        assert_eq!(vec![("WARN", "reference".to_string())], missing.outcomes);
+
+        let mut found = DoctorReport::default();
+        check_reference_url(&mut found, &reference, &url).await;
+        assert_eq!(vec![("OK", "reference".to_string())], found.outcomes);
+    }
+}
@@ -1,10 +1,14 @@
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Datelike, Duration, Utc};
 use log::*;
 use regex::Regex;
 use serde::Deserialize;
 use tokio::process::Command;
 
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::future::Future;
+use std::io::{BufRead, BufReader, Read};
+use std::pin::Pin;
 
 use std::str::FromStr;
 
@@ -15,8 +19,12 @@ pub enum Error {
     #[snafu(display(r#"Unable spawn docker command.\n{}"#, source))]
     SpawnDockerCommand { source: std::io::Error },
 
-    #[snafu(display(r#"Unable to read docker output.\n{}"#, source))]
-    ReadChildOutput { source: std::io::Error },
+    #[snafu(display(r#"The {} images command failed ({}).\n{}"#, program, status, stderr))]
+    ImagesCommandFailed {
+        program: String,
+        status: String,
+        stderr: String,
+    },
 
     #[snafu(display(r#"Unable to parse docker output.\n{}"#, source))]
     ParseChildOutput { source: std::string::FromUtf8Error },
@@ -24,6 +32,15 @@ pub enum Error {
     #[snafu(display(r#"Unable to read docker output.\n{}"#, source))]
     ReadChildLine { source: std::io::Error },
 
+    #[snafu(display(r#"Image labels must have the form key=value: {}"#, input))]
+    InvalidImageLabel { input: String },
+
+    #[snafu(display(r#"Unable to read the images file: {}\n{}"#, file, source))]
+    ReadImagesFile {
+        file: String,
+        source: std::io::Error,
+    },
+
     #[snafu(display(r#"Unable to terminate child process.\n{}"#, source))]
     WaitChild { source: std::io::Error },
 
@@ -57,7 +74,111 @@ pub struct LocalContainerImage {
     id: String,
     repository: String,
     tag: String,
+    /// The repository digest, listed as `<none>` for images which were built locally rather
+    /// than pulled or pushed.
+    #[serde(default)]
+    digest: Option<String>,
     size: String,
+    /// Only present when captured by a tool which reports image labels, as `key=value,...`.
+    #[serde(default)]
+    labels: Option<String>,
+    /// When the image was last tagged locally, e.g. pulled, if it has been inspected.
+    #[serde(skip)]
+    pulled_at: Option<DateTime<Utc>>,
+}
+
+impl LocalContainerImage {
+    /// The time compared against `--since`, falling back to the creation time when the pull
+    /// time isn't known.
+    fn basis_time(&self, basis: SinceBasis) -> DateTime<Utc> {
+        match basis {
+            SinceBasis::Created => self.created_at,
+            SinceBasis::Pulled => self.pulled_at.unwrap_or(self.created_at),
+        }
+    }
+}
+
+/// Which time of a local image is compared against `--since`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SinceBasis {
+    /// When the image was built, as listed by `docker images`.
+    #[default]
+    Created,
+    /// When the image was last pulled or tagged locally, which requires an inspect.
+    Pulled,
+}
+
+/// A `key=value` image label which local images must carry to be considered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageLabel {
+    key: String,
+    value: String,
+}
+
+impl ImageLabel {
+    pub fn parse(label: &str) -> Result<ImageLabel> {
+        match label.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok(ImageLabel {
+                key: key.to_string(),
+                value: value.to_string(),
+            }),
+            _ => Err(Error::InvalidImageLabel {
+                input: label.to_string(),
+            }),
+        }
+    }
+
+    /// Whether the given `key=value,...` list of labels includes this one.
+    fn matches(&self, labels: &str) -> bool {
+        labels
+            .split(',')
+            .filter_map(|l| l.split_once('='))
+            .any(|(k, v)| k.trim() == self.key && v.trim() == self.value)
+    }
+}
+
+/// A pattern in docker's reference filter syntax, e.g. `example/*` or `example/*:1.*`, which
+/// local images must match to be considered.  `*` and `?` don't match a `/`, and a pattern
+/// without a tag matches any tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageRepoFilter {
+    pattern: String,
+}
+
+impl ImageRepoFilter {
+    pub fn new(pattern: &str) -> ImageRepoFilter {
+        ImageRepoFilter {
+            pattern: pattern.to_string(),
+        }
+    }
+
+    /// The literal start of the pattern, which any matching line must contain.
+    fn literal_prefix(&self) -> &str {
+        match self.pattern.find(['*', '?']) {
+            Some(i) => &self.pattern[..i],
+            None => &self.pattern,
+        }
+    }
+
+    fn matches(&self, repository: &str, tag: &str) -> bool {
+        let pattern = self.pattern.as_bytes();
+        glob_match(pattern, format!("{}:{}", repository, tag).as_bytes())
+            || glob_match(pattern, repository.as_bytes())
+    }
+}
+
+/// Matches `*` and `?` wildcards in the manner of go's `path.Match`, neither matching a `/`.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], text)
+                || (text.first().is_some_and(|&c| c != b'/') && glob_match(pattern, &text[1..]))
+        }
+        (Some(b'?'), Some(&c)) if c != b'/' => glob_match(&pattern[1..], &text[1..]),
+        (Some(p), Some(c)) if p == c => glob_match(&pattern[1..], &text[1..]),
+        _ => false,
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -80,6 +201,12 @@ impl ContainerImage {
         }
     }
 
+    /// A local image of the given service read from a line of `docker images` JSON output.
+    #[cfg(test)]
+    pub fn from_json(name: &str, line: &str) -> ContainerImage {
+        ContainerImage::new(name, serde_json::from_str(line).unwrap())
+    }
+
     pub fn version(self: &ContainerImage) -> String {
         self.container.tag.to_string()
     }
@@ -91,80 +218,58 @@ impl ContainerImage {
     pub fn repository(self: &ContainerImage) -> String {
         self.container.repository.clone()
     }
+
+    pub fn created_at(self: &ContainerImage) -> DateTime<Utc> {
+        self.container.created_at
+    }
+
+    /// The digest of the image, e.g. `sha256:...`, when it has one.
+    pub fn digest(self: &ContainerImage) -> Option<String> {
+        self.container
+            .digest
+            .clone()
+            .filter(|d| crate::templates::is_digest(d))
+    }
 }
 
 impl ContainerImages {
-    pub async fn find(since: &str) -> Result<Vec<ContainerImage>> {
-        let since = parse_since_string(since)?;
-
-        trace!("{} - got since duration: {:?}", module_path!(), &since);
-
-        let container_age_limit = Utc::now()
-            .checked_sub_signed(since)
-            .expect("Internal error: unable to calculate minimum datetime from given since string");
-
-        trace!("{:?}", &container_age_limit);
-
-        let output = Command::new("docker")
-            .arg("images")
-            .arg("--format")
-            .arg("{{json .}}")
-            .output()
-            .await
-            .context(ReadChildOutput)?;
-
-        let mut image_map: HashMap<String, ContainerImage> = HashMap::new();
-
-        trace!("{} - got since duration: {:?}", module_path!(), &since);
-
-        String::from_utf8(output.stdout)
-            .context(ParseChildOutput)?
-            .lines()
-            .filter_map(|line| {
-                serde_json::from_str::<LocalContainerImage>(line).ok()
-            })
-            .filter(|lc| {
-                debug!("id: {} tag: {} size: {}", &lc.id, &lc.tag, &lc.size);
-                !matches!(lc.tag.as_str(), "TRUNK")
-            })
-            .filter_map(|lc| match "<none>".eq(&lc.repository) {
-                true => None,
-                false => Some(lc),
-            })
-            .take_while(|lc| {
-                trace!(
-                    "{} - parsed container from docker command output {:?}",
-                    module_path!(),
-                    &lc
-                );
+    /// Finds recently built local images, either by listing those of the given sources or, when
+    /// given, by reading the `{{json .}}` lines previously captured from `docker images` in a file.
+    /// If a label or repository filter is given only the images carrying or matching it are
+    /// considered.
+    pub async fn find(
+        since: &str,
+        basis: SinceBasis,
+        images_from: Option<&str>,
+        sources: &[ImageSourceKind],
+        label: Option<&ImageLabel>,
+        repo: Option<&ImageRepoFilter>,
+    ) -> Result<Vec<ContainerImage>> {
+        let container_age_limit = parse_since_cutoff(since, Utc::now())?;
 
-                if lc.created_at.le(&container_age_limit) {
+        trace!("{} - got since cutoff: {:?}", module_path!(), &container_age_limit);
+
+        let images = match images_from {
+            Some(file) => {
+                let input = std::fs::File::open(file).context(ReadImagesFile {
+                    file: file.to_string(),
+                })?;
+                if basis == SinceBasis::Pulled {
                     info!(
-                        "{} - ignoring container {} which is too old: {:?}",
-                        module_path!(),
-                        &lc.repository,
-                        &lc.created_at.to_rfc2822()
+                        "{} - images read from a file can't be inspected, using their created time",
+                        module_path!()
                     );
-                    return false;
-                }
-
-                true
-            })
-            .for_each(|lc| {
-                let service_name = get_service_name_from_repository(&lc.repository);
-
-                if let Some(sn) = service_name {
-                    if !image_map.contains_key(&sn) ||
-                        // Eurgh - https://github.com/rust-lang/rust/issues/53667
-                        image_map.get(&sn).unwrap().version().ends_with("-SNAPSHOT")
-                    {
-                        let c = ContainerImage::new(&sn, lc);
-                        image_map.insert(sn, c);
-                    }
                 }
-            });
-
-        let images = image_map.values().cloned().collect::<Vec<_>>();
+                let mut image_map = HashMap::new();
+                let images = read_images(input, label, repo)?;
+                select_recent(images, container_age_limit, basis, &mut image_map);
+                image_map.into_values().collect()
+            }
+            None => {
+                let sources = sources.iter().map(|s| s.source(label, repo)).collect::<Vec<_>>();
+                find_in_sources(&sources, container_age_limit, basis).await?
+            }
+        };
 
         if !images.is_empty() {
             let names = images
@@ -181,28 +286,457 @@ impl ContainerImages {
     }
 }
 
-fn parse_since_string(since: &str) -> Result<Duration> {
-    let captures = Regex::new(r"(?P<value>\d{0,10}(?:\.\d{0,5})?)(?P<units>s|m|h|d|w)?")
-        .map(|r| r.captures(since))
+/// The tools which can be asked for the locally available images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageSourceKind {
+    Docker,
+    Nerdctl,
+    Podman,
+}
+
+impl ImageSourceKind {
+    fn source(self, label: Option<&ImageLabel>, repo: Option<&ImageRepoFilter>) -> Box<dyn ImageSource> {
+        let format = match self {
+            ImageSourceKind::Podman => PODMAN_IMAGES_FORMAT,
+            _ => DOCKER_IMAGES_FORMAT,
+        };
+
+        Box::new(CliImageSource {
+            kind: self,
+            format,
+            label: label.cloned(),
+            repo: repo.cloned(),
+        })
+    }
+
+    /// The program run for this kind of source, e.g. `podman`.
+    pub fn program(self) -> &'static str {
+        match self {
+            ImageSourceKind::Docker => "docker",
+            ImageSourceKind::Nerdctl => "nerdctl",
+            ImageSourceKind::Podman => "podman",
+        }
+    }
+
+    /// Parses the name of a container CLI, e.g. as given by `--container-cli`.
+    pub fn parse(name: &str) -> Option<ImageSourceKind> {
+        match name {
+            "docker" => Some(ImageSourceKind::Docker),
+            "nerdctl" => Some(ImageSourceKind::Nerdctl),
+            "podman" => Some(ImageSourceKind::Podman),
+            _ => None,
+        }
+    }
+}
+
+const DOCKER_IMAGES_FORMAT: &str = "{{json .}}";
+
+/// Podman's `{{json .}}` is its own image summary (e.g. an `Id` and a numeric `Created`), so the
+/// fields docker lists are asked for explicitly.
+const PODMAN_IMAGES_FORMAT: &str = r#"{"CreatedAt":{{json .CreatedAt}},"Digest":{{json .Digest}},"ID":{{json .ID}},"Repository":{{json .Repository}},"Size":{{json .Size}},"Tag":{{json .Tag}}}"#;
+
+/// Podman names locally built images `localhost/<repository>`, which is removed so they match
+/// the repositories of the templates as docker's do.
+fn strip_podman_localhost(output: String) -> String {
+    output.replace(r#""Repository":"localhost/"#, r#""Repository":""#)
+}
+
+type ListFuture<'a> = Pin<Box<dyn Future<Output = Result<String>> + Send + 'a>>;
+type PulledFuture<'a> = Pin<Box<dyn Future<Output = Result<HashMap<String, DateTime<Utc>>>> + Send + 'a>>;
+
+/// A source of locally available images, listed as lines in the `docker images --format
+/// '{{json .}}'` form.
+pub(crate) trait ImageSource: Send + Sync {
+    fn name(&self) -> &str;
+    fn list(&self) -> ListFuture<'_>;
+
+    /// Maps the full id of each of the given images to when it was last pulled or tagged
+    /// locally.  Sources which can't tell report nothing, so the created time is used.
+    fn pulled_times<'a>(&'a self, _ids: &'a [String]) -> PulledFuture<'a> {
+        Box::pin(async { Ok(HashMap::new()) })
+    }
+}
+
+/// An image source backed by a docker compatible CLI, e.g. docker itself or nerdctl for
+/// images held by containerd.  A label and repository filter are applied by the CLI itself.
+struct CliImageSource {
+    kind: ImageSourceKind,
+    format: &'static str,
+    label: Option<ImageLabel>,
+    repo: Option<ImageRepoFilter>,
+}
+
+impl ImageSource for CliImageSource {
+    fn name(&self) -> &str {
+        self.kind.program()
+    }
+
+    fn list(&self) -> ListFuture<'_> {
+        Box::pin(async move {
+            let output = images_output(self.kind.program(), self.format, self.label.as_ref(), self.repo.as_ref()).await?;
+            match self.kind {
+                ImageSourceKind::Podman => Ok(strip_podman_localhost(output)),
+                _ => Ok(output),
+            }
+        })
+    }
+
+    fn pulled_times<'a>(&'a self, ids: &'a [String]) -> PulledFuture<'a> {
+        // Podman has no last tag time, so its images fall back to their creation time.
+        match self.kind {
+            ImageSourceKind::Podman => Box::pin(async { Ok(HashMap::new()) }),
+            _ => Box::pin(last_tag_times(self.kind.program(), ids)),
+        }
+    }
+}
+
+/// Lists the images of each source, merging them into a single candidate per service.  A
+/// source which fails is skipped with a warning, unless every source fails.
+async fn find_in_sources(
+    sources: &[Box<dyn ImageSource>],
+    container_age_limit: DateTime<Utc>,
+    basis: SinceBasis,
+) -> Result<Vec<ContainerImage>> {
+    let mut image_map = HashMap::new();
+    let mut last_error = None;
+    let mut listed = 0;
+
+    for source in sources {
+        match source.list().await {
+            Ok(output) => {
+                listed += 1;
+                let mut images = read_images(output.as_bytes(), None, None)?;
+                if basis == SinceBasis::Pulled {
+                    inspect_pulled(source.as_ref(), &mut images, container_age_limit).await;
+                }
+                select_recent(images, container_age_limit, basis, &mut image_map);
+            }
+            Err(e) => {
+                warn!(
+                    "{} - Unable to list images from {}: {}",
+                    module_path!(),
+                    source.name(),
+                    e
+                );
+                last_error = Some(e);
+            }
+        }
+    }
+
+    match last_error {
+        Some(e) if listed == 0 => Err(e),
+        _ => Ok(image_map.into_values().collect()),
+    }
+}
+
+/// Records when each image created before the limit was pulled, as only those could be
+/// included by using the pull time.  Should the inspect fail, the created time is used.
+async fn inspect_pulled(
+    source: &dyn ImageSource,
+    images: &mut [LocalContainerImage],
+    container_age_limit: DateTime<Utc>,
+) {
+    let ids = images
+        .iter()
+        .filter(|i| i.created_at.le(&container_age_limit))
+        .map(|i| i.id.clone())
+        .collect::<Vec<_>>();
+
+    if ids.is_empty() {
+        return;
+    }
+
+    match source.pulled_times(&ids).await {
+        Ok(times) => {
+            for image in images.iter_mut() {
+                image.pulled_at = times
+                    .iter()
+                    .find(|(id, _)| id.starts_with(&image.id))
+                    .map(|(_, t)| *t);
+            }
+        }
+        Err(e) => warn!(
+            "{} - Unable to inspect images from {}, using their created time: {}",
+            module_path!(),
+            source.name(),
+            e
+        ),
+    }
+}
+
+/// Runs `<program> image inspect` for the last tag time of the given images.
+async fn last_tag_times(program: &str, ids: &[String]) -> Result<HashMap<String, DateTime<Utc>>> {
+    let mut command = Command::new(program);
+    command
+        .arg("image")
+        .arg("inspect")
+        .arg("--format")
+        .arg("{{.Id}} {{.Metadata.LastTagTime}}")
+        .args(ids);
+
+    crate::hooks::log_command(module_path!(), &command);
+
+    let output = command.output().await.context(SpawnDockerCommand)?;
+
+    if !output.status.success() {
+        return Err(Error::ImagesCommandFailed {
+            program: program.to_string(),
+            status: output.status.to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+
+    let stdout = String::from_utf8(output.stdout).context(ParseChildOutput)?;
+
+    Ok(parse_last_tag_times(&stdout))
+}
+
+/// Parses `<id> <time>` lines, the time in Go's default format, e.g. `2024-03-02
+/// 10:15:00.123456789 +0000 UTC`.  Images which have never been tagged have the zero time.
+fn parse_last_tag_times(output: &str) -> HashMap<String, DateTime<Utc>> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (id, time) = line.trim().split_once(' ')?;
+            let id = id.strip_prefix("sha256:").unwrap_or(id);
+            let time = time.rsplit_once(' ').map_or(time, |(t, _)| t);
+            let time = DateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S%.f %z")
+                .ok()?
+                .with_timezone(&Utc);
+
+            (time.year() > 1).then(|| (id.to_string(), time))
+        })
+        .collect()
+}
+
+const IMAGES_ATTEMPTS: u32 = 3;
+const IMAGES_BACKOFF: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Whether a failed `docker images` (or `nerdctl images`) invocation is worth retrying, i.e. the
+/// tool is installed but its daemon isn't (yet) accepting connections, e.g. as it has just been restarted.
+fn is_transient_failure(spawn_error: Option<std::io::ErrorKind>, stderr: &str) -> bool {
+    if spawn_error.is_some() {
+        // The binary is missing or can't be executed, retrying won't help.
+        return false;
+    }
+
+    let stderr = stderr.to_lowercase();
+
+    [
+        "cannot connect to the docker daemon",
+        "is the docker daemon running",
+        "error during connect",
+        "connection refused",
+        "cannot access containerd socket",
+    ]
+    .iter()
+    .any(|m| stderr.contains(m))
+}
+
+/// Runs `<program> images`, retrying with a doubling backoff while the daemon isn't ready.
+async fn images_output(
+    program: &str,
+    format: &str,
+    label: Option<&ImageLabel>,
+    repo: Option<&ImageRepoFilter>,
+) -> Result<String> {
+    let mut delay = IMAGES_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        let mut command = Command::new(program);
+        command.arg("images").arg("--digests").arg("--format").arg(format);
+
+        if let Some(l) = label {
+            command.arg("--filter").arg(format!("label={}={}", l.key, l.value));
+        }
+
+        if let Some(r) = repo {
+            command.arg(&r.pattern);
+        }
+
+        crate::hooks::log_command(module_path!(), &command);
+
+        let result = command.output().await;
+
+        let (spawn_error, stderr, error) = match result {
+            Ok(output) if output.status.success() => {
+                return String::from_utf8(output.stdout).context(ParseChildOutput);
+            }
+            Ok(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+                let error = Error::ImagesCommandFailed {
+                    program: program.to_string(),
+                    status: output.status.to_string(),
+                    stderr: stderr.clone(),
+                };
+                (None, stderr, error)
+            }
+            Err(e) => (Some(e.kind()), e.to_string(), Error::SpawnDockerCommand { source: e }),
+        };
+
+        if attempt >= IMAGES_ATTEMPTS || !is_transient_failure(spawn_error, &stderr) {
+            return Err(error);
+        }
+
+        warn!(
+            "{} - {} images failed (attempt {} of {}), retrying in {:?}: {}",
+            module_path!(),
+            program,
+            attempt,
+            IMAGES_ATTEMPTS,
+            delay,
+            &stderr
+        );
+
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+/// Parses the `{{json .}}` lines output by `docker images`, keeping the tagged images which
+/// carry the label and match the repository filter if given.  Lines which can't match the
+/// filter aren't parsed at all.
+fn read_images<R: Read>(
+    input: R,
+    label: Option<&ImageLabel>,
+    repo: Option<&ImageRepoFilter>,
+) -> Result<Vec<LocalContainerImage>> {
+    let prefix = repo.map(|r| format!(r#""Repository":"{}"#, r.literal_prefix()));
+
+    let images = BufReader::new(input)
+        .lines()
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .context(ReadChildLine)?
+        .iter()
+        .filter(|line| prefix.as_ref().is_none_or(|p| line.contains(p.as_str())))
+        .filter_map(|line| {
+            serde_json::from_str::<LocalContainerImage>(line).ok()
+        })
+        .filter(|lc| {
+            debug!("id: {} tag: {} size: {}", &lc.id, &lc.tag, &lc.size);
+            !matches!(lc.tag.as_str(), "TRUNK")
+        })
+        .filter(|lc| match label {
+            Some(l) => lc.labels.as_deref().is_some_and(|labels| l.matches(labels)),
+            None => true,
+        })
+        .filter(|lc| repo.is_none_or(|r| r.matches(&lc.repository, &lc.tag)))
+        .filter_map(|lc| match "<none>".eq(&lc.repository) {
+            true => None,
+            false => Some(lc),
+        })
+        .collect();
+
+    Ok(images)
+}
+
+/// Adds the most recent image of each service which is newer than the given limit to the map.
+fn select_recent(
+    images: Vec<LocalContainerImage>,
+    container_age_limit: DateTime<Utc>,
+    basis: SinceBasis,
+    image_map: &mut HashMap<String, ContainerImage>,
+) {
+    images
+        .into_iter()
+        .filter(|lc| {
+            trace!(
+                "{} - parsed container from docker command output {:?}",
+                module_path!(),
+                &lc
+            );
+
+            let time = lc.basis_time(basis);
+
+            if time.le(&container_age_limit) {
+                info!(
+                    "{} - ignoring container {} which is too old: {:?}",
+                    module_path!(),
+                    &lc.repository,
+                    &time.to_rfc2822()
+                );
+                return false;
+            }
+
+            true
+        })
+        .for_each(|lc| {
+            let service_name = get_service_name_from_repository(&lc.repository);
+
+            if let Some(sn) = service_name {
+                // The newest image of a service wins, a release being preferred over a snapshot
+                // created at the same time.
+                let replace = match image_map.get(&sn) {
+                    None => true,
+                    Some(existing) => match lc.created_at.cmp(&existing.container.created_at) {
+                        Ordering::Greater => true,
+                        Ordering::Equal => {
+                            existing.version().ends_with("-SNAPSHOT") && !lc.tag.ends_with("-SNAPSHOT")
+                        }
+                        Ordering::Less => false,
+                    },
+                };
+
+                if replace {
+                    let c = ContainerImage::new(&sn, lc);
+                    image_map.insert(sn, c);
+                }
+            }
+        });
+}
+
+/// Parses an age such as `--since`, a number without a unit being in hours.
+pub fn parse_since_string(since: &str) -> Result<Duration> {
+    parse_duration(since, "h")
+}
+
+/// Parses a duration such as `30s`, `20m`, `0.5h`, `1d` or `2w`, a number without a unit being
+/// in the given default unit.  The whole of the input must match, so e.g. `30x` is an error.
+pub fn parse_duration(input: &str, default_unit: &str) -> Result<Duration> {
+    let captures = Regex::new(r"^(?P<value>\d{0,10}(?:\.\d{0,5})?)(?P<units>s|m|h|d|w)?$")
+        .map(|r| r.captures(input))
         .expect("Internal error: invalid regular expression");
 
     let captures = captures.ok_or(Error::NoValue {
-        input: since.to_string(),
+        input: input.to_string(),
     })?;
 
-    let value = captures.name("value").map_or("24", |d| d.as_str());
+    let value = captures.name("value").map_or("", |d| d.as_str());
 
     let value = f64::from_str(value).context(ParseNumeric {
         input: value.to_string(),
     })?;
 
-    Ok(match captures.name("units").map_or("h", |d| d.as_str()) {
-        "s" => Duration::seconds(value.round() as i64),
-        "m" => Duration::seconds((value * 60.0).round() as i64),
-        "d" => Duration::seconds((value * 86400.0).round() as i64),
-        "w" => Duration::seconds((value * 604800.0).round() as i64),
-        _ => Duration::minutes((value * 60.0).round() as i64),
-    })
+    let unit_seconds = match captures.name("units").map_or(default_unit, |d| d.as_str()) {
+        "s" => 1.0,
+        "m" => 60.0,
+        "d" => 86400.0,
+        "w" => 604800.0,
+        _ => 3600.0,
+    };
+
+    Ok(Duration::milliseconds((value * unit_seconds * 1000.0).round() as i64))
+}
+
+/// The time before which local images are too old, given `--since` as either an absolute date
+/// (`2024-01-15`, midnight UTC) or RFC3339 timestamp, or else as an age relative to now.
+pub fn parse_since_cutoff(since: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(since) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(since, "%Y-%m-%d") {
+        return Ok(date.and_time(chrono::NaiveTime::MIN).and_utc());
+    }
+
+    let since = parse_since_string(since)?;
+
+    Ok(now
+        .checked_sub_signed(since)
+        .expect("Internal error: unable to calculate minimum datetime from given since string"))
 }
 
 fn get_service_name_from_repository(repo: &str) -> Option<String> {
@@ -216,21 +750,40 @@ fn get_service_name_from_repository(repo: &str) -> Option<String> {
 }
 
 mod docker_image_datetime_format {
-    use chrono::{DateTime, Utc};
+    use chrono::{DateTime, NaiveDateTime, Utc};
+    use log::*;
     use serde::{self, Deserialize, Deserializer};
 
     const FORMAT: &str = "%Y-%m-%d %H:%M:%S %z %Z";
+    const OFFSET_FORMAT: &str = "%Y-%m-%d %H:%M:%S %z";
+    const NAIVE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
 
     pub fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
     where
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
+        parse(&s).map_err(serde::de::Error::custom)
+    }
+
+    /// Converts the local date timestamps to UTC.  The timezone abbreviation depends on the
+    /// locale, so should it not be understood the offset alone is used, and without an offset
+    /// the time is taken to be UTC already.
+    pub(super) fn parse(s: &str) -> Result<DateTime<Utc>, chrono::ParseError> {
+        let without_zone = s.rsplit_once(' ').map_or(s, |(time, _)| time);
+
+        if let Some(time) = [(s, FORMAT), (s, OFFSET_FORMAT), (without_zone, OFFSET_FORMAT)]
+            .iter()
+            .find_map(|(s, format)| DateTime::parse_from_str(s, format).ok())
+        {
+            return Ok(time.with_timezone(&Utc));
+        }
+
+        let time = s.get(..19).unwrap_or(s);
+        let time = NaiveDateTime::parse_from_str(time, NAIVE_FORMAT)?.and_utc();
+        warn!("Unable to read the timezone of image created at: {}, assuming UTC", s);
 
-        // Convert the local date timestamps to UTC.
-        DateTime::parse_from_str(&s, FORMAT)
-            .map_err(serde::de::Error::custom)
-            .map(|d| d.with_timezone(&Utc) )
+        Ok(time)
     }
 }
 
@@ -285,6 +838,36 @@ mod tests {
         assert_eq!(Duration::seconds(864), parse_since_string("0.01d").unwrap());
     }
 
+    #[test]
+    fn test_parse_unmatched_duration() {
+        for invalid in ["", "h", "30x", "5 m", "2024-01-01", "1h30m"] {
+            assert!(parse_since_string(invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!(Duration::seconds(30), parse_duration("30", "s").unwrap());
+    }
+
+    #[test]
+    fn test_parse_since_date() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        assert_eq!(
+            Utc.with_ymd_and_hms(2024, 1, 15, 0, 0, 0).unwrap(),
+            parse_since_cutoff("2024-01-15", now).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_since_rfc3339() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        assert_eq!(
+            Utc.with_ymd_and_hms(2024, 1, 15, 8, 30, 0).unwrap(),
+            parse_since_cutoff("2024-01-15T10:30:00+02:00", now).unwrap()
+        );
+        assert_eq!(
+            Utc.with_ymd_and_hms(2024, 3, 1, 9, 0, 0).unwrap(),
+            parse_since_cutoff("3h", now).unwrap()
+        );
+    }
+
     #[derive(Debug, Deserialize)]
     #[serde(rename_all = "PascalCase")]
     struct TestTime {
@@ -300,4 +883,277 @@ mod tests {
 
         assert_eq!(expected, de.created_at, "Times should match");
     }
+
+    #[test]
+    fn test_parse_created_at_variants() {
+        let expected = Utc.with_ymd_and_hms(2024, 6, 3, 8, 15, 0).unwrap();
+
+        for created_at in [
+            "2024-06-03 10:15:00 +0200 CEST",
+            "2024-06-03 10:15:00 +0200",
+            "2024-06-03 10:15:00 +0200 MESZ",
+            "2024-06-03 08:15:00",
+        ] {
+            assert_eq!(expected, docker_image_datetime_format::parse(created_at).unwrap(), "{}", created_at);
+        }
+
+        assert!(docker_image_datetime_format::parse("3 hours ago").is_err());
+    }
+
+    const IMAGES_FIXTURE: &str = r#"{"Containers":"N/A","CreatedAt":"2024-03-02 10:15:00 +0000 UTC","CreatedSince":"2 hours ago","Digest":"<none>","ID":"a1b2c3d4e5f6","Repository":"example/content-query","SharedSize":"N/A","Size":"412MB","Tag":"1.0.5-SNAPSHOT","UniqueSize":"N/A","VirtualSize":"412MB"}
+{"Containers":"N/A","CreatedAt":"2024-03-02 09:00:00 +0000 UTC","CreatedSince":"3 hours ago","Digest":"<none>","ID":"b2c3d4e5f6a1","Repository":"<none>","SharedSize":"N/A","Size":"98MB","Tag":"<none>","UniqueSize":"N/A","VirtualSize":"98MB"}
+{"Containers":"N/A","CreatedAt":"2024-03-01 16:40:12 +0000 UTC","CreatedSince":"20 hours ago","Digest":"<none>","ID":"c3d4e5f6a1b2","Repository":"121212121.dkr.ecr.us-east-1.amazonaws.com/content-repo","SharedSize":"N/A","Size":"380MB","Tag":"2.3.1","UniqueSize":"N/A","VirtualSize":"380MB"}
+{"Containers":"N/A","CreatedAt":"2024-01-10 08:00:00 +0000 UTC","CreatedSince":"7 weeks ago","Digest":"<none>","ID":"d4e5f6a1b2c3","Repository":"mysql","SharedSize":"N/A","Size":"450MB","Tag":"5.7","UniqueSize":"N/A","VirtualSize":"450MB"}
+"#;
+
+    #[test]
+    fn test_parse_images_fixture() {
+        let limit = Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+        let mut image_map = HashMap::new();
+        let images = read_images(IMAGES_FIXTURE.as_bytes(), None, None).unwrap();
+        select_recent(images, limit, SinceBasis::Created, &mut image_map);
+        let mut images = image_map.into_values().collect::<Vec<_>>();
+        images.sort_by_key(|i| i.name());
+
+        assert_eq!(2, images.len());
+        assert_eq!("content-query", images[0].name());
+        assert_eq!("example/content-query", images[0].repository());
+        assert_eq!("1.0.5-SNAPSHOT", images[0].version());
+        assert_eq!("content-repo", images[1].name());
+        assert_eq!("2.3.1", images[1].version());
+    }
+
+    #[tokio::test]
+    async fn test_find_images_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("images.json");
+        std::fs::write(&path, IMAGES_FIXTURE).unwrap();
+
+        let images = ContainerImages::find("5200w", SinceBasis::Created, path.to_str(), &[], None, None).await.unwrap();
+
+        assert_eq!(3, images.len());
+        assert!(images.iter().any(|i| i.name() == "mysql" && i.version() == "5.7"));
+    }
+
+    #[test]
+    fn test_newest_image_selected() {
+        let fixture = r#"{"CreatedAt":"2024-03-02 09:00:00 +0000 UTC","ID":"a1b2c3d4e5f6","Repository":"example/content-query","Size":"412MB","Tag":"1.0.6"}
+{"CreatedAt":"2024-03-02 11:30:00 +0000 UTC","ID":"b2c3d4e5f6a1","Repository":"example/content-query","Size":"412MB","Tag":"1.0.7"}
+{"CreatedAt":"2024-03-02 10:00:00 +0000 UTC","ID":"c3d4e5f6a1b2","Repository":"example/content-query","Size":"412MB","Tag":"1.0.5"}
+{"CreatedAt":"2024-03-02 08:00:00 +0000 UTC","ID":"d4e5f6a1b2c3","Repository":"example/search","Size":"120MB","Tag":"3.1.0-SNAPSHOT"}
+{"CreatedAt":"2024-03-02 08:00:00 +0000 UTC","ID":"e5f6a1b2c3d4","Repository":"example/search","Size":"120MB","Tag":"3.1.0"}
+"#;
+        let limit = Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+
+        let mut image_map = HashMap::new();
+        select_recent(read_images(fixture.as_bytes(), None, None).unwrap(), limit, SinceBasis::Created, &mut image_map);
+
+        assert_eq!("1.0.7", image_map["content-query"].version());
+        assert_eq!("3.1.0", image_map["search"].version());
+    }
+
+    #[test]
+    fn test_image_digests() {
+        let fixture = r#"{"CreatedAt":"2024-03-02 10:15:00 +0000 UTC","Digest":"sha256:4f3c2b1a","ID":"a1b2c3d4e5f6","Repository":"example/content-query","Size":"412MB","Tag":"1.0.7"}
+{"CreatedAt":"2024-03-02 10:00:00 +0000 UTC","Digest":"<none>","ID":"b2c3d4e5f6a1","Repository":"example/content-repo","Size":"380MB","Tag":"2.3.2"}
+{"CreatedAt":"2024-03-02 09:45:00 +0000 UTC","ID":"c3d4e5f6a1b2","Repository":"example/search","Size":"120MB","Tag":"3.1.0"}
+"#;
+        let limit = Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+
+        let mut image_map = HashMap::new();
+        select_recent(read_images(fixture.as_bytes(), None, None).unwrap(), limit, SinceBasis::Created, &mut image_map);
+
+        assert_eq!(Some("sha256:4f3c2b1a".to_string()), image_map["content-query"].digest());
+        assert_eq!(None, image_map["content-repo"].digest());
+        assert_eq!(None, image_map["search"].digest());
+    }
+
+    #[test]
+    fn test_transient_failure_classification() {
+        assert!(!is_transient_failure(Some(std::io::ErrorKind::NotFound), ""));
+        assert!(!is_transient_failure(Some(std::io::ErrorKind::PermissionDenied), ""));
+        assert!(is_transient_failure(
+            None,
+            "Cannot connect to the Docker daemon at unix:///var/run/docker.sock. Is the docker daemon running?"
+        ));
+        assert!(is_transient_failure(
+            None,
+            "error during connect: this error may indicate that the docker daemon is not running"
+        ));
+        assert!(!is_transient_failure(None, "unknown flag: --format"));
+    }
+
+    struct MockImageSource {
+        output: Option<&'static str>,
+    }
+
+    impl ImageSource for MockImageSource {
+        fn name(&self) -> &str {
+            "mock"
+        }
+
+        fn list(&self) -> ListFuture<'_> {
+            let result = self.output.map(|o| o.to_string()).ok_or(Error::ImagesCommandFailed {
+                program: "mock".to_string(),
+                status: "exit status: 1".to_string(),
+                stderr: String::new(),
+            });
+            Box::pin(async move { result })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_find_in_multiple_sources() {
+        let containerd = r#"{"CreatedAt":"2024-03-02 11:00:00 +0000 UTC","ID":"e5f6a1b2c3d4","Repository":"example/search","Size":"120MB","Tag":"3.1.0"}
+{"CreatedAt":"2024-03-02 10:30:00 +0000 UTC","ID":"f6a1b2c3d4e5","Repository":"example/content-query","Size":"410MB","Tag":"1.0.6"}
+"#;
+        let sources: Vec<Box<dyn ImageSource>> = vec![
+            Box::new(MockImageSource { output: Some(IMAGES_FIXTURE) }),
+            Box::new(MockImageSource { output: None }),
+            Box::new(MockImageSource { output: Some(containerd) }),
+        ];
+
+        let limit = Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+        let mut images = find_in_sources(&sources, limit, SinceBasis::Created).await.unwrap();
+        images.sort_by_key(|i| i.name());
+
+        let found = images
+            .iter()
+            .map(|i| format!("{}:{}", i.name(), i.version()))
+            .collect::<Vec<_>>();
+
+        // The snapshot from the first source gives way to the release found by the second.
+        assert_eq!(vec!["content-query:1.0.6", "content-repo:2.3.1", "search:3.1.0"], found);
+
+        let failing: Vec<Box<dyn ImageSource>> = vec![Box::new(MockImageSource { output: None })];
+        assert!(find_in_sources(&failing, limit, SinceBasis::Created).await.is_err());
+    }
+
+    #[test]
+    fn test_image_label_filter() {
+        let fixture = r#"{"CreatedAt":"2024-03-02 10:15:00 +0000 UTC","ID":"a1b2c3d4e5f6","Repository":"example/content-query","Size":"412MB","Tag":"1.0.7","Labels":"builder=ci-456"}
+{"CreatedAt":"2024-03-02 10:00:00 +0000 UTC","ID":"b2c3d4e5f6a1","Repository":"example/content-repo","Size":"380MB","Tag":"2.3.2","Labels":"org.opencontainers.image.source=repo, builder=ci-123"}
+{"CreatedAt":"2024-03-02 09:45:00 +0000 UTC","ID":"c3d4e5f6a1b2","Repository":"example/search","Size":"120MB","Tag":"3.1.0"}
+"#;
+        let label = ImageLabel::parse("builder=ci-123").unwrap();
+        let limit = Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+
+        let mut image_map = HashMap::new();
+        let images = read_images(fixture.as_bytes(), Some(&label), None).unwrap();
+        select_recent(images, limit, SinceBasis::Created, &mut image_map);
+
+        assert_eq!(1, image_map.len());
+        assert_eq!("2.3.2", image_map["content-repo"].version());
+
+        assert!(ImageLabel::parse("builder").is_err());
+        assert!(ImageLabel::parse("=ci-123").is_err());
+    }
+
+    #[test]
+    fn test_image_repo_filter() {
+        let fixture = r#"{"CreatedAt":"2024-03-02 10:15:00 +0000 UTC","ID":"a1b2c3d4e5f6","Repository":"example/content-query","Size":"412MB","Tag":"1.0.7"}
+{"CreatedAt":"2024-03-02 10:00:00 +0000 UTC","ID":"b2c3d4e5f6a1","Repository":"example/search","Size":"120MB","Tag":"2.0.1"}
+{"CreatedAt":"2024-03-02 09:45:00 +0000 UTC","ID":"c3d4e5f6a1b2","Repository":"example/nested/search","Size":"120MB","Tag":"1.0.0"}
+{"CreatedAt":"2024-03-02 09:30:00 +0000 UTC","ID":"d4e5f6a1b2c3","Repository":"mysql","Size":"450MB","Tag":"5.7"}
+"#;
+        let repos = |pattern: &str| {
+            read_images(fixture.as_bytes(), None, Some(&ImageRepoFilter::new(pattern)))
+                .unwrap()
+                .into_iter()
+                .map(|i| format!("{}:{}", i.repository, i.tag))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec!["example/content-query:1.0.7", "example/search:2.0.1"], repos("example/*"));
+        assert_eq!(vec!["example/content-query:1.0.7"], repos("example/*:1.*"));
+        assert_eq!(vec!["mysql:5.7"], repos("mysql"));
+        assert_eq!(vec!["example/nested/search:1.0.0"], repos("example/*/s?arch"));
+        assert!(repos("other/*").is_empty());
+
+        assert_eq!("example/", ImageRepoFilter::new("example/*").literal_prefix());
+    }
+
+    #[test]
+    fn test_parse_podman_images() {
+        // As listed by podman images with PODMAN_IMAGES_FORMAT.
+        let output = r#"{"CreatedAt":"2024-03-02 10:15:00 +0000 UTC","ID":"a1b2c3d4e5f6","Repository":"localhost/example/content-query","Size":"412 MB","Tag":"1.0.7"}
+{"CreatedAt":"2024-03-01 08:00:00 +0000 UTC","ID":"b2c3d4e5f6a1","Repository":"docker.io/library/postgres","Size":"438 MB","Tag":"16"}
+"#;
+        let output = strip_podman_localhost(output.to_string());
+        let images = read_images(output.as_bytes(), None, None).unwrap();
+
+        assert_eq!(2, images.len());
+        assert_eq!("example/content-query", images[0].repository);
+        assert_eq!(Utc.with_ymd_and_hms(2024, 3, 2, 10, 15, 0).unwrap(), images[0].created_at);
+        assert_eq!("docker.io/library/postgres", images[1].repository);
+
+        assert_eq!(Some(ImageSourceKind::Podman), ImageSourceKind::parse("podman"));
+        assert_eq!(None, ImageSourceKind::parse("lxc"));
+    }
+
+    /// Answers `pulled_times` from canned `image inspect` output, recording the ids asked for.
+    struct StubImageSource {
+        inspected: &'static str,
+        requested: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl ImageSource for StubImageSource {
+        fn name(&self) -> &str {
+            "stub"
+        }
+
+        fn list(&self) -> ListFuture<'_> {
+            Box::pin(async { Ok(String::new()) })
+        }
+
+        fn pulled_times<'a>(&'a self, ids: &'a [String]) -> PulledFuture<'a> {
+            self.requested.lock().unwrap().extend(ids.iter().cloned());
+            Box::pin(async move { Ok(parse_last_tag_times(self.inspected)) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_since_basis_selection() {
+        // The never pulled redis image is listed first, so the recently pulled postgres image
+        // after it is only found if every image is checked against the limit.
+        let fixture = r#"{"CreatedAt":"2023-05-01 09:00:00 +0000 UTC","ID":"e5f6a1b2c3d4","Repository":"redis","Size":"130MB","Tag":"7.0"}
+{"CreatedAt":"2023-06-01 09:00:00 +0000 UTC","ID":"d4e5f6a1b2c3","Repository":"postgres","Size":"379MB","Tag":"15.3"}
+{"CreatedAt":"2024-03-05 09:00:00 +0000 UTC","ID":"f6a1b2c3d4e5","Repository":"nginx","Size":"187MB","Tag":"1.25"}
+"#;
+        let source = StubImageSource {
+            inspected: "sha256:d4e5f6a1b2c3aaaa 2024-03-02 10:15:00.123456789 +0000 UTC\n\
+                sha256:e5f6a1b2c3d4bbbb 0001-01-01 00:00:00 +0000 UTC\n",
+            requested: Default::default(),
+        };
+
+        let limit = Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+
+        let mut images = read_images(fixture.as_bytes(), None, None).unwrap();
+        inspect_pulled(&source, &mut images, limit).await;
+
+        // Only images created before the limit need inspecting.
+        assert_eq!(
+            vec!["e5f6a1b2c3d4".to_string(), "d4e5f6a1b2c3".to_string()],
+            *source.requested.lock().unwrap()
+        );
+        assert_eq!(None, images[0].pulled_at);
+        assert_eq!(
+            Some(Utc.with_ymd_and_hms(2024, 3, 2, 10, 15, 0).unwrap() + Duration::nanoseconds(123_456_789)),
+            images[1].pulled_at
+        );
+        assert_eq!(None, images[2].pulled_at);
+
+        let mut created = HashMap::new();
+        select_recent(images.clone(), limit, SinceBasis::Created, &mut created);
+        assert_eq!(1, created.len());
+        assert!(created.contains_key("nginx"));
+
+        // The recently pulled postgres image is included too, redis was never pulled so its
+        // created time is used.
+        let mut pulled = HashMap::new();
+        select_recent(images, limit, SinceBasis::Pulled, &mut pulled);
+        assert_eq!(2, pulled.len());
+        assert_eq!("15.3", pulled["postgres"].version());
+        assert!(!pulled.contains_key("redis"));
+    }
 }
@@ -2,25 +2,38 @@
 
 #[macro_use]
 extern crate clap;
-use clap::App;
+use clap::{App, ArgMatches};
 use log::*;
 
 use templates::ComposeServiceMap;
 
-use crate::compose::DockerCompose;
-use crate::images::ContainerImages;
+use crate::bundle::Bundle;
+use crate::compose::{ComposeOptions, DockerCompose};
+use crate::images::{ContainerImages, ImageLabel, ImageRepoFilter, ImageSourceKind, SinceBasis};
 use crate::reference::RunningServices;
-use crate::volumes::Volumes;
+use crate::volumes::{VolumeOptions, Volumes};
+use crate::colors::ColorMode;
+use crate::warnings::{Category, WarningFormat, Warnings};
 use config::HandelConfig;
 use snafu::{ResultExt, Snafu};
+use std::io::IsTerminal;
 
+mod bundle;
+mod check;
+mod colors;
 mod compose;
 mod config;
+mod doctor;
+mod hooks;
 mod images;
+mod oci;
+mod quadlet;
 mod reference;
 mod templates;
 mod utils;
+mod vcs;
 mod volumes;
+mod warnings;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -34,6 +47,45 @@ pub enum Error {
         source: crate::config::Error,
     },
 
+    #[snafu(display(r#"Problem occurred trying to load the environment file.\n{}"#, source))]
+    EnvFile { source: crate::utils::Error },
+
+    #[snafu(display(r#"Unable to parse the image label.\n{}"#, source))]
+    ImageLabelValue { source: crate::images::Error },
+
+    #[snafu(display(r#"Unable to read the patch file: {}\n{}"#, file, source))]
+    ReadPatch {
+        file: String,
+        source: crate::utils::Error,
+    },
+
+    #[snafu(display(r#"Unable to read the existing .env file: {}\n{}"#, file, source))]
+    ReadImageEnv {
+        file: String,
+        source: crate::utils::Error,
+    },
+
+    #[snafu(display(r#"Unable to read the output template: {}\n{}"#, file, source))]
+    ReadOutputTemplate {
+        file: String,
+        source: crate::utils::Error,
+    },
+
+    #[snafu(display(r#"Unable to parse the patch file: {} as a JSON Patch\n{}"#, file, source))]
+    ParsePatch {
+        file: String,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display(r#"Problem occurred trying to open the bundle.\n{}"#, source))]
+    OpenBundle { source: crate::bundle::Error },
+
+    #[snafu(display(r#"Problem occurred trying to read the templates from an image.\n{}"#, source))]
+    ImageTemplates { source: crate::oci::Error },
+
+    #[snafu(display(r#"Unable to initialise volumes.\n{}"#, source))]
+    InitialiseVolumes { source: crate::volumes::Error },
+
     #[snafu(display(r#"Problem occurred trying to load service fragments.\n{}"#, source))]
     Fragments { source: crate::templates::TemplateError },
 
@@ -43,6 +95,12 @@ pub enum Error {
     ))]
     BuildServices { source: crate::config::Error },
 
+    #[snafu(display(r#"Unable to read the service file: {}\n{}"#, file, source))]
+    ServiceFile {
+        file: String,
+        source: crate::utils::Error,
+    },
+
     #[snafu(display(
         r#"Problem occurred trying to generate scenario configuration for scenario: {}\n{}"#,
         scenario,
@@ -54,10 +112,74 @@ pub enum Error {
     },
 
     #[snafu(display(
-        r#"Problem occurred trying to write the docker-compose fileload service fragments.\n{}"#,
+        r#"Problem occurred trying to write the docker-compose file: {}\n{}"#,
+        file,
         source
     ))]
-    WriteComposeFile { source: crate::utils::Error },
+    WriteComposeFile {
+        file: String,
+        source: crate::utils::Error,
+    },
+
+    #[snafu(display(r#"The following named volumes have no volume-init entry: {}"#, volumes))]
+    UnseededVolumes { volumes: String },
+
+    #[snafu(display(r#"The output mode must be an octal file mode, e.g. 0644: {}"#, input))]
+    OutputModeValue { input: String },
+
+    #[snafu(display(r#"Unable to create the directory for the compose file: {}\n{}"#, dir, source))]
+    CreateOutputDir {
+        dir: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display(r#"Unable to create the directory for quadlet units: {}\n{}"#, dir, source))]
+    CreateQuadletDir {
+        dir: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display(r#"Unable to write the quadlet unit: {}\n{}"#, file, source))]
+    WriteQuadletUnit {
+        file: String,
+        source: crate::utils::Error,
+    },
+
+    #[snafu(display(r#"Unable to create the directory for per-service compose files: {}\n{}"#, dir, source))]
+    CreateSplitDir {
+        dir: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display(r#"Unable to write the warnings file: {}\n{}"#, file, source))]
+    WriteWarningsFile {
+        file: String,
+        source: crate::utils::Error,
+    },
+
+    #[snafu(display(r#"Problem occurred running the on-success command.\n{}"#, source))]
+    OnSuccess { source: crate::hooks::Error },
+
+    #[snafu(display(r#"The on-success command has an unterminated quote: {}"#, input))]
+    OnSuccessValue { input: String },
+
+    #[snafu(display(r#"Invalid pin: {}, expecting service=version, e.g. api=1.2.3"#, input))]
+    PinValue { input: String },
+
+    #[snafu(display(r#"Unable to parse the stale warning age: {}\n{}"#, input, source))]
+    StaleWarnAgeValue {
+        input: String,
+        source: crate::images::Error,
+    },
+
+    #[snafu(display(r#"The timeout must be a positive duration, e.g. 30s, 5m or 1h: {}"#, input))]
+    TimeoutValue { input: String },
+
+    #[snafu(display(
+        r#"Handel did not complete within the configured timeout of {}, some work (e.g. volume initialisation) may already have completed."#,
+        duration
+    ))]
+    Timeout { duration: String },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
@@ -68,71 +190,409 @@ async fn main() -> Result<()> {
     let matches = App::from_yaml(yaml)
         .version(crate_version!())
         .get_matches();
-    let config_file = matches
-        .value_of("config")
-        .expect("The input file is required - should default to handel.yml");
-    let env = matches.value_of("env").expect("An environment is required");
-    let since = matches
-        .value_of("since")
-        .expect("Expecting a value for since");
     let verbose = matches.occurrences_of("verbosity") as usize + 1;
     let quiet = matches.is_present("quiet");
 
+    colors::init(match matches.value_of("color") {
+        Some("always") => ColorMode::Always,
+        Some("never") => ColorMode::Never,
+        _ => ColorMode::Auto,
+    });
+
     stderrlog::new()
         .module(module_path!())
+        .color(match colors::stderr_enabled() {
+            true => stderrlog::ColorChoice::Always,
+            false => stderrlog::ColorChoice::Never,
+        })
         .quiet(quiet)
         .verbosity(verbose)
         .timestamp(stderrlog::Timestamp::Off)
         .init()
         .unwrap();
 
-    let config = HandelConfig::new(config_file).context(ConfigFile {
-        file: config_file.to_string(),
+    hooks::explain_commands(matches.is_present("explain-commands"));
+
+    let warnings = Warnings::new(match matches.value_of("warning-format") {
+        Some("github") => WarningFormat::Github,
+        _ => WarningFormat::Human,
+    });
+
+    let result = match matches.value_of("timeout") {
+        Some(t) => {
+            let duration = utils::parse_timeout(t).ok_or_else(|| Error::TimeoutValue {
+                input: t.to_string(),
+            })?;
+
+            tokio::time::timeout(duration, run(&matches, &warnings))
+                .await
+                .map_err(|_| Error::Timeout {
+                    duration: t.to_string(),
+                })
+                .and_then(|r| r)
+        }
+        None => run(&matches, &warnings).await,
+    };
+
+    // Warnings are written even when the run fails, as they may explain why.
+    if let Some(file) = matches.value_of("warnings-file") {
+        warnings.write_file(std::path::Path::new(file)).context(WriteWarningsFile {
+            file: file.to_string(),
+        })?;
+    }
+
+    // The run has returned, so any extracted bundle or templates have already been removed.
+    match result? {
+        0 => Ok(()),
+        code => std::process::exit(code),
+    }
+}
+
+/// Runs handel, returning the exit code of a run which completed but didn't succeed, e.g. as a
+/// check failed or the on-success command exited non-zero.
+async fn run(matches: &ArgMatches<'_>, warnings: &Warnings) -> Result<i32> {
+    let config_files = matches
+        .values_of("config")
+        .expect("The input file is required - should default to handel.yml")
+        .collect::<Vec<_>>();
+    let config_file = config_files[0];
+    let env = matches.value_of("env").expect("An environment is required");
+    let since = matches
+        .value_of("since")
+        .expect("Expecting a value for since");
+
+    if let Some(file) = matches.value_of("env-file") {
+        utils::load_env_file(std::path::Path::new(file), matches.is_present("env-file-override"))
+            .context(EnvFile)?;
+    }
+
+    let bundle = match matches.value_of("bundle") {
+        Some(b) => Some(Bundle::open(b).context(OpenBundle)?),
+        None => None,
+    };
+
+    let load_config = || match &bundle {
+        Some(b) => HandelConfig::new(&[&b.config_file().to_string_lossy()], warnings)
+            .map(|c| c.relative_to(b.dir())),
+        None => HandelConfig::new(&config_files, warnings),
+    };
+
+    if let Some(check_matches) = matches.subcommand_matches("check") {
+        let scenario = check_matches
+            .value_of("scenario")
+            .expect("A scenario is required");
+        let options = check::CheckOptions {
+            env,
+            since,
+            images_from: matches.value_of("images-from"),
+            strict: matches.is_present("strict"),
+            container_cli: matches.value_of("container-cli").and_then(ImageSourceKind::parse),
+            output_template: matches.value_of("output-template"),
+        };
+        let passed = check::run(load_config(), scenario, &options, warnings).await;
+        return Ok(if passed { 0 } else { 1 });
+    }
+
+    if matches.subcommand_matches("doctor").is_some() {
+        let container_cli = matches.value_of("container-cli").and_then(ImageSourceKind::parse);
+        let output = std::path::Path::new(matches.value_of("output").unwrap_or("docker-compose.yml"));
+        let passed = doctor::run(load_config(), env, container_cli, output).await;
+        return Ok(if passed { 0 } else { 1 });
+    }
+
+    let config = load_config().context(ConfigFile {
+        file: matches
+            .value_of("bundle")
+            .map_or_else(|| config_files.join(", "), |b| b.to_string()),
     })?;
 
-    let scenario = matches.value_of("scenario")
-        .or_else(|| {
+    // Templates copied out of an image are removed once the run returns.
+    let container_cli = matches.value_of("container-cli").and_then(ImageSourceKind::parse);
+    let (config, _image_templates) = oci::resolve_templates(config, container_cli)
+        .await
+        .context(ImageTemplates)?;
+
+    if matches.is_present("validate") {
+        let templates = ComposeServiceMap::new(config.template_dir(), config.get_port_range(), warnings)
+            .await
+            .context(Fragments)?;
+
+        let missing = config.missing_references(&templates);
+        for (scenario, entry) in &missing {
+            eprintln!("{} references unknown template or scenario: {}", scenario, entry);
+        }
+
+        if missing.is_empty() {
+            println!("Every scenario entry refers to a template or scenario");
+            return Ok(0);
+        }
+
+        return Ok(1);
+    }
+
+    let service_file = matches.value_of("service-file");
+
+    let scenario_args = matches
+        .values_of("scenario")
+        .map(|s| s.collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let scenario_terms = match scenario_args.is_empty() {
+        true => Vec::new(),
+        false => config::parse_scenario_expression(&scenario_args)
+            .context(BuildServices)?
+            .into_iter()
+            .map(|(op, name)| (op, config.resolve_scenario_alias(&name).to_string()))
+            .collect(),
+    };
+
+    // A scenario expression is described compactly, e.g. web+db-cache, in messages and the manifest.
+    let scenario = match service_file {
+        Some(file) => file.to_string(),
+        None if !scenario_terms.is_empty() => config::describe_scenario_expression(&scenario_terms),
+        None => {
             eprintln!("Expecting a scenario to be provided - the config file defines the following scenarios:\n\t{}",
                 config.get_scenarios().join("\n\t") );
-            std::process::exit(1);
-        }).unwrap();
+            return Ok(1);
+        }
+    };
+    let scenario = scenario.as_str();
+
+    let patch = match matches.value_of("patch") {
+        Some(file) => {
+            let contents = utils::read_file_contents(std::path::Path::new(file))
+                .context(ReadPatch { file: file.to_string() })?;
+            let patch: json_patch::Patch = serde_json::from_str(&contents)
+                .context(ParsePatch { file: file.to_string() })?;
+            Some(patch)
+        }
+        None => None,
+    };
+
+    let output_template = match matches.value_of("output-template") {
+        Some(file) => Some(
+            utils::read_file_contents(std::path::Path::new(file))
+                .context(ReadOutputTemplate { file: file.to_string() })?,
+        ),
+        None => None,
+    };
+
+    let path = std::path::Path::new(
+        matches
+            .value_of("output")
+            .expect("An output path is required - should default to docker-compose.yml"),
+    );
+
+    let output_mode = match matches.value_of("output-mode") {
+        Some(m) => Some(utils::parse_file_mode(m).ok_or_else(|| Error::OutputModeValue {
+            input: m.to_string(),
+        })?),
+        None => None,
+    };
+
+    let dry_run = matches.is_present("dry-run");
+    let override_path = path.with_file_name("docker-compose.override.yml");
+    let image_env_path = path.with_file_name(".env");
+
+    // With the quadlet target units are written in place of the compose file.
+    let quadlet_dir = match matches.value_of("target") {
+        Some("quadlet") => matches.value_of("out-dir"),
+        _ => None,
+    };
+
+    if !dry_run {
+        let mut others = Vec::new();
+        if quadlet_dir.is_none() {
+            if matches.is_present("dev-ports-override") {
+                others.push(override_path.as_path());
+            }
+            if matches.is_present("image-env") {
+                others.push(image_env_path.as_path());
+            }
+        }
+        if let Some(file) = matches.value_of("manifest") {
+            others.push(std::path::Path::new(file));
+        }
+
+        prepare_outputs(quadlet_dir.is_none().then_some(path), &others)?;
+    }
+
+    let mut volume_options =
+        VolumeOptions::new(config.variables(), env, scenario, matches.is_present("strict"));
+    if !matches.is_present("no-cache") {
+        volume_options.cache_dir = volumes::default_cache_dir();
+    }
+    volume_options.force = matches.is_present("force-volumes");
+    volume_options.root = config.volume_root().clone();
+    volume_options.warnings = warnings.clone();
+    volume_options.progress = !matches.is_present("quiet") && std::io::stdout().is_terminal();
+
+    // A container CLI given on the command line replaces the configured image sources.
+    let image_sources = match container_cli {
+        Some(cli) => vec![cli],
+        None => config.image_sources(),
+    };
+
+    let since_basis = match matches.value_of("since-basis") {
+        Some("pulled") => SinceBasis::Pulled,
+        _ => SinceBasis::Created,
+    };
+
+    let image_label = matches
+        .value_of("image-label")
+        .map(ImageLabel::parse)
+        .transpose()
+        .context(ImageLabelValue)?;
+    let image_repo = matches.value_of("image-repo").map(ImageRepoFilter::new);
 
     let (versions, images, fragment_map, volumes) = tokio::join!(
-        RunningServices::load(env, config.get_reference()),
-        ContainerImages::find(since),
-        ComposeServiceMap::new(config.template_dir(),config.get_port_range()),
-        Volumes::initialise(config.volumes())
+        RunningServices::load(env, config.get_reference(), warnings),
+        ContainerImages::find(
+            since,
+            since_basis,
+            matches.value_of("images-from"),
+            &image_sources,
+            image_label.as_ref(),
+            image_repo.as_ref()
+        ),
+        ComposeServiceMap::new(config.template_dir(), config.get_port_range(), warnings),
+        async {
+            // A dry run only checks the volume configuration rather than extracting anything.
+            match dry_run {
+                true => Volumes::validate(config.volumes(), &volume_options),
+                false => Volumes::initialise(config.volumes(), &volume_options).await,
+            }
+        }
     );
 
-    volumes.unwrap_or_else(|e| {
-        error!("Unable to initialise volumes.\n{:?}", e);
-        std::process::exit(1);
-    });
+    volumes.context(InitialiseVolumes)?;
 
     let fragment_map = fragment_map.context(Fragments)?;
 
-    if !config.has_scenario(scenario) {
-        eprintln!("Expecting a valid scenario to be provided ({} supplied) - the config file defines the following scenarios:\n\t{}",
-                  scenario, config.get_scenarios().join("\n\t") );
-        std::process::exit(1);
+    let entries = match service_file {
+        Some(file) => {
+            let contents = utils::read_file_contents(std::path::Path::new(file))
+                .context(ServiceFile { file: file.to_string() })?;
+            config::parse_service_file(&contents)
+        }
+        None => scenario_terms.iter().map(|(_, s)| s.to_string()).collect(),
+    };
+
+    let required_services = match service_file {
+        Some(_) => config
+            .build_service_list_from_names(&entries, &fragment_map)
+            .context(BuildServices)?,
+        None => {
+            if let Some((_, unknown)) = scenario_terms.iter().find(|(_, s)| !config.has_scenario(s)) {
+                eprintln!("Expecting a valid scenario to be provided ({} supplied) - the config file defines the following scenarios:\n\t{}",
+                          unknown, config.get_scenarios().join("\n\t") );
+                return Ok(1);
+            }
+
+            config
+                .build_service_list_from_expression(&scenario_terms, &fragment_map)
+                .context(BuildServices)?
+        }
+    };
+
+    let required_services = match matches.values_of("services") {
+        Some(services) => config
+            .filter_service_list(
+                required_services,
+                &services.collect::<Vec<_>>(),
+                matches.is_present("include-depends"),
+                &fragment_map,
+            )
+            .context(BuildServices)?,
+        None => required_services,
+    };
+
+    // With active profiles, services which none of them enable are excluded as well.
+    let outside_profiles = match matches.values_of("profile") {
+        Some(profiles) => templates::outside_profiles(&required_services, &profiles.collect::<Vec<_>>()),
+        None => Vec::new(),
+    };
+
+    let excluded = matches
+        .values_of("exclude")
+        .into_iter()
+        .flatten()
+        .chain(outside_profiles.iter().map(|s| s.as_str()))
+        .collect::<Vec<_>>();
+
+    // Excluded services are also dropped from the depends_on of those remaining.
+    let pruned;
+    let required_services = match excluded.is_empty() {
+        false => {
+            let (remaining, dependents) = config
+                .exclude_services(required_services, &excluded)
+                .context(BuildServices)?;
+
+            for (service, dependency) in dependents {
+                let message = format!("{} depends on excluded service {}", service, dependency);
+                let file = fragment_map.get_service_fragment(&service).and_then(|s| s.file());
+                warnings.warn(Category::MissingDependency, &message, file, None);
+            }
+
+            pruned = remaining;
+            pruned.iter().collect()
+        }
+        true => required_services,
+    };
+
+    // Conflicts are only resolved between the services of the run, not every template.
+    let reassigned_services;
+    let required_services = match (matches.is_present("auto-port"), config.get_port_range()) {
+        (true, Some(range)) => {
+            let (services, reassigned) = templates::reassign_ports(&required_services, range);
+
+            for (service, port, new_port) in reassigned {
+                let message = format!("Reassigned the conflicting host port {} of {} to {}", port, service, new_port);
+                let file = fragment_map.get_service_fragment(&service).and_then(|s| s.file());
+                warnings.warn(Category::PortConflict, &message, file, None);
+            }
+
+            reassigned_services = services;
+            reassigned_services.iter().collect()
+        }
+        _ => required_services,
+    };
+
+    fragment_map.warn_missing_dependencies(&required_services, warnings);
+
+    let unseeded = volumes::unseeded_named_volumes(&required_services, config.volumes());
+
+    for (svc, volume) in &unseeded {
+        let message = format!(
+            "{} uses the named volume {} which has no volume-init entry, so will start empty",
+            svc.name(),
+            volume
+        );
+        warnings.warn(Category::Volume, &message, svc.file(), None);
     }
 
-    let required_services = config
-        .build_service_list(scenario, &fragment_map)
-        .context(BuildServices)?;
+    if matches.is_present("strict") && !unseeded.is_empty() {
+        return Err(Error::UnseededVolumes {
+            volumes: unseeded.iter().map(|(_, v)| v.as_str()).collect::<Vec<_>>().join(", "),
+        });
+    }
 
     let running_svcs = versions.unwrap_or_else(|e| {
-        warn!(
-            "Warning: Unable to fetch running versions data for {}\n{:?}",
-            &env, e
+        warnings.warn(
+            Category::Reference,
+            &format!("Unable to fetch running versions data for {}\n{}", &env, e),
+            Some(config_file),
+            None,
         );
         Vec::new()
     });
 
     let images = images.unwrap_or_else(|e| {
-        warn!(
-            "\nWarning: Unable to read local container images from docker.\n{:?}",
-            e
+        warnings.warn(
+            Category::LocalImages,
+            &format!("Unable to read local container images from docker.\n{}", e),
+            None,
+            None,
         );
         Vec::new()
     });
@@ -146,11 +606,330 @@ async fn main() -> Result<()> {
         println!("Required services:\n\t{}", names.join("\n\t"));
     }
 
-    let contents =
-        DockerCompose::generate(&required_services, &running_svcs, &images)
+    if matches.is_present("show-unused") {
+        let unused = fragment_map.unused_templates(&required_services);
+
+        match unused.is_empty() {
+            true => println!("Unused templates: none"),
+            false => println!("Unused templates:\n\t{}", unused.join("\n\t")),
+        }
+    }
+
+    let git_tags = match config.git_tag() {
+        Some(source) => {
+            let names = required_services.iter().map(|s| s.name()).collect::<Vec<_>>();
+            source.versions(&names).await
+        }
+        None => Default::default(),
+    };
+
+    let stale_warn_age = match matches.value_of("stale-warn-age") {
+        Some(age) => Some(crate::images::parse_since_string(age).context(StaleWarnAgeValue {
+            input: age.to_string(),
+        })?),
+        None => None,
+    };
+
+    // Pins given on the command line take precedence over those of the scenario.
+    let mut pins = config.collect_pins(&entries);
+    for pin in matches.values_of("pin").into_iter().flatten() {
+        let (service, version) = crate::compose::parse_pin(pin).ok_or_else(|| Error::PinValue {
+            input: pin.to_string(),
+        })?;
+
+        if !required_services.iter().any(|s| s.name() == service) {
+            let message = format!("The pinned service {} isn't among the resolved services", service);
+            warnings.warn(Category::Config, &message, None, None);
+        }
+
+        pins.insert(service, version);
+    }
+
+    let options = ComposeOptions {
+        git_tags,
+        auto_healthcheck: matches.is_present("auto-healthcheck"),
+        stale_warn_age,
+        ..ComposeOptions::from_config(&config, pins, warnings)
+    };
+
+    let mut compose =
+        DockerCompose::build(&required_services, &running_svcs, &images, &options)
             .context(Generate { scenario: scenario.to_string(), })?;
 
-    let path = std::path::Path::new("docker-compose.yml");
+    let ports_override = match matches.is_present("dev-ports-override") {
+        true => Some(compose.take_ports_override().context(Generate { scenario: scenario.to_string(), })?),
+        false => None,
+    };
+
+    // Quadlet units can't interpolate variables, so always name their images directly.  Any
+    // other variables of an existing .env file are kept.
+    let image_env = match matches.is_present("image-env") && quadlet_dir.is_none() {
+        true => {
+            let env = compose.take_image_env();
+            match image_env_path.is_file() {
+                true => Some(DockerCompose::merge_image_env(
+                    &utils::read_file_contents(&image_env_path).context(ReadImageEnv {
+                        file: utils::resolved_display(&image_env_path),
+                    })?,
+                    &env,
+                )),
+                false => Some(env),
+            }
+        }
+        false => None,
+    };
+
+    let contents = match &patch {
+        Some(p) => compose.to_yaml_patched(p),
+        None => compose.to_yaml(),
+    }
+    .context(Generate { scenario: scenario.to_string(), })?;
+
+    // Where a version was resolved from depends on the local images, so isn't reproducible.
+    let contents = match matches.is_present("annotate-source") && !config.deterministic() {
+        true => compose.annotate_sources(&contents),
+        false => contents,
+    };
 
-    utils::write_str_to_file(path, &contents).context(WriteComposeFile)
+    let contents = match &output_template {
+        Some(t) => compose
+            .render_template(t, &contents, scenario, env)
+            .context(Generate { scenario: scenario.to_string(), })?,
+        None => contents,
+    };
+
+    let quadlet_units = quadlet_dir.map(|_| {
+        let (units, unsupported) = quadlet::units(&compose);
+        for message in unsupported {
+            warnings.warn(Category::Unsupported, &message, None, None);
+        }
+        units
+    });
+
+    if dry_run {
+        match quadlet_dir {
+            Some(dir) => println!("Dry run - no quadlet units were written to {}", dir),
+            None => println!("Dry run - {} was not written", path.display()),
+        }
+        return Ok(0);
+    }
+
+    if let (Some(dir), Some(units)) = (quadlet_dir, quadlet_units) {
+        std::fs::create_dir_all(dir).context(CreateQuadletDir { dir: dir.to_string() })?;
+
+        for unit in units {
+            let unit_path = std::path::Path::new(dir).join(&unit.file_name);
+            utils::write_str_to_file(&unit_path, &unit.contents).context(WriteQuadletUnit {
+                file: utils::resolved_display(&unit_path),
+            })?;
+        }
+    } else {
+        write_compose_files(path, &contents, (&override_path, ports_override), (&image_env_path, image_env), output_mode)?;
+    }
+
+    if let Some(file) = matches.value_of("manifest") {
+        let timestamp = match config.deterministic() {
+            true => None,
+            false => Some(chrono::Utc::now().to_rfc3339()),
+        };
+        let manifest = compose
+            .manifest(scenario, env, timestamp)
+            .to_json()
+            .context(Generate { scenario: scenario.to_string(), })?;
+
+        let manifest_path = std::path::Path::new(file);
+        utils::write_str_to_file(manifest_path, &manifest).context(WriteComposeFile {
+            file: utils::resolved_display(manifest_path),
+        })?;
+    }
+
+    if quadlet_dir.is_none() && matches.is_present("check-vcs") && vcs::status(path).await == vcs::VcsStatus::Tracked {
+        let message = format!(
+            "{} is tracked by git but is regenerated by each run, either ignore it in .gitignore \
+            or commit it only from deterministic runs",
+            path.display()
+        );
+        warnings.warn(Category::Vcs, &message, Some(&path.to_string_lossy()), None);
+    }
+
+    if let Some(dir) = matches.value_of("split-dir") {
+        let documents = compose.split().context(Generate { scenario: scenario.to_string(), })?;
+
+        std::fs::create_dir_all(dir).context(CreateSplitDir { dir: dir.to_string() })?;
+
+        for (file_name, document) in documents {
+            let path = std::path::Path::new(dir).join(file_name);
+            utils::write_str_to_file(&path, &document).context(WriteComposeFile {
+                file: utils::resolved_display(&path),
+            })?;
+        }
+    }
+
+    let on_success = match matches.value_of("on-success") {
+        Some(c) => hooks::parse_command_line(c).ok_or_else(|| Error::OnSuccessValue {
+            input: c.to_string(),
+        })?,
+        None => config.on_success().clone().unwrap_or_default(),
+    };
+
+    if !on_success.is_empty() {
+        return hooks::run_on_success(&on_success).await.context(OnSuccess);
+    }
+
+    Ok(0)
+}
+
+/// Creates the directory of the compose file, when one is written, and checks that it and each
+/// of the other output files could be written, so that a run fails before any work is done.
+fn prepare_outputs(compose: Option<&std::path::Path>, others: &[&std::path::Path]) -> Result<()> {
+    if let Some(dir) = compose.and_then(|p| p.parent()).filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).context(CreateOutputDir {
+            dir: dir.display().to_string(),
+        })?;
+    }
+
+    for path in compose.iter().chain(others) {
+        utils::preflight_output_path(path).context(WriteComposeFile {
+            file: utils::resolved_display(path),
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Writes the compose file, along with the ports override and image `.env` file when they were
+/// taken, setting their mode.
+fn write_compose_files(
+    path: &std::path::Path,
+    contents: &str,
+    ports_override: (&std::path::Path, Option<String>),
+    image_env: (&std::path::Path, Option<String>),
+    output_mode: Option<u32>,
+) -> Result<()> {
+    for (extra_path, extra) in [ports_override, image_env] {
+        if let Some(extra) = extra {
+            utils::write_str_to_file(extra_path, &extra).context(WriteComposeFile {
+                file: utils::resolved_display(extra_path),
+            })?;
+
+            if let Some(mode) = output_mode {
+                utils::set_file_mode(extra_path, mode).context(WriteComposeFile {
+                    file: utils::resolved_display(extra_path),
+                })?;
+            }
+        }
+    }
+
+    utils::write_str_to_file(path, contents).context(WriteComposeFile {
+        file: utils::resolved_display(path),
+    })?;
+
+    if let Some(mode) = output_mode {
+        utils::set_file_mode(path, mode).context(WriteComposeFile {
+            file: utils::resolved_display(path),
+        })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prepare_outputs_creates_compose_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested/dir/compose.yml");
+        let manifest = dir.path().join("manifest.json");
+
+        prepare_outputs(Some(&path), &[&manifest]).unwrap();
+
+        assert!(dir.path().join("nested/dir").is_dir());
+        assert!(!path.exists());
+        assert!(!manifest.exists());
+    }
+
+    #[test]
+    fn test_prepare_outputs_fails_on_unwritable_path() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("file"), "").unwrap();
+
+        // The compose directory can't be created beneath a file.
+        let path = dir.path().join("file/compose.yml");
+        assert!(matches!(
+            prepare_outputs(Some(&path), &[]),
+            Err(Error::CreateOutputDir { .. })
+        ));
+
+        // Nor can an output be written over a directory, and nothing is written before the
+        // failing output is found.
+        let path = dir.path().join("compose.yml");
+        let manifest = dir.path().join("manifest");
+        std::fs::create_dir(&manifest).unwrap();
+        assert!(matches!(
+            prepare_outputs(Some(&path), &[&manifest]),
+            Err(Error::WriteComposeFile { .. })
+        ));
+        assert!(!path.exists());
+
+        // With the quadlet target only the other outputs are checked.
+        assert!(matches!(
+            prepare_outputs(None, &[&dir.path().join("missing/manifest.json")]),
+            Err(Error::WriteComposeFile { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_writes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let templates = dir.path().join("templates");
+        std::fs::create_dir(&templates).unwrap();
+        std::fs::write(templates.join("redis.yml"), "image: redis:4\nports:\n  - 6379:6379\n").unwrap();
+
+        let config = dir.path().join("handel.yml");
+        std::fs::write(
+            &config,
+            format!("template-folder-path: {}\nscenarios:\n  app:\n    - redis\n", templates.display()),
+        )
+        .unwrap();
+
+        let path = |name: &str| dir.path().join(name).to_string_lossy().to_string();
+        let marker = dir.path().join("on-success-ran");
+        let on_success = format!("touch {}", marker.display());
+
+        let yaml = load_yaml!("./cli.yml");
+        let matches = App::from_yaml(yaml)
+            .get_matches_from_safe(vec![
+                "handel".to_string(),
+                "-c".to_string(),
+                path("handel.yml"),
+                "--images-from".to_string(),
+                "/dev/null".to_string(),
+                "--no-cache".to_string(),
+                "--dry-run".to_string(),
+                "-o".to_string(),
+                path("out/docker-compose.yml"),
+                "--dev-ports-override".to_string(),
+                "--image-env".to_string(),
+                "--manifest".to_string(),
+                path("manifest.json"),
+                "--split-dir".to_string(),
+                path("split"),
+                "--on-success".to_string(),
+                on_success,
+                "app".to_string(),
+            ])
+            .unwrap();
+
+        assert_eq!(0, run(&matches, &Warnings::default()).await.unwrap());
+
+        let mut written = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        written.sort();
+        assert_eq!(vec!["handel.yml", "templates"], written);
+        assert!(!marker.exists());
+    }
 }
@@ -0,0 +1,212 @@
+use log::*;
+use std::path::{Path, PathBuf};
+
+use snafu::{ResultExt, Snafu};
+use tempfile::TempDir;
+
+use crate::config::HandelConfig;
+use crate::images::ImageSourceKind;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display(
+        "Invalid image template path: {}, expecting e.g. oci://example/templates:1.0#/templates",
+        input
+    ))]
+    InvalidReference { input: String },
+
+    #[snafu(display("Unable to create a temporary directory for the image templates.\n{}", source))]
+    TemplateDir { source: std::io::Error },
+
+    #[snafu(display("Unable to run {} to read the templates of image: {}\n{}", program, image, source))]
+    RunContainerCli {
+        program: String,
+        image: String,
+        source: crate::hooks::Error,
+    },
+
+    #[snafu(display("Unable to create a container from image: {}\n{}", image, message))]
+    CreateContainer { image: String, message: String },
+
+    #[snafu(display("Unable to copy {} out of image: {}\n{}", path, image, message))]
+    CopyTemplates {
+        image: String,
+        path: String,
+        message: String,
+    },
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+pub const OCI_SCHEME: &str = "oci://";
+
+/// A template directory held within a container image, given as `oci://image:tag#path`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct OciReference {
+    pub image: String,
+    pub path: String,
+}
+
+impl OciReference {
+    /// Parses an `oci://` template path, returning None for any other path.  The path within
+    /// the image is made absolute.
+    pub fn parse(input: &str) -> Result<Option<OciReference>> {
+        let reference = match input.strip_prefix(OCI_SCHEME) {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+
+        match reference.rsplit_once('#') {
+            Some((image, path)) if !image.is_empty() && !path.is_empty() => {
+                let path = match path.starts_with('/') {
+                    true => path.to_string(),
+                    false => format!("/{}", path),
+                };
+
+                Ok(Some(OciReference {
+                    image: image.to_string(),
+                    path,
+                }))
+            }
+            _ => Err(Error::InvalidReference {
+                input: input.to_string(),
+            }),
+        }
+    }
+}
+
+/// Templates copied out of a container image into a temporary directory, which is removed
+/// when this is dropped.
+pub struct OciTemplates {
+    dir: TempDir,
+}
+
+impl OciTemplates {
+    /// Creates (pulling if required) a container from the image with the given container CLI,
+    /// copies the template directory out of it and removes the container again.  The container
+    /// is never started.
+    pub async fn extract(reference: &OciReference, cli: ImageSourceKind) -> Result<OciTemplates> {
+        let dir = tempfile::tempdir().context(TemplateDir)?;
+        let templates = OciTemplates { dir };
+        std::fs::create_dir(templates.dir()).context(TemplateDir)?;
+
+        // A command is given as docker refuses to create a container from an image without one.
+        let output = run_cli(cli, &reference.image, &["create", &reference.image, "true"]).await?;
+        if !output.status.success() {
+            return Err(Error::CreateContainer {
+                image: reference.image.clone(),
+                message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+
+        let container = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        let source = format!("{}:{}/.", container, reference.path.trim_end_matches('/'));
+        let target = templates.dir().to_string_lossy().to_string();
+
+        let copied = run_cli(cli, &reference.image, &["cp", &source, &target]).await;
+
+        if let Err(e) = run_cli(cli, &reference.image, &["rm", &container]).await {
+            warn!("Warning: Unable to remove the container {}: {}", container, e);
+        }
+
+        let copied = copied?;
+        if !copied.status.success() {
+            return Err(Error::CopyTemplates {
+                image: reference.image.clone(),
+                path: reference.path.clone(),
+                message: String::from_utf8_lossy(&copied.stderr).trim().to_string(),
+            });
+        }
+
+        info!("{} - copied the templates of {} to {}", module_path!(), reference.image, target);
+
+        Ok(templates)
+    }
+
+    pub fn dir(&self) -> PathBuf {
+        self.dir.path().join("templates")
+    }
+}
+
+async fn run_cli(cli: ImageSourceKind, image: &str, args: &[&str]) -> Result<std::process::Output> {
+    let spec = std::iter::once(cli.program())
+        .chain(args.iter().copied())
+        .map(|a| a.to_string())
+        .collect::<Vec<_>>();
+
+    let output = crate::hooks::run_captured(&spec).await.context(RunContainerCli {
+        program: cli.program(),
+        image: image.to_string(),
+    })?;
+
+    Ok(output.expect("Internal error: the container command is never empty"))
+}
+
+/// The container CLI used to copy templates out of an image, the one given on the command line
+/// if any, otherwise the first of the configured image sources, as for listing local images.
+pub fn template_cli(config: &HandelConfig, container_cli: Option<ImageSourceKind>) -> ImageSourceKind {
+    container_cli
+        .or_else(|| config.image_sources().first().copied())
+        .unwrap_or(ImageSourceKind::Docker)
+}
+
+/// Copies the templates out of the image when the template folder path is an `oci://`
+/// reference, pointing the configuration at the copy.  The returned templates must be kept
+/// until they are no longer needed.
+pub async fn resolve_templates(
+    config: HandelConfig,
+    container_cli: Option<ImageSourceKind>,
+) -> Result<(HandelConfig, Option<OciTemplates>)> {
+    match OciReference::parse(config.template_dir())? {
+        Some(reference) => {
+            let templates = OciTemplates::extract(&reference, template_cli(&config, container_cli)).await?;
+            let config = config.with_template_dir(Path::new(&templates.dir()));
+            Ok((config, Some(templates)))
+        }
+        None => Ok((config, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reference() {
+        assert_eq!(None, OciReference::parse("./templates").unwrap());
+
+        assert_eq!(
+            Some(OciReference {
+                image: "registry.example.com/handel/templates:1.4".to_string(),
+                path: "/templates".to_string(),
+            }),
+            OciReference::parse("oci://registry.example.com/handel/templates:1.4#/templates").unwrap()
+        );
+
+        let digest = OciReference::parse("oci://example/templates@sha256:abc123#srv/templates")
+            .unwrap()
+            .unwrap();
+        assert_eq!("example/templates@sha256:abc123", digest.image);
+        assert_eq!("/srv/templates", digest.path);
+
+        for invalid in ["oci://example/templates:1.4", "oci://#/templates", "oci://example:1#"] {
+            assert!(
+                matches!(OciReference::parse(invalid), Err(Error::InvalidReference { .. })),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_template_cli() {
+        let config = |yaml: &str| -> HandelConfig { serde_yaml::from_str(yaml).unwrap() };
+
+        let default = config("template-folder-path: oci://example/templates:1.4#/templates");
+        assert_eq!(ImageSourceKind::Docker, template_cli(&default, None));
+        assert_eq!(ImageSourceKind::Podman, template_cli(&default, Some(ImageSourceKind::Podman)));
+
+        let podman = config("template-folder-path: oci://example/templates:1.4#/templates\nimage-sources: [podman, docker]");
+        assert_eq!(ImageSourceKind::Podman, template_cli(&podman, None));
+    }
+}
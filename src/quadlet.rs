@@ -0,0 +1,245 @@
+use crate::compose::DockerCompose;
+use crate::templates::ComposeServiceFragment;
+use std::fmt::Write;
+
+/// A Podman quadlet `.container` unit translated from a resolved service.
+#[derive(Debug)]
+pub struct QuadletUnit {
+    pub file_name: String,
+    pub contents: String,
+}
+
+/// Translates each service of the compose document into a quadlet unit, returning the units in
+/// service name order along with a warning for each field which quadlet can't express.
+pub fn units(compose: &DockerCompose) -> (Vec<QuadletUnit>, Vec<String>) {
+    let mut names = compose.services().keys().collect::<Vec<_>>();
+    names.sort();
+
+    let mut warnings = Vec::new();
+    let units = names
+        .into_iter()
+        .map(|name| QuadletUnit {
+            file_name: format!("{}.container", unit_name(name)),
+            contents: container_unit(name, &compose.services()[name], &mut warnings),
+        })
+        .collect();
+
+    (units, warnings)
+}
+
+/// Systemd unit names are restricted to ASCII alphanumerics and `:_.-`.
+fn unit_name(service: &str) -> String {
+    service
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || matches!(c, ':' | '_' | '.' | '-') {
+            true => c,
+            false => '_',
+        })
+        .collect()
+}
+
+/// Quotes a value in the systemd style when it contains whitespace or quotes.
+fn quote(value: &str) -> String {
+    match value.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'' || c == '\\') {
+        true => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+        false => value.to_string(),
+    }
+}
+
+fn container_unit(name: &str, fragment: &ComposeServiceFragment, warnings: &mut Vec<String>) -> String {
+    let mut unsupported = |field: &str| {
+        warnings.push(format!(
+            "{} of service {} isn't supported by quadlet units and was ignored",
+            field, name
+        ));
+    };
+
+    let mut unit = String::new();
+    let _ = writeln!(unit, "[Unit]");
+    let _ = writeln!(unit, "Description={}", name);
+
+    if let Some(depends_on) = &fragment.depends_on {
+        let mut dependencies = depends_on.names();
+        dependencies.sort();
+
+        for dependency in dependencies {
+            let _ = writeln!(unit, "Requires={}.service", unit_name(&dependency));
+            let _ = writeln!(unit, "After={}.service", unit_name(&dependency));
+        }
+
+        if let crate::templates::DependsOn::Map(m) = depends_on {
+            if m.values().any(|c| c.condition.as_deref().unwrap_or("service_started") != "service_started") {
+                unsupported("A depends_on condition");
+            }
+        }
+    }
+
+    let _ = writeln!(unit);
+    let _ = writeln!(unit, "[Container]");
+    let _ = writeln!(unit, "Image={}", fragment.image);
+    let _ = writeln!(unit, "ContainerName={}", name);
+
+    for port in fragment.ports.iter().flatten() {
+        let _ = writeln!(unit, "PublishPort={}", port);
+    }
+
+    if let Some(environment) = &fragment.environment {
+        let mut environment = environment.iter().collect::<Vec<_>>();
+        environment.sort();
+
+        for (key, value) in environment {
+            let _ = writeln!(unit, "Environment={}", quote(&format!("{}={}", key, value)));
+        }
+    }
+
+    for file in fragment.env_file.iter().flatten() {
+        let _ = writeln!(unit, "EnvironmentFile={}", file);
+    }
+
+    for volume in fragment.volumes.iter().flatten() {
+        let _ = writeln!(unit, "Volume={}", volume);
+    }
+
+    if let Some(labels) = &fragment.labels {
+        let mut labels = labels.iter().collect::<Vec<_>>();
+        labels.sort();
+
+        for (key, value) in labels {
+            let _ = writeln!(unit, "Label={}", quote(&format!("{}={}", key, value)));
+        }
+    }
+
+    for network in fragment.networks.iter().flatten() {
+        let _ = writeln!(unit, "Network={}", network);
+    }
+
+    for secret in fragment.secrets.iter().flatten() {
+        let _ = writeln!(unit, "Secret={}", secret);
+    }
+
+    for capability in fragment.cap_add.iter().flatten() {
+        let _ = writeln!(unit, "AddCapability={}", capability);
+    }
+
+    for capability in fragment.cap_drop.iter().flatten() {
+        let _ = writeln!(unit, "DropCapability={}", capability);
+    }
+
+    if let Some(logging) = &fragment.logging {
+        if let Some(driver) = &logging.driver {
+            let _ = writeln!(unit, "LogDriver={}", driver);
+        }
+        if logging.options.as_ref().is_some_and(|o| !o.is_empty()) {
+            unsupported("Logging options");
+        }
+    }
+
+    if fragment.platform.is_some() {
+        unsupported("platform");
+    }
+    if fragment.deploy.is_some() {
+        unsupported("deploy");
+    }
+    if fragment.configs.is_some() {
+        unsupported("configs");
+    }
+    if fragment.security_opt.is_some() {
+        unsupported("security_opt");
+    }
+    if fragment.healthcheck.is_some() {
+        unsupported("healthcheck");
+    }
+    if fragment.profiles.is_some() {
+        unsupported("profiles");
+    }
+
+    if let Some(restart) = &fragment.restart {
+        // unless-stopped has no systemd equivalent, a stopped unit isn't restarted anyway.
+        let policy = match restart.policy() {
+            "unless-stopped" => "always",
+            p => p,
+        };
+
+        let _ = writeln!(unit);
+        let _ = writeln!(unit, "[Service]");
+        let _ = writeln!(unit, "Restart={}", policy);
+
+        if restart.max_retries().is_some() {
+            unsupported("The restart retry count");
+        }
+    }
+
+    let _ = writeln!(unit);
+    let _ = writeln!(unit, "[Install]");
+    let _ = writeln!(unit, "WantedBy=default.target");
+
+    unit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compose::ComposeOptions;
+    use crate::templates::ComposeService;
+
+    #[test]
+    fn test_container_unit() {
+        let api: ComposeServiceFragment = serde_yaml::from_str(
+            r#"
+image: example/api:1.0
+depends_on:
+  - db
+ports:
+  - "8080:80"
+  - 53:53/udp
+environment:
+  LOG_LEVEL: debug
+  GREETING: hello world
+restart: unless-stopped
+deploy:
+  replicas: 2
+"#,
+        )
+        .unwrap();
+        let db: ComposeServiceFragment = serde_yaml::from_str("image: mysql:5.7").unwrap();
+
+        let svcs = [
+            &ComposeService::new("api", "example/api:1.0", &api),
+            &ComposeService::new("db", "mysql:5.7", &db),
+        ];
+
+        let compose = DockerCompose::build(&svcs, &[], &[], &ComposeOptions::default()).unwrap();
+        let (units, warnings) = units(&compose);
+
+        assert_eq!(
+            vec!["api.container", "db.container"],
+            units.iter().map(|u| u.file_name.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            r#"[Unit]
+Description=api
+Requires=db.service
+After=db.service
+
+[Container]
+Image=example/api:1.0
+ContainerName=api
+PublishPort=8080:80
+PublishPort=53:53/udp
+Environment="GREETING=hello world"
+Environment=LOG_LEVEL=debug
+
+[Service]
+Restart=always
+
+[Install]
+WantedBy=default.target
+"#,
+            units[0].contents
+        );
+        assert_eq!(
+            vec!["deploy of service api isn't supported by quadlet units and was ignored"],
+            warnings
+        );
+    }
+}
@@ -1,10 +1,15 @@
 use log::*;
 use serde::Deserialize;
 use snafu::{ResultExt, Snafu};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
 use std::time::Duration;
-use tokio::io::AsyncWriteExt;
-use tokio::process::Command;
+use jaq_core::load::{Arena, File, Loader};
+use jaq_core::{Compiler, Ctx, RcIter};
+use jaq_json::Val;
+
+use crate::templates::ImageVersion;
+use crate::warnings::{Category, Warnings};
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -14,32 +19,36 @@ pub enum Error {
     #[snafu(display(r#"Unable to create HTTP request to {}.\n{}"#, url, source))]
     HttpRequest { url: String, source: reqwest::Error },
 
-    #[snafu(display(r#"Unable to read HTTP response body.\n{}"#, source))]
-    HttpResponseBody { source: reqwest::Error },
+    #[snafu(display(r#"Unable to expand the environment variables of the reference header: {}\n{}"#, name, source))]
+    HeaderValue {
+        name: String,
+        source: shellexpand::LookupError<std::env::VarError>,
+    },
 
-    #[snafu(display(r#"Unable to parse HTTP response body as JSON.\n{}"#, source))]
-    ParseResponseBody { source: serde_json::Error },
+    #[snafu(display(r#"Unable to expand the environment variables of {}: {}\n{}"#, field, value, source))]
+    ExpandUrl {
+        field: String,
+        value: String,
+        source: shellexpand::LookupError<std::env::VarError>,
+    },
 
-    #[snafu(display(
-        r#"Unable to execute jq command, is it available and in the path?\n{}"#,
-        source
-    ))]
-    JqExecute { source: std::io::Error },
+    #[snafu(display(r#"The reference at {} responded with HTTP status {}"#, url, status))]
+    HttpStatus { url: String, status: reqwest::StatusCode },
 
-    #[snafu(display(r#"Unable to get input for jq command"#))]
-    JqStdinOpen,
+    #[snafu(display(r#"Unable to read the reference file.\n{}"#, source))]
+    ReadReferenceFile { source: crate::utils::Error },
 
-    #[snafu(display(r#"Unable wait for jq command termination\n{}"#, source))]
-    JqAwait { source: std::io::Error },
+    #[snafu(display(r#"Unable to read HTTP response body.\n{}"#, source))]
+    HttpResponseBody { source: reqwest::Error },
 
-    #[snafu(display(r#"Unable to get input for jq command\n{}"#, source))]
-    JqStdinHandle { source: std::io::Error },
+    #[snafu(display(r#"Unable to parse HTTP response body as JSON.\n{}"#, source))]
+    ParseResponseBody { source: serde_json::Error },
 
-    #[snafu(display(r#"Unable to get input for jq command\n{}"#, source))]
-    JqStdoutHandle { source: std::io::Error },
+    #[snafu(display(r#"Unable to compile the jq filter: {}\n{}"#, filter, message))]
+    JqCompile { filter: String, message: String },
 
-    #[snafu(display(r#"Unable to read jq output as utf8\n{}"#, source))]
-    JqStdoutRead { source: std::string::FromUtf8Error },
+    #[snafu(display(r#"Unable to apply the jq filter to the reference response.\n{}"#, message))]
+    JqRun { message: String },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
@@ -48,6 +57,10 @@ type Result<T, E = Error> = std::result::Result<T, E>;
 pub struct RunningService {
     name: String,
     version: String,
+    /// The other properties of the reference entry, only those listed by the reference's
+    /// `provenance` are kept, e.g. the git commit and build time.
+    #[serde(flatten)]
+    extra: BTreeMap<String, serde_json::Value>,
 }
 
 
@@ -55,12 +68,77 @@ pub struct RunningService {
 pub struct RunningServices {
 }
 
+/// Describes the shape of the (possibly jq filtered) reference response.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReferenceKind {
+    /// A list of objects with name and version properties.
+    #[default]
+    Versions,
+    /// A list of objects with an image property, e.g. as reported for Kubernetes deployments.
+    Kubernetes,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Reference {
     url: String,
     env_mappings: Option<HashMap<String, String>>,
     jq_filter: Option<String>,
+    #[serde(default)]
+    kind: ReferenceKind,
+    #[serde(default, deserialize_with = "de_optional_duration")]
+    connect_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "de_optional_duration")]
+    read_timeout: Option<Duration>,
+    headers: Option<HashMap<String, String>>,
+    health_url: Option<String>,
+    #[serde(default, deserialize_with = "de_optional_duration")]
+    health_timeout: Option<Duration>,
+    retries: Option<u32>,
+    #[serde(default, deserialize_with = "de_optional_duration")]
+    retry_delay: Option<Duration>,
+    provenance: Option<Vec<String>>,
+}
+
+/// The overall timeout applied to the reference request when no read-timeout is configured.
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the health-url is polled for when no health-timeout is configured.
+const DEFAULT_HEALTH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The delay between polls of the health-url.
+const HEALTH_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many times a failed reference request is retried when no retries are configured.
+const DEFAULT_RETRIES: u32 = 3;
+
+/// The delay before the first retry when no retry-delay is configured, doubling for each retry.
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The timeouts applied to the HTTP client used to fetch the reference.
+#[derive(Debug, PartialEq, Eq)]
+struct ClientTimeouts {
+    connect: Option<Duration>,
+    request: Duration,
+}
+
+fn de_optional_duration<'de, D>(deserializer: D) -> std::result::Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+
+    crate::images::parse_since_string(&s)
+        .map_err(serde::de::Error::custom)?
+        .to_std()
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Deserialize)]
+struct DeploymentImage {
+    image: String,
 }
 
 impl RunningService {
@@ -69,7 +147,20 @@ impl RunningService {
     pub fn new(name: &str, version: &str) -> Self {
         RunningService {
             name: name.to_string(),
-            version: version.to_string()
+            version: version.to_string(),
+            extra: BTreeMap::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_provenance(name: &str, version: &str, provenance: &[(&str, &str)]) -> Self {
+        RunningService {
+            name: name.to_string(),
+            version: version.to_string(),
+            extra: provenance
+                .iter()
+                .map(|(k, v)| (k.to_string(), serde_json::Value::from(*v)))
+                .collect(),
         }
     }
 
@@ -80,11 +171,126 @@ impl RunningService {
     pub fn version(self: &RunningService) -> String {
         self.version.clone()
     }
+
+    /// The captured provenance properties, non-string values being given as JSON.
+    pub fn provenance(self: &RunningService) -> BTreeMap<String, String> {
+        self.extra
+            .iter()
+            .map(|(k, v)| match v {
+                serde_json::Value::String(s) => (k.clone(), s.clone()),
+                v => (k.clone(), v.to_string()),
+            })
+            .collect()
+    }
+}
+
+impl Reference {
+    /// The url from which versions are fetched for the given environment.
+    pub fn url_for(&self, env: &str) -> Result<String> {
+        self.expand_url("url", &self.url, env)
+    }
+
+    /// The url polled until healthy before the versions are fetched for the given environment.
+    pub fn health_url_for(&self, env: &str) -> Result<Option<String>> {
+        self.health_url
+            .as_ref()
+            .map(|u| self.expand_url("health-url", u, env))
+            .transpose()
+    }
+
+    /// Substitutes the environment, after any env-mapping, and expands environment variables,
+    /// failing rather than leaving an unset variable in the url.
+    fn expand_url(&self, field: &str, url: &str, env: &str) -> Result<String> {
+        // Map the incoming env str to using the env-mappings if they exist.
+        let env = match &self.env_mappings {
+            Some(m) => m.get(env).map(|e| e.as_str()).unwrap_or(env),
+            None => env,
+        };
+
+        let url = url.replace("{env}", env);
+        shellexpand::env(&url)
+            .map(|u| u.to_string())
+            .context(ExpandUrl {
+                field: field.to_string(),
+                value: url.clone(),
+            })
+    }
+
+    /// The file to read the versions from rather than making a request, when the url for the
+    /// environment is a `file://` url or a plain path without a scheme.
+    pub fn file_for(&self, env: &str) -> Result<Option<PathBuf>> {
+        let url = self.url_for(env)?;
+
+        Ok(match url.strip_prefix("file://") {
+            Some(path) => Some(PathBuf::from(path)),
+            None if !url.contains("://") => Some(PathBuf::from(url)),
+            None => None,
+        })
+    }
+
+    pub fn jq_filter(&self) -> Option<&str> {
+        self.jq_filter.as_deref()
+    }
+
+    /// Without either timeout configured the whole request is limited to 10s, otherwise the
+    /// connect-timeout limits establishing the connection and the read-timeout the request.
+    fn timeouts(&self) -> ClientTimeouts {
+        ClientTimeouts {
+            connect: self.connect_timeout,
+            request: self.read_timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT),
+        }
+    }
+
+    /// The headers sent with the reference request, with environment variables expanded in their
+    /// values so that e.g. tokens needn't be committed to the configuration.
+    pub fn headers(&self) -> Result<Vec<(String, String)>> {
+        let mut headers = self
+            .headers
+            .iter()
+            .flatten()
+            .map(|(name, value)| {
+                shellexpand::env(value)
+                    .map(|v| (name.clone(), v.to_string()))
+                    .context(HeaderValue { name: name.clone() })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        headers.sort();
+        Ok(headers)
+    }
+
+    fn retries(&self) -> u32 {
+        self.retries.unwrap_or(DEFAULT_RETRIES)
+    }
+
+    /// The exponential backoff before the given retry, counting from zero.
+    fn retry_delay(&self, retry: u32) -> Duration {
+        let base = self.retry_delay.unwrap_or(DEFAULT_RETRY_DELAY);
+
+        2u32.checked_pow(retry)
+            .and_then(|factor| base.checked_mul(factor))
+            .unwrap_or(Duration::MAX)
+    }
+
+    /// A client with the configured connect and request timeouts.
+    pub fn client_builder(&self) -> reqwest::ClientBuilder {
+        let timeouts = self.timeouts();
+        let builder = reqwest::Client::builder().timeout(timeouts.request);
+
+        match timeouts.connect {
+            Some(c) => builder.connect_timeout(c),
+            None => builder,
+        }
+    }
 }
 
 impl RunningServices {
 
-    pub async fn load(env: &str, reference: &Option<Reference>) -> Result<Vec<RunningService>> {
+    pub async fn load(
+        env: &str,
+        reference: &Option<Reference>,
+        warnings: &Warnings,
+    ) -> Result<Vec<RunningService>> {
         if reference.is_none() {
             return Ok(Vec::new());
         }
@@ -93,31 +299,37 @@ impl RunningServices {
         let reference = reference.as_ref().unwrap();
         debug!("{} - Reference options: {:?}", module_path!(), &reference);
 
-        // Map the incoming env str to using the env-mappings if they exist.
-        let env = match &reference.env_mappings {
-            Some(m) => m.get(env).map(|e| e.as_str()).unwrap_or(env),
-            None => env,
-        };
+        let body = match reference.file_for(env)? {
+            Some(path) => {
+                info!(
+                    "{} - Reading versions from reference file at: {}",
+                    module_path!(),
+                    path.display()
+                );
 
-        let url = reference.url.replace("{env}", env);
+                crate::utils::read_file_contents(&path).context(ReadReferenceFile)?
+            }
+            None => {
+                let url = reference.url_for(env)?;
 
-        info!(
-            "{} - Downloading versions from reference url at: {}",
-            module_path!(),
-            url
-        );
+                info!(
+                    "{} - Downloading versions from reference url at: {}",
+                    module_path!(),
+                    url
+                );
 
-        let response = reqwest::Client::builder()
-            .build()
-            .context(HttpClient)?
-            .get(&url)
-            .timeout(Duration::from_secs(10))
-            .send()
-            .await
-            .context(HttpRequest { url: url.clone() })?;
+                let client = reference.client_builder().build().context(HttpClient)?;
 
-        debug!("{} - Waiting on body from reference", module_path!());
-        let body = response.text().await.context(HttpResponseBody)?;
+                if let Some(health_url) = reference.health_url_for(env)? {
+                    wait_for_health(&client, &health_url, reference, warnings).await?;
+                }
+
+                let response = fetch(&client, &url, reference).await?;
+
+                debug!("{} - Waiting on body from reference", module_path!());
+                response.text().await.context(HttpResponseBody)?
+            }
+        };
 
         debug!(
             "{} - Processing body of length {} from reference",
@@ -130,8 +342,8 @@ impl RunningServices {
             None => Ok(body),
         }?;
 
-        let svcs = serde_json::from_str::<Vec<RunningService>>(&filtered_body)
-            .context(ParseResponseBody)?;
+        let capture = reference.provenance.as_deref().unwrap_or_default();
+        let svcs = parse_running_services(reference.kind, &filtered_body, capture)?;
 
         info!(
             "{} - Extracted {} versions from reference: {:?}",
@@ -144,38 +356,421 @@ impl RunningServices {
     }
 }
 
+/// Polls the health-url until it responds with a 2xx status.  Should it not do so within the
+/// health-timeout the versions are requested anyway, which then fails or succeeds as usual.
+async fn wait_for_health(
+    client: &reqwest::Client,
+    url: &str,
+    reference: &Reference,
+    warnings: &Warnings,
+) -> Result<()> {
+    let headers = reference.headers()?;
+    let timeout = reference.health_timeout.unwrap_or(DEFAULT_HEALTH_TIMEOUT);
+
+    info!("{} - Waiting for the reference to be healthy at: {}", module_path!(), url);
+
+    let probe = || {
+        let request = headers
+            .iter()
+            .fold(client.get(url), |request, (name, value)| request.header(name, value));
+
+        async move { request.send().await.ok().map(|r| r.status()) }
+    };
+
+    if !poll_until_healthy(probe, timeout, HEALTH_POLL_INTERVAL).await {
+        let message = format!(
+            "The reference health url {} wasn't healthy within {:?}, fetching the versions anyway",
+            url, timeout
+        );
+        warnings.warn(Category::Reference, &message, None, None);
+    }
+
+    Ok(())
+}
+
+/// Calls the probe every interval until it gives a 2xx status, returning false if none did
+/// within the timeout.  A probe which couldn't connect gives None.
+async fn poll_until_healthy<F, Fut>(mut probe: F, timeout: Duration, interval: Duration) -> bool
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Option<reqwest::StatusCode>>,
+{
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    loop {
+        let status = probe().await;
+        debug!("{} - Reference health: {:?}", module_path!(), status);
+
+        if status.is_some_and(|s| s.is_success()) {
+            return true;
+        }
+
+        if tokio::time::Instant::now() + interval > deadline {
+            return false;
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Requests the reference, retrying with an exponential backoff on connection errors and server
+/// errors.  Any other unsuccessful status fails immediately.
+async fn fetch(client: &reqwest::Client, url: &str, reference: &Reference) -> Result<reqwest::Response> {
+    let headers = reference.headers()?;
+    let mut retry = 0;
+
+    loop {
+        let result = headers
+            .iter()
+            .fold(client.get(url), |request, (name, value)| request.header(name, value))
+            .send()
+            .await;
+
+        let retryable = match &result {
+            Ok(r) => r.status().is_server_error(),
+            Err(e) => e.is_connect() || e.is_timeout(),
+        };
+
+        if !retryable || retry >= reference.retries() {
+            let response = result.context(HttpRequest { url: url.to_string() })?;
+            return match response.status() {
+                s if s.is_success() => Ok(response),
+                status => Err(Error::HttpStatus {
+                    url: url.to_string(),
+                    status,
+                }),
+            };
+        }
+
+        let delay = reference.retry_delay(retry);
+        match &result {
+            Ok(r) => warn!("Reference responded with {}, retrying in {:?}", r.status(), delay),
+            Err(e) => warn!("Unable to request the reference, retrying in {:?}: {}", delay, e),
+        }
+
+        tokio::time::sleep(delay).await;
+        retry += 1;
+    }
+}
+
+/// Parses the reference response, keeping only the `capture` properties (which aren't null) of
+/// each entry as its provenance.
+fn parse_running_services(kind: ReferenceKind, body: &str, capture: &[String]) -> Result<Vec<RunningService>> {
+    match kind {
+        ReferenceKind::Versions => {
+            let mut svcs = serde_json::from_str::<Vec<RunningService>>(body).context(ParseResponseBody)?;
+            svcs.iter_mut()
+                .for_each(|s| s.extra.retain(|k, v| !v.is_null() && capture.contains(k)));
+            Ok(svcs)
+        }
+        ReferenceKind::Kubernetes => {
+            let images = serde_json::from_str::<Vec<DeploymentImage>>(body)
+                .context(ParseResponseBody)?;
+
+            Ok(images
+                .iter()
+                .filter_map(|d| {
+                    let image = ImageVersion::new(&d.image).ok();
+                    let version = image.as_ref().and_then(|i| i.get_version());
+
+                    if version.is_none() {
+                        debug!(
+                            "{} - Ignoring deployment image without a version: {}",
+                            module_path!(),
+                            &d.image
+                        );
+                    }
+
+                    Some(RunningService {
+                        name: image?.get_name(),
+                        version: version?,
+                        extra: BTreeMap::new(),
+                    })
+                })
+                .collect())
+        }
+    }
+}
+
+type JqFilter = jaq_core::Filter<jaq_core::Native<jaq_json::Val>>;
+
+/// Compiles a jq filter, along with the jq standard library, with the embedded jq engine.
+pub fn compile_filter(filter: &str) -> Result<JqFilter> {
+    let compile_error = |message: String| Error::JqCompile {
+        filter: filter.to_string(),
+        message,
+    };
+
+    let loader = Loader::new(jaq_std::defs().chain(jaq_json::defs()));
+    let arena = Arena::default();
+    let program = File { code: filter, path: () };
+
+    let modules = loader.load(&arena, program).map_err(|errors| {
+        compile_error(
+            errors
+                .iter()
+                .map(|(_, e)| format!("{:?}", e))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        )
+    })?;
+
+    Compiler::default()
+        .with_funs(jaq_std::funs().chain(jaq_json::funs()))
+        .compile(modules)
+        .map_err(|errors| {
+            compile_error(
+                errors
+                    .iter()
+                    .flat_map(|(_, undefined)| undefined.iter())
+                    .map(|(name, _)| format!("undefined: {}", name))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            )
+        })
+}
+
+/// Applies the jq filter to the JSON input, giving each output value as JSON on its own line as
+/// the jq tool would.
 async fn apply_filter(filter: &str, input: &str) -> Result<String> {
     debug!("jq - Input: {}", input);
     debug!("jq - Filter: {}", filter);
 
-    let mut jq = Command::new("jq")
-        .arg(filter)
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .spawn()
-        .context(JqExecute)?;
+    let compiled = compile_filter(filter)?;
+    let input = serde_json::from_str::<serde_json::Value>(input).context(ParseResponseBody)?;
 
-    let jq_stdin = jq.stdin.as_mut().ok_or(Error::JqStdinOpen)?;
+    let inputs = RcIter::new(core::iter::empty());
+    let outputs = compiled
+        .run((Ctx::new([], &inputs), Val::from(input)))
+        .map(|v| v.map(|v| v.to_string()))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| Error::JqRun {
+            message: e.to_string(),
+        })?;
 
-    jq_stdin
-        .write_all(input.as_bytes())
-        .await
-        .context(JqStdinHandle)?;
+    let out = outputs.join("\n");
 
-    debug!("Waiting reading output from jq process....");
-    let jq_result = jq.wait_with_output().await.context(JqAwait)?;
+    debug!("Returning processed reference versions: {:?}", &out);
 
-    let out = String::from_utf8(jq_result.stdout).context(JqStdoutRead)?;
-    let err = String::from_utf8(jq_result.stderr).context(JqStdoutRead)?;
-    if !err.is_empty() {
-        error!(
-            "{} failed to apply jq command to the given input - {}",
-            module_path!(),
-            err
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_timeouts() {
+        let reference: Reference = serde_yaml::from_str("url: http://localhost/versions.json").unwrap();
+        assert_eq!(
+            ClientTimeouts { connect: None, request: Duration::from_secs(10) },
+            reference.timeouts()
+        );
+
+        let reference: Reference = serde_yaml::from_str(
+            "url: http://localhost/versions.json\nconnect-timeout: 2s\nread-timeout: 1m",
+        )
+        .unwrap();
+        assert_eq!(
+            ClientTimeouts {
+                connect: Some(Duration::from_secs(2)),
+                request: Duration::from_secs(60),
+            },
+            reference.timeouts()
         );
+        assert!(reference.client_builder().build().is_ok());
+
+        assert!(serde_yaml::from_str::<Reference>(
+            "url: http://localhost/versions.json\nconnect-timeout: soon"
+        )
+        .is_err());
     }
 
-    debug!("Returning processed reference versions: {:?}", &out);
+    #[tokio::test]
+    async fn test_load_reference_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("versions-testing.json"),
+            r#"{"contentrepo": {"1.0.425": "ok"}}"#,
+        )
+        .unwrap();
+
+        for url in ["file://{dir}/versions-{env}.json", "{dir}/versions-{env}.json"] {
+            let reference: Reference = serde_yaml::from_str(&format!(
+                r#"
+url: {}
+env-mappings:
+  test: testing
+jq-filter: '[to_entries[] | {{ "name": .key, "version": ( .value | to_entries[0].key ) }}]'
+"#,
+                url.replace("{dir}", &dir.path().to_string_lossy())
+            ))
+            .unwrap();
+
+            let svcs = RunningServices::load("test", &Some(reference), &Warnings::default()).await.unwrap();
+            assert_eq!(1, svcs.len());
+            assert_eq!("1.0.425", svcs[0].version());
+        }
 
-    Ok(out)
+        let reference: Reference = serde_yaml::from_str("url: https://localhost/versions.json").unwrap();
+        assert_eq!(None, reference.file_for("test").unwrap());
+    }
+
+    #[test]
+    fn test_unset_url_variable() {
+        std::env::set_var("HANDEL_TEST_REFERENCE_HOST", "versions.example.com");
+
+        let reference: Reference = serde_yaml::from_str(
+            "url: https://${HANDEL_TEST_REFERENCE_HOST}/{env}.json\nhealth-url: https://${HANDEL_TEST_UNDEFINED_HOST}/health",
+        )
+        .unwrap();
+
+        assert_eq!("https://versions.example.com/test.json", reference.url_for("test").unwrap());
+        assert!(matches!(reference.health_url_for("test"), Err(Error::ExpandUrl { .. })));
+
+        let reference: Reference = serde_yaml::from_str("url: https://${HANDEL_TEST_UNDEFINED_HOST}/{env}.json").unwrap();
+        assert!(matches!(reference.url_for("test"), Err(Error::ExpandUrl { .. })));
+        assert!(matches!(reference.file_for("test"), Err(Error::ExpandUrl { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_poll_until_healthy() {
+        let poll = |statuses: Vec<Option<u16>>, timeout: Duration| async move {
+            let mut statuses = statuses.into_iter();
+            let mut polls = 0;
+            let probe = || {
+                polls += 1;
+                let status = statuses
+                    .next()
+                    .flatten()
+                    .map(|s| reqwest::StatusCode::from_u16(s).unwrap());
+                async move { status }
+            };
+
+            let healthy = poll_until_healthy(probe, timeout, Duration::from_millis(10)).await;
+            (healthy, polls)
+        };
+
+        let warming_up = vec![None, Some(503), Some(503), Some(200)];
+        assert_eq!((true, 4), poll(warming_up, Duration::from_secs(5)).await);
+
+        assert_eq!((true, 1), poll(vec![Some(204)], Duration::ZERO).await);
+
+        let unavailable = vec![Some(503); 100];
+        let (healthy, polls) = poll(unavailable, Duration::from_millis(45)).await;
+        assert!(!healthy);
+        assert!((2..=5).contains(&polls), "{} polls", polls);
+    }
+
+    #[test]
+    fn test_headers() {
+        std::env::set_var("HANDEL_TEST_REFERENCE_TOKEN", "s3cr3t");
+
+        let reference: Reference = serde_yaml::from_str(
+            r#"
+url: http://localhost/versions.json
+headers:
+  Authorization: Bearer ${HANDEL_TEST_REFERENCE_TOKEN}
+  X-Client: handel
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                ("Authorization".to_string(), "Bearer s3cr3t".to_string()),
+                ("X-Client".to_string(), "handel".to_string()),
+            ],
+            reference.headers().unwrap()
+        );
+
+        let reference: Reference = serde_yaml::from_str(
+            "url: http://localhost/versions.json\nheaders:\n  Authorization: Bearer ${HANDEL_TEST_UNDEFINED_TOKEN}",
+        )
+        .unwrap();
+        assert!(matches!(reference.headers(), Err(Error::HeaderValue { .. })));
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let reference: Reference = serde_yaml::from_str("url: http://localhost/versions.json").unwrap();
+        assert_eq!(3, reference.retries());
+        assert_eq!(Duration::from_secs(1), reference.retry_delay(0));
+        assert_eq!(Duration::from_secs(4), reference.retry_delay(2));
+
+        let reference: Reference = serde_yaml::from_str(
+            "url: http://localhost/versions.json\nretries: 0\nretry-delay: 5s",
+        )
+        .unwrap();
+        assert_eq!(0, reference.retries());
+        assert_eq!(Duration::from_secs(10), reference.retry_delay(1));
+        assert_eq!(Duration::MAX, reference.retry_delay(40));
+    }
+
+    #[test]
+    fn test_parse_versions() {
+        let body = r#"[{"name": "contentrepo", "version": "1.0.425"}]"#;
+        let svcs = parse_running_services(ReferenceKind::Versions, body, &[]).unwrap();
+
+        assert_eq!(1, svcs.len());
+        assert_eq!("contentrepo", svcs[0].name());
+        assert_eq!("1.0.425", svcs[0].version());
+    }
+
+    #[test]
+    fn test_parse_versions_provenance() {
+        let body = r#"[
+            {"name": "contentrepo", "version": "1.0.425", "commit": "9f2c1e4", "built": "2024-03-01T10:00:00Z", "build": 812, "owner": "search"},
+            {"name": "kafka", "version": "2.4.0", "commit": null}
+        ]"#;
+        let capture = ["commit".to_string(), "built".to_string(), "build".to_string()];
+        let svcs = parse_running_services(ReferenceKind::Versions, body, &capture).unwrap();
+
+        let provenance = svcs[0].provenance();
+        assert_eq!(3, provenance.len());
+        assert_eq!("9f2c1e4", provenance["commit"]);
+        assert_eq!("812", provenance["build"]);
+        assert!(svcs[1].provenance().is_empty());
+    }
+
+    #[test]
+    fn test_parse_kubernetes_deployments() {
+        let body = r#"[
+            {"name": "content-repo", "image": "12121212121.dkr.ecr.us-east-1.amazonaws.com/contentrepo:1.0.425"},
+            {"name": "kafka", "image": "wurstmeister/kafka:2.12-2.4.0"},
+            {"name": "untagged", "image": "mailhog/mailhog"}
+        ]"#;
+        let svcs = parse_running_services(ReferenceKind::Kubernetes, body, &[]).unwrap();
+
+        assert_eq!(2, svcs.len());
+        assert_eq!("contentrepo", svcs[0].name());
+        assert_eq!("1.0.425", svcs[0].version());
+        assert_eq!("kafka", svcs[1].name());
+        assert_eq!("2.12-2.4.0", svcs[1].version());
+    }
+
+    #[tokio::test]
+    async fn test_apply_filter() {
+        let body = r#"{"contentrepo": {"1.0.425": "ok"}, "search": {"2.1.0": "ok"}}"#;
+        let filter = r#". | [to_entries[] | { "name": .key, "version": ( .value | to_entries[0].key ) }] "#;
+
+        let filtered = apply_filter(filter, body).await.unwrap();
+        let svcs = parse_running_services(ReferenceKind::Versions, &filtered, &[]).unwrap();
+
+        assert_eq!(2, svcs.len());
+        assert_eq!("search", svcs[1].name());
+        assert_eq!("2.1.0", svcs[1].version());
+
+        assert!(matches!(apply_filter(".[", body).await, Err(Error::JqCompile { .. })));
+        assert!(matches!(apply_filter("undefined_fn(1)", body).await, Err(Error::JqCompile { .. })));
+        assert!(matches!(apply_filter(".a.b", "[1]").await, Err(Error::JqRun { .. })));
+    }
+
+    #[test]
+    fn test_reference_kind() {
+        let r: Reference = serde_yaml::from_str("url: http://localhost\nkind: kubernetes").unwrap();
+        assert_eq!(ReferenceKind::Kubernetes, r.kind);
+
+        let r: Reference = serde_yaml::from_str("url: http://localhost").unwrap();
+        assert_eq!(ReferenceKind::Versions, r.kind);
+    }
 }
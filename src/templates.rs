@@ -1,11 +1,14 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap};
 use std::cmp::Ordering;
+use std::fmt;
 use std::ops::RangeInclusive;
+use std::path::Path;
 use log::*;
 use regex::Regex;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde::de::{Error};
 use snafu::{ResultExt, Snafu};
+use crate::warnings::{Category, Warnings};
 
 #[derive(Debug, Snafu)]
 pub enum TemplateError {
@@ -48,6 +51,19 @@ pub enum TemplateError {
         source: serde_yaml::Error,
     },
 
+    #[snafu(display(r#"Unable to parse the image in fragment file: {}\n{}"#, file, source))]
+    TemplateImage {
+        file: String,
+        #[snafu(source(from(TemplateError, Box::new)))]
+        source: Box<TemplateError>,
+    },
+
+    #[snafu(display(r#"The service {} is defined by more than one template: {}"#, name, files.join(", ")))]
+    DuplicateService { name: String, files: Vec<String> },
+
+    #[snafu(display(r#"Template loading task failed.\n{}"#, source))]
+    TemplateTask { source: tokio::task::JoinError },
+
     #[snafu(display(r#"Unable to parse port mapping: {}"#, input))]
     PortMappingFormat {
         input: String,
@@ -65,8 +81,38 @@ pub struct ImageVersion {
 
 #[derive(Debug, Clone)]
 pub struct PortMapping {
+    /// The interface the port is published on, e.g. `127.0.0.1` or `[::1]`.
+    host_ip: Option<String>,
     source: Option<u16>,
-    target: u16
+    target: u16,
+    /// Either tcp or udp when given as a suffix, e.g. `53:53/udp`.
+    protocol: Option<String>,
+}
+
+/// A published host port and its protocol, conflicts only arising between services publishing
+/// the same port for the same protocol.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HostPort {
+    pub port: u16,
+    /// Either tcp, the default, or udp.
+    pub protocol: String,
+}
+
+impl fmt::Display for HostPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.protocol.as_str() {
+            "tcp" => write!(f, "{}", self.port),
+            _ => write!(f, "{}/{}", self.port, self.protocol),
+        }
+    }
+}
+
+/// A restart policy, one of "no", "always", "unless-stopped" or "on-failure" where the latter
+/// may carry a maximum retry count, e.g. "on-failure:3".
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestartPolicy {
+    policy: String,
+    max_retries: Option<u32>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -75,6 +121,121 @@ pub struct DeployOptions {
     mode: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     replicas: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    restart_policy: Option<DeployRestartPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    update_config: Option<DeployUpdateConfig>,
+}
+
+/// How swarm restarts the service's containers when they exit, durations being e.g. `5s`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DeployRestartPolicy {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    condition: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delay: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_attempts: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    window: Option<String>,
+}
+
+/// How swarm rolls out updates to the service.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DeployUpdateConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parallelism: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delay: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    order: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Logging {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub driver: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Healthcheck {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub test: Option<HealthcheckTest>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_period: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disable: Option<bool>,
+}
+
+/// A healthcheck test, either a shell command or a list such as `["CMD", "redis-cli", "ping"]`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum HealthcheckTest {
+    Command(String),
+    List(Vec<String>),
+}
+
+/// Reads a value which may be given as a single string or as a list of strings.
+fn de_string_or_list<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+
+    Ok(Some(match StringOrList::deserialize(deserializer)? {
+        StringOrList::String(s) => vec![s],
+        StringOrList::List(l) => l,
+    }))
+}
+
+/// Reads container labels given either as a map or as a list of `key=value` entries, where a
+/// key without a value is given an empty one.
+fn de_labels<'de, D>(deserializer: D) -> Result<Option<HashMap<String, String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Labels {
+        Map(HashMap<String, String>),
+        List(Vec<String>),
+    }
+
+    let labels = match Labels::deserialize(deserializer)? {
+        Labels::Map(m) => m,
+        Labels::List(l) => l
+            .iter()
+            .map(|entry| match entry.split_once('=') {
+                Some((k, v)) => (k.to_string(), v.to_string()),
+                None => (entry.to_string(), String::new()),
+            })
+            .collect(),
+    };
+
+    Ok(Some(labels))
+}
+
+impl PortMapping {
+    /// The host port published by the mapping, if any, along with its protocol.
+    pub fn host_port(&self) -> Option<HostPort> {
+        self.source.map(|port| HostPort {
+            port,
+            protocol: self.protocol.clone().unwrap_or_else(|| "tcp".to_string()),
+        })
+    }
 }
 
 impl<'de> Deserialize<'de> for PortMapping {
@@ -84,72 +245,259 @@ impl<'de> Deserialize<'de> for PortMapping {
     {
         let s = String::deserialize(deserializer)?;
 
-        let captures = Regex::new(r"(?P<a>\d{1,5})(?::(?P<b>\d{1,5}))?")
+        if Regex::new(r"\d-\d")
+            .expect("Internal error: invalid regular expression")
+            .is_match(&s)
+        {
+            return Err(D::Error::custom(format!("Port ranges are not supported: {}", s)));
+        }
+
+        let captures = Regex::new(r"^(?:(?P<ip>\d{1,3}(?:\.\d{1,3}){3}|\[[0-9a-fA-F:.]+\]):)?(?P<a>\d{1,5})(?::(?P<b>\d{1,5}))?(?:/(?P<protocol>(?i:tcp|udp|sctp)))?$")
             .map(|r| r.captures(&s))
             .expect("Internal error: invalid regular expression");
 
-        let captures = captures
-            .ok_or_else(|| D::Error::custom("Port mapping unexpected"))?;
+        let captures = captures.ok_or_else(|| {
+            D::Error::custom(format!(
+                "Invalid port mapping, expected [host-ip:][host-port:]container-port[/tcp|udp|sctp]: {}", s
+            ))
+        })?;
 
-        let port_a = captures.name("a")
-            .map(|m| m.as_str().parse::<u16>().unwrap_or(0))
-            .ok_or_else(|| D::Error::custom("No port "))?;
+        let parse_port = |m: regex::Match<'_>| {
+            m.as_str()
+                .parse::<u16>()
+                .map_err(|_| D::Error::custom(format!("Invalid port number in port mapping: {}", s)))
+        };
+
+        let port_a = parse_port(captures.name("a").expect("The container port is required by the pattern"))?;
+
+        let port_b = captures.name("b").map(parse_port).transpose()?;
 
-        let port_b = captures.name("b")
-            .map(|m| Some(m.as_str().parse::<u16>().unwrap_or(0)))
-            .unwrap_or(None);
+        // Compose accepts the protocol in any case, it is kept lower case for comparisons.
+        let protocol = captures.name("protocol").map(|m| m.as_str().to_lowercase());
+        let host_ip = captures.name("ip").map(|m| m.as_str().to_string());
 
         if let Some(pb) = port_b {
             return Ok(PortMapping {
+                host_ip,
                 source: Some(port_a),
                 target: pb,
+                protocol,
             });
         }
 
+        if host_ip.is_some() {
+            return Err(D::Error::custom(format!(
+                "A host IP requires both the host and container ports: {}", s
+            )));
+        }
+
         Ok(PortMapping {
+            host_ip: None,
             source: None,
             target: port_a,
+            protocol,
         })
     }
 }
 
-impl Serialize for PortMapping {
+impl<'de> Deserialize<'de> for RestartPolicy {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+
+        let (policy, retries) = match s.split_once(':') {
+            Some((p, r)) => (p, Some(r)),
+            None => (s.as_str(), None),
+        };
+
+        if !matches!(policy, "no" | "always" | "unless-stopped" | "on-failure") {
+            return Err(D::Error::custom(format!("Unknown restart policy: {}", s)));
+        }
+
+        let max_retries = match retries {
+            Some(_) if policy != "on-failure" => {
+                return Err(D::Error::custom(format!(
+                    "Only the on-failure restart policy accepts a retry count: {}", s
+                )));
+            }
+            Some(r) => Some(r.parse::<u32>().map_err(|_| {
+                D::Error::custom(format!("Invalid restart retry count: {}", s))
+            })?),
+            None => None,
+        };
+
+        Ok(RestartPolicy {
+            policy: policy.to_string(),
+            max_retries,
+        })
+    }
+}
+
+impl Serialize for RestartPolicy {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where S: Serializer,
     {
-        let s = if let Some(source_port) = self.source {
-            format!("{}:{}", source_port, self.target)
-        } else {
-            format!("{}", self.target)
+        let s = match self.max_retries {
+            Some(r) => format!("{}:{}", self.policy, r),
+            None => self.policy.clone(),
         };
         serializer.serialize_str(&s)
     }
 }
 
+impl RestartPolicy {
+    pub fn policy(&self) -> &str {
+        &self.policy
+    }
+
+    pub fn max_retries(&self) -> Option<u32> {
+        self.max_retries
+    }
+}
+
+impl fmt::Display for PortMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ip) = &self.host_ip {
+            write!(f, "{}:", ip)?;
+        }
+
+        match self.source {
+            Some(source_port) => write!(f, "{}:{}", source_port, self.target)?,
+            None => write!(f, "{}", self.target)?,
+        }
+
+        match &self.protocol {
+            Some(p) => write!(f, "/{}", p),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Serialize for PortMapping {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+/// A service's `depends_on`, either a list of service names or a map of each service to the
+/// condition it must meet before this service is started.  The form used by the template is kept.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum DependsOn {
+    List(Vec<String>),
+    Map(BTreeMap<String, DependencyCondition>),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DependencyCondition {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub condition: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restart: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub required: Option<bool>,
+}
+
+impl DependsOn {
+    pub fn names(&self) -> Vec<String> {
+        match self {
+            DependsOn::List(l) => l.clone(),
+            DependsOn::Map(m) => m.keys().cloned().collect(),
+        }
+    }
+
+    /// Adds a dependency upon the given service if it isn't already present, in the map form
+    /// waiting only for the service to have started.
+    pub fn add(&mut self, service: &str) {
+        match self {
+            DependsOn::List(l) => {
+                if !l.iter().any(|s| s == service) {
+                    l.push(service.to_string());
+                }
+            }
+            DependsOn::Map(m) => {
+                m.entry(service.to_string()).or_insert_with(|| DependencyCondition {
+                    condition: Some("service_started".to_string()),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    /// Removes any dependency upon the given services.
+    pub fn remove(&mut self, services: &[&str]) {
+        match self {
+            DependsOn::List(l) => l.retain(|s| !services.contains(&s.as_str())),
+            DependsOn::Map(m) => m.retain(|s, _| !services.contains(&s.as_str())),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            DependsOn::List(l) => l.is_empty(),
+            DependsOn::Map(m) => m.is_empty(),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ComposeServiceFragment {
     pub image: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub platform: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub restart: Option<String>,
+    pub restart: Option<RestartPolicy>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub depends_on: Option<Vec<String>>,
+    pub depends_on: Option<DependsOn>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub volumes: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub environment: Option<HashMap<String, String>>,
+    #[serde(default, deserialize_with = "de_string_or_list", skip_serializing_if = "Option::is_none")]
+    pub env_file: Option<Vec<String>>,
+    #[serde(rename = "env-groups", default, skip_serializing)]
+    pub env_groups: Option<Vec<String>>,
+    #[serde(rename = "default-version", default, skip_serializing)]
+    pub default_version: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ports: Option<Vec<PortMapping>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub deploy: Option<DeployOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logging: Option<Logging>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secrets: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub configs: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cap_add: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cap_drop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub security_opt: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub networks: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub healthcheck: Option<Healthcheck>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profiles: Option<Vec<String>>,
+    #[serde(default, deserialize_with = "de_labels", skip_serializing_if = "Option::is_none")]
+    pub labels: Option<HashMap<String, String>>,
+    #[serde(rename = "x-handel", default, skip_serializing_if = "Option::is_none")]
+    pub handel_labels: Option<HashMap<String, String>>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ComposeService {
     name: String,
     image: String,
     fragment: ComposeServiceFragment,
+    #[serde(skip)]
+    file: Option<String>,
 }
 
 impl Ord for ComposeService {
@@ -177,15 +525,93 @@ pub struct ComposeServiceMap {
     templates: HashMap<String, ComposeService>,
 }
 
+/// Values applied to every generated service which doesn't specify its own, e.g. a common
+/// restart policy.  Environment variables are merged with the service's taking precedence.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ServiceDefaults {
+    pub platform: Option<String>,
+    pub restart: Option<RestartPolicy>,
+    pub environment: Option<HashMap<String, String>>,
+    pub deploy: Option<DeployOptions>,
+    pub logging: Option<Logging>,
+    pub cap_add: Option<Vec<String>>,
+    pub cap_drop: Option<Vec<String>>,
+    pub security_opt: Option<Vec<String>>,
+}
+
+impl ServiceDefaults {
+    pub fn apply_to(&self, fragment: &mut ComposeServiceFragment) {
+        fn or_default<T: Clone>(value: &mut Option<T>, default: &Option<T>) {
+            if value.is_none() {
+                *value = default.clone();
+            }
+        }
+
+        or_default(&mut fragment.platform, &self.platform);
+        or_default(&mut fragment.restart, &self.restart);
+        or_default(&mut fragment.deploy, &self.deploy);
+        or_default(&mut fragment.logging, &self.logging);
+        or_default(&mut fragment.cap_add, &self.cap_add);
+        or_default(&mut fragment.cap_drop, &self.cap_drop);
+        or_default(&mut fragment.security_opt, &self.security_opt);
+
+        if let Some(defaults) = &self.environment {
+            let mut environment = defaults.clone();
+            environment.extend(fragment.environment.take().unwrap_or_default());
+            fragment.environment = Some(environment);
+        }
+    }
+}
+
+/// The Linux capability names accepted by cap_add and cap_drop, without the CAP_ prefix.
+const LINUX_CAPABILITIES: &[&str] = &[
+    "ALL", "AUDIT_CONTROL", "AUDIT_READ", "AUDIT_WRITE", "BLOCK_SUSPEND", "BPF",
+    "CHECKPOINT_RESTORE", "CHOWN", "DAC_OVERRIDE", "DAC_READ_SEARCH", "FOWNER", "FSETID",
+    "IPC_LOCK", "IPC_OWNER", "KILL", "LEASE", "LINUX_IMMUTABLE", "MAC_ADMIN", "MAC_OVERRIDE",
+    "MKNOD", "NET_ADMIN", "NET_BIND_SERVICE", "NET_BROADCAST", "NET_RAW", "PERFMON", "SETFCAP",
+    "SETGID", "SETPCAP", "SETUID", "SYSLOG", "SYS_ADMIN", "SYS_BOOT", "SYS_CHROOT", "SYS_MODULE",
+    "SYS_NICE", "SYS_PACCT", "SYS_PTRACE", "SYS_RAWIO", "SYS_RESOURCE", "SYS_TIME",
+    "SYS_TTY_CONFIG", "WAKE_ALARM",
+];
+
 impl ComposeServiceFragment {
     pub fn get_version(&self) -> Option<ImageVersion> {
         ImageVersion::new(&self.image).ok()
     }
 
-    pub fn get_image_name(&self) -> Option<String> {
-        ImageVersion::new(&self.image)
-            .map(ImageVersion::get_without_version)
-            .ok()
+    /// Whether the service is enabled by any of the active compose profiles, a service without
+    /// profiles always being enabled.
+    pub fn in_profiles(&self, active: &[&str]) -> bool {
+        match &self.profiles {
+            Some(profiles) if !profiles.is_empty() => profiles.iter().any(|p| active.contains(&p.as_str())),
+            _ => true,
+        }
+    }
+
+    /// Lists the named volumes mounted by the service, e.g. `pgdata` of `pgdata:/data`, as opposed
+    /// to host paths and anonymous volumes.
+    pub fn named_volumes(&self) -> Vec<&str> {
+        self.volumes
+            .iter()
+            .flatten()
+            .filter_map(|v| v.split_once(':').map(|(source, _)| source))
+            .filter(|s| !s.is_empty() && !s.starts_with(['.', '~', '$']) && !s.contains(['/', '\\']))
+            .collect()
+    }
+
+    /// Lists the cap_add and cap_drop entries which aren't known Linux capabilities.
+    pub fn unknown_capabilities(&self) -> Vec<&str> {
+        self.cap_add
+            .iter()
+            .chain(self.cap_drop.iter())
+            .flatten()
+            .filter(|c| {
+                let name = c.to_uppercase();
+                let name = name.strip_prefix("CAP_").unwrap_or(&name);
+                !LINUX_CAPABILITIES.contains(&name)
+            })
+            .map(|c| c.as_str())
+            .collect()
     }
 }
 
@@ -196,20 +622,32 @@ impl ComposeService {
         ComposeService {
             name: name.to_string(),
             image: image.to_string(),
-            fragment: frag.clone()
+            fragment: frag.clone(),
+            file: None,
         }
     }
 
     pub fn get_dependencies(self: &ComposeService) -> Vec<String> {
-        let mut dependencies = Vec::<String>::new();
+        self.fragment
+            .depends_on
+            .as_ref()
+            .map(DependsOn::names)
+            .unwrap_or_default()
+    }
+
+    /// A copy of the service without any dependency upon the given services, e.g. those excluded
+    /// from the run, which compose would otherwise reject as undefined.
+    pub fn without_dependencies(self: &ComposeService, services: &[&str]) -> ComposeService {
+        let mut service = self.clone();
 
-        if let Some(i) = &self.fragment.depends_on {
-            for s in i {
-                dependencies.push(s.to_string());
+        if let Some(depends_on) = service.fragment.depends_on.as_mut() {
+            depends_on.remove(services);
+            if depends_on.is_empty() {
+                service.fragment.depends_on = None;
             }
-        };
+        }
 
-        dependencies
+        service
     }
 
     pub fn name(self: &ComposeService) -> String {
@@ -224,6 +662,20 @@ impl ComposeService {
         &self.fragment
     }
 
+    pub fn file(self: &ComposeService) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    /// Whether the template is marked `disabled: true` under `x-handel`, i.e. is kept but never
+    /// included.
+    pub fn is_disabled(self: &ComposeService) -> bool {
+        self.fragment
+            .handel_labels
+            .as_ref()
+            .and_then(|l| l.get("disabled"))
+            .is_some_and(|v| v == "true")
+    }
+
     pub fn fragment_using_version(
         self: &ComposeService,
         version: Option<String>,
@@ -255,125 +707,333 @@ impl ComposeService {
 }
 
 impl ComposeServiceMap {
-    pub async fn new(templates_dir: &str, port_range: Option<(u16,u16)>) -> Result<ComposeServiceMap> {
+    pub async fn new(
+        templates_dir: &str,
+        port_range: Option<(u16,u16)>,
+        warnings: &Warnings,
+    ) -> Result<ComposeServiceMap> {
+
+        let mut templates: HashMap<String, ComposeService> = HashMap::new();
+        let mut files = Vec::new();
+
+        collect_template_files(Path::new(templates_dir), &mut files)?;
+
+        // Read and parse the templates in parallel, but merge them in path order so that the
+        // resulting map does not depend on which task completes first.
+        files.sort_by(|a, b| a.1.cmp(&b.1));
+
+        let tasks = files
+            .into_iter()
+            .map(|(stem, path)| {
+                let warnings = warnings.clone();
+                tokio::task::spawn_blocking(move || load_template(&stem, &path, &warnings))
+            })
+            .collect::<Vec<_>>();
+
+        for t in tasks {
+            let service = t.await.context(TemplateTask)??;
+
+            if let Some(previous) = templates.get(&service.name()) {
+                return Err(TemplateError::DuplicateService {
+                    name: service.name(),
+                    files: [previous.file(), service.file()]
+                        .iter()
+                        .map(|f| f.unwrap_or_default().to_string())
+                        .collect(),
+                });
+            }
 
-        let mut templates = HashMap::new();
-        let mut target_ports: HashMap<u16,Vec<String>> = HashMap::new();
-        let mut assigned_ports = HashSet::<u16>::new();
+            templates.insert(service.name(), service);
+        }
 
-        let entries = std::fs::read_dir(templates_dir).context(TemplateDirectoryNotReadable {
-            dir: templates_dir.to_string(),
-        })?;
+        let target_ports = host_ports(templates.values());
+        let conflicts = port_conflicts(&target_ports);
 
-        for e in entries {
-            let entry = e.context(DirEntryNotReadable {
-                dir: templates_dir.to_string(),
-            })?;
+        if !conflicts.is_empty() {
+            // Each conflict is reported against the template of the first service by name.
+            for (port, names) in &conflicts {
+                let message = format!("Host port {} conflicts between: {}", port, names.join(", "));
+                let file = names.first().and_then(|n| templates.get(n)).and_then(|s| s.file());
+                warnings.warn(Category::PortConflict, &message, file, None);
+            }
 
-            let m = entry.metadata().context(MetadataNotReadable {
-                dir: templates_dir.to_string(),
-            })?;
+            if let Some(r) = port_range {
+                let free_ports = RangeInclusive::<u16>::new(r.0, r.1)
+                    .filter(|p| !target_ports.keys().any(|k| k.port == *p))
+                    .take(conflicts.len())
+                    .map(|p|format!("\t{}", p))
+                    .collect::<Vec<_>>();
 
-            if m.is_dir() {
-                continue;
+                let message = format!("The following host ports are free in the port-range:\n{}",
+                          free_ports.join("\n"));
+                warnings.warn(Category::PortConflict, &message, None, None);
             }
 
-            let file_name = entry.file_name();
-            let file_name = file_name.as_os_str().to_str();
-            if file_name.is_none() {
-                error!(
-                    "{} - Unprocessable template file name: {:?}",
-                    module_path!(),
-                    entry.file_name()
-                );
-                continue;
-            }
+        }
+
+        Ok(ComposeServiceMap { templates })
+    }
 
-            let b = entry.path();
-            let path = b.as_path();
-            let stem = b.file_stem().and_then(|s| s.to_str()).unwrap_or("");
-            let ext = b.extension().and_then(|s| s.to_str()).unwrap_or("");
+    #[cfg(test)]
+    pub fn from_services(services: Vec<ComposeService>) -> ComposeServiceMap {
+        ComposeServiceMap {
+            templates: services.into_iter().map(|s| (s.name(), s)).collect(),
+        }
+    }
 
-            if !ext.eq("yml") && !ext.eq("yaml") {
-                debug!(
-                    "{} - Ignoring invalid file: {}",
-                    module_path!(),
-                    file_name.unwrap()
-                );
-                continue;
-            }
+    /// Lists the `depends_on` entries of the given services which do not match any template,
+    /// along with the dependent service.
+    pub fn missing_dependencies<'a>(
+        self: &ComposeServiceMap,
+        services: &[&'a ComposeService],
+    ) -> Vec<(&'a ComposeService, String)> {
+        let mut missing = services
+            .iter()
+            .flat_map(|s| {
+                s.get_dependencies()
+                    .into_iter()
+                    .filter(|d| self.get_service_fragment(d).is_none())
+                    .map(move |d| (*s, d))
+            })
+            .collect::<Vec<_>>();
+
+        missing.sort();
+        missing
+    }
 
-            let file_name = file_name.unwrap();
+    /// Warns about each `depends_on` entry of the given services which doesn't match a template.
+    pub fn warn_missing_dependencies(
+        self: &ComposeServiceMap,
+        services: &[&ComposeService],
+        warnings: &Warnings,
+    ) {
+        for (svc, dependency) in self.missing_dependencies(services) {
+            let message = format!("{} depends on unknown service {}", svc.name(), dependency);
+            warnings.warn(Category::MissingDependency, &message, svc.file(), None);
+        }
+    }
 
-            let contents = crate::utils::read_file_contents(path).context(ReadTemplate {
-                file: file_name.to_string(),
-            })?;
+    /// Lists, sorted by name, the templates which aren't among the given services.
+    pub fn unused_templates(self: &ComposeServiceMap, services: &[&ComposeService]) -> Vec<String> {
+        let mut unused = self
+            .templates
+            .keys()
+            .filter(|name| !services.iter().any(|s| &s.name == *name))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        unused.sort();
+        unused
+    }
+
+    /// Finds the template of the given service, disabled templates being treated as absent.
+    pub fn get_service_fragment(
+        self: &ComposeServiceMap,
+        service: &str,
+    ) -> Option<&ComposeService> {
+        self.templates.get(service).filter(|s| !s.is_disabled())
+    }
 
-            let service_fragment: ComposeServiceFragment = serde_yaml::from_str(&contents)
-                .context(ParseTemplate {
-                    file: file_name.to_string(),
-                })?;
+    pub fn is_disabled(self: &ComposeServiceMap, service: &str) -> bool {
+        self.templates.get(service).is_some_and(|s| s.is_disabled())
+    }
 
-            let service = ComposeService {
-                name: stem.to_string(),
-                image: service_fragment.get_image_name().unwrap(),
-                fragment: service_fragment,
-            };
+    /// Lists, by name, the templates whose `x-handel` labels include the given key and value.
+    pub fn with_label(self: &ComposeServiceMap, key: &str, value: &str) -> Vec<&ComposeService> {
+        let mut matching = self
+            .templates
+            .values()
+            .filter(|s| !s.is_disabled())
+            .filter(|s| {
+                s.fragment()
+                    .handel_labels
+                    .as_ref()
+                    .and_then(|l| l.get(key))
+                    .is_some_and(|v| v == value)
+            })
+            .collect::<Vec<_>>();
+
+        matching.sort_by_key(|s| s.name());
+
+        matching
+    }
+}
+
+fn load_template(stem: &str, path: &Path, warnings: &Warnings) -> Result<ComposeService> {
+    let file_name = path
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .unwrap_or_default();
 
-            if let Some(p) = service.fragment.ports.as_ref() {
-                p.iter()
-                    .for_each(|pm| {
-                        if let Some(pm_source) = pm.source {
-                            assigned_ports.insert(pm_source);
-                            target_ports.entry(pm_source)
-                                .or_insert_with(Vec::new)
-                                .push(service.name.clone());
-                        }
-                    });
+    let contents = crate::utils::read_file_contents(path).context(ReadTemplate {
+        file: file_name.to_string(),
+    })?;
+
+    let service_fragment: ComposeServiceFragment = serde_yaml::from_str(&contents)
+        .context(ParseTemplate {
+            file: file_name.to_string(),
+        })?;
+
+    let image = ImageVersion::new(&service_fragment.image)
+        .map(ImageVersion::get_without_version)
+        .context(TemplateImage {
+            file: file_name.to_string(),
+        })?;
+
+    for c in service_fragment.unknown_capabilities() {
+        let message = format!("Template {} adds or drops an unknown capability: {}", &file_name, c);
+        warnings.warn(Category::Template, &message, Some(&path.to_string_lossy()), None);
+    }
+
+    Ok(ComposeService {
+        name: stem.to_string(),
+        image,
+        fragment: service_fragment,
+        file: Some(path.to_string_lossy().to_string()),
+    })
+}
+
+/// Maps each published host port and protocol to the (sorted, distinct) names of the services
+/// which publish it.
+pub fn host_ports<'a>(svcs: impl Iterator<Item = &'a ComposeService>) -> BTreeMap<HostPort, Vec<String>> {
+    let mut target_ports: BTreeMap<HostPort, Vec<String>> = BTreeMap::new();
+
+    for service in svcs {
+        if let Some(p) = service.fragment.ports.as_ref() {
+            p.iter()
+                .filter_map(|pm| pm.host_port())
+                .for_each(|host_port| {
+                    target_ports.entry(host_port)
+                        .or_default()
+                        .push(service.name.clone());
+                });
+        };
+    }
+
+    target_ports.values_mut().for_each(|v| {
+        v.sort();
+        v.dedup();
+    });
+
+    target_ports
+}
+
+/// Copies the given services, e.g. those resolved for a scenario, moving every service but the
+/// first (by name) publishing each conflicting host port onto the next free port of the range.
+/// The service, original and new port of each change is returned along with the copies.
+/// Conflicts which remain once the range is exhausted are left as they are.
+pub fn reassign_ports(
+    services: &[&ComposeService],
+    port_range: (u16, u16),
+) -> (Vec<ComposeService>, Vec<(String, HostPort, u16)>) {
+    let mut services = services.iter().map(|s| (*s).clone()).collect::<Vec<_>>();
+
+    let target_ports = host_ports(services.iter());
+    let conflicts = port_conflicts(&target_ports);
+
+    let mut free_ports = RangeInclusive::<u16>::new(port_range.0, port_range.1)
+        .filter(|p| !target_ports.keys().any(|k| k.port == *p));
+    let mut reassigned = Vec::new();
+
+    for (host_port, names) in &conflicts {
+        for name in names.iter().skip(1) {
+            let new_port = match free_ports.next() {
+                Some(p) => p,
+                None => return (services, reassigned),
             };
 
-            templates.insert(stem.to_string(), service);
+            if let Some(service) = services.iter_mut().find(|s| &s.name == name) {
+                service.fragment.ports.iter_mut()
+                    .flatten()
+                    .filter(|pm| pm.host_port().as_ref() == Some(host_port))
+                    .for_each(|pm| pm.source = Some(new_port));
+            }
+
+            reassigned.push((name.clone(), host_port.clone(), new_port));
         }
+    }
 
-        if let true = target_ports.values().any(|s|s.len()>1) {
-            let conflicting_ports = target_ports.iter()
-                .filter(|(_,v)|v.len()>1)
-                .map(|(k,v)|{
-                    let conflicts = v.join(", ");
-                    format!("\t{}\t{}", k, conflicts)
-                })
-                .collect::<Vec<_>>();
+    (services, reassigned)
+}
 
-            eprintln!("Warning: The following host port conflicts exist:\n\tPort\tConflicting\n{}\n",
-                      conflicting_ports.join("\n") );
+/// Adds the `.yml` and `.yaml` files of the directory and, recursively, of its subdirectories
+/// to `files` along with their stems.  Hidden directories, e.g. `.git`, are skipped.
+fn collect_template_files(dir: &Path, files: &mut Vec<(String, std::path::PathBuf)>) -> Result<()> {
+    let display = dir.display().to_string();
 
-            if let Some(r) = port_range {
-                let free_ports = RangeInclusive::<u16>::new(r.0, r.1)
-                    .filter(|p| !assigned_ports.contains(p) )
-                    .take(conflicting_ports.len())
-                    .map(|p|format!("\t{}", p))
-                    .collect::<Vec<_>>();
+    let entries = std::fs::read_dir(dir).context(TemplateDirectoryNotReadable { dir: display.clone() })?;
+
+    for e in entries {
+        let entry = e.context(DirEntryNotReadable { dir: display.clone() })?;
+
+        let m = entry.metadata().context(MetadataNotReadable { dir: display.clone() })?;
 
-                eprintln!("The following host ports are free in the port-range:\n{}\n",
-                          free_ports.join("\n") );
+        let file_name = entry.file_name();
+        let file_name = match file_name.as_os_str().to_str() {
+            Some(f) => f,
+            None => {
+                error!(
+                    "{} - Unprocessable template file name: {:?}",
+                    module_path!(),
+                    entry.file_name()
+                );
+                continue;
             }
+        };
 
+        if m.is_dir() {
+            if !file_name.starts_with('.') {
+                collect_template_files(&entry.path(), files)?;
+            }
+            continue;
         }
 
-        Ok(ComposeServiceMap { templates })
-    }
+        let b = entry.path();
+        let stem = b.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+        let ext = b.extension().and_then(|s| s.to_str()).unwrap_or("");
+
+        if !ext.eq("yml") && !ext.eq("yaml") {
+            debug!(
+                "{} - Ignoring invalid file: {}",
+                module_path!(),
+                file_name
+            );
+            continue;
+        }
 
-    pub fn get_service_fragment(
-        self: &ComposeServiceMap,
-        service: &str,
-    ) -> Option<&ComposeService> {
-        self.templates.get(service)
+        files.push((stem.to_string(), b));
     }
+
+    Ok(())
+}
+
+/// Lists the names of the given services which none of the active profiles enable.
+pub fn outside_profiles(services: &[&ComposeService], active: &[&str]) -> Vec<String> {
+    services
+        .iter()
+        .filter(|s| !s.fragment().in_profiles(active))
+        .map(|s| s.name())
+        .collect()
+}
+
+/// Returns only those host ports which are published by more than one service.
+pub fn port_conflicts(target_ports: &BTreeMap<HostPort, Vec<String>>) -> BTreeMap<HostPort, Vec<String>> {
+    target_ports.iter()
+        .filter(|(_, v)| v.len() > 1)
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+/// Whether an image version is a content digest, e.g. `sha256:...`, rather than a tag.
+pub fn is_digest(version: &str) -> bool {
+    version.starts_with("sha256:")
 }
 
 impl ImageVersion {
+    /// Parses an image, e.g. `example/api:1.0`.  An image pinned by digest, e.g.
+    /// `example/api@sha256:...`, has the digest as its version.
     pub fn new(image_str: &str) -> Result<ImageVersion> {
-        let re = Regex::new(r"(?:(?P<repo>[^/]+)/)?(?P<svc>[^:]+)(?::(?P<version>.+))?")
+        let re = Regex::new(r"^(?:(?P<repo>[^/]+)/)?(?P<svc>[^:@]+)(?:[:@](?P<version>.+))?$")
             .expect("Regex not valid");
 
         let result = match re.captures(image_str) {
@@ -381,6 +1041,7 @@ impl ImageVersion {
                 let name =
                     c.name("svc")
                         .map(|m| m.as_str().to_string())
+                        .filter(|n| !n.starts_with('/') && !n.ends_with('/'))
                         .ok_or(TemplateError::ServiceName {
                             input: image_str.to_string(),
                         })?;
@@ -408,6 +1069,7 @@ impl ImageVersion {
             },
             &self.name,
             match &self.version {
+                Some(v) if is_digest(v) => format!("@{}", &v),
                 Some(v) => format!(":{}", &v),
                 None => "".to_string(),
             }
@@ -430,6 +1092,17 @@ impl ImageVersion {
     }
 
     pub fn get_version(&self) -> Option<String> { self.version.clone() }
+
+    pub fn get_repository(&self) -> Option<String> {
+        self.repository.clone()
+    }
+
+    pub fn with_repository(self, repository: Option<String>) -> ImageVersion {
+        ImageVersion {
+            repository,
+            ..self
+        }
+    }
 }
 
 #[cfg(test)]
@@ -481,6 +1154,324 @@ mod tests {
         assert_eq!("1.6.7", i.version.unwrap());
     }
 
+    #[test]
+    fn test_digest_version() {
+        let i = ImageVersion::new("example/api@sha256:4f3c2b1a").unwrap();
+        assert_eq!("api", i.name);
+        assert_eq!(Some("example".to_string()), i.repository);
+        assert_eq!("sha256:4f3c2b1a", i.version.as_deref().unwrap());
+        assert_eq!("example/api@sha256:4f3c2b1a", i.get());
+        assert_eq!("example/api", i.get_without_version());
+
+        let tagged = ImageVersion {
+            version: Some("sha256:9e8d7c6b".to_string()),
+            ..ImageVersion::new("example/api:1.0").unwrap()
+        };
+        assert_eq!("example/api@sha256:9e8d7c6b", tagged.get());
+    }
+
+    #[test]
+    fn test_security_options_round_trip() {
+        let t = r#"image: example/api:1.0
+cap_add:
+- NET_ADMIN
+- SYS_PTRACE
+cap_drop:
+- ALL
+security_opt:
+- no-new-privileges:true
+- seccomp:unconfined
+"#;
+        let frag: ComposeServiceFragment = serde_yaml::from_str(t).unwrap();
+
+        assert_eq!(Some(vec!["NET_ADMIN".to_string(), "SYS_PTRACE".to_string()]), frag.cap_add);
+        assert_eq!(Some(vec!["ALL".to_string()]), frag.cap_drop);
+        assert_eq!(2, frag.security_opt.as_ref().unwrap().len());
+        assert!(frag.unknown_capabilities().is_empty());
+
+        assert_eq!(t, serde_yaml::to_string(&frag).unwrap());
+    }
+
+    #[test]
+    fn test_profiles() {
+        let t = "image: example/debug:1.0\nprofiles:\n- debug\n- tools\n";
+        let debug: ComposeServiceFragment = serde_yaml::from_str(t).unwrap();
+        assert_eq!(t, serde_yaml::to_string(&debug).unwrap());
+
+        let api: ComposeServiceFragment = serde_yaml::from_str("image: example/api:1.0").unwrap();
+        let svcs = [
+            &ComposeService::new("api", "example/api", &api),
+            &ComposeService::new("debug", "example/debug", &debug),
+        ];
+
+        assert_eq!(vec!["debug"], outside_profiles(&svcs, &["ci"]));
+        assert!(outside_profiles(&svcs, &["ci", "tools"]).is_empty());
+        assert!(api.in_profiles(&[]));
+        assert!(!debug.in_profiles(&[]));
+    }
+
+    #[test]
+    fn test_unknown_capabilities() {
+        let t = "image: example/api:1.0\ncap_add:\n  - CAP_NET_RAW\n  - net_admin\n  - NET_MAGIC\ncap_drop:\n  - CHWON";
+        let frag: ComposeServiceFragment = serde_yaml::from_str(t).unwrap();
+
+        assert_eq!(vec!["NET_MAGIC", "CHWON"], frag.unknown_capabilities());
+    }
+
+    #[test]
+    fn test_logging_json_file() {
+        let t = r#"
+image: example/api:1.0
+logging:
+  driver: json-file
+  options:
+    max-size: 10m
+    max-file: "3"
+"#;
+        let frag: ComposeServiceFragment = serde_yaml::from_str(t).unwrap();
+        let logging = frag.logging.as_ref().unwrap();
+
+        assert_eq!(Some("json-file".to_string()), logging.driver);
+        let options = logging.options.as_ref().unwrap();
+        assert_eq!("10m", options["max-size"]);
+        assert_eq!("3", options["max-file"]);
+
+        let output: serde_yaml::Value = serde_yaml::to_value(&frag).unwrap();
+        assert_eq!("json-file", output["logging"]["driver"].as_str().unwrap());
+        assert_eq!("10m", output["logging"]["options"]["max-size"].as_str().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_warnings_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("orders.yml"),
+            "image: example/orders:1.0\nports:\n  - 47123:80\ndepends_on:\n  - ledger\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("billing.yml"), "image: example/billing:1.0\nports:\n  - 47123:81\n").unwrap();
+
+        let warnings = Warnings::default();
+        let map = ComposeServiceMap::new(dir.path().to_str().unwrap(), None, &warnings).await.unwrap();
+        let orders = map.get_service_fragment("orders").unwrap();
+        map.warn_missing_dependencies(&[orders], &warnings);
+
+        let file = dir.path().join("warnings.json");
+        warnings.write_file(&file).unwrap();
+
+        let warnings: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap();
+        let find = |category: &str, text: &str| {
+            warnings.as_array().unwrap().iter().find(|w| {
+                w["category"] == category && w["message"].as_str().unwrap().contains(text)
+            }).cloned()
+        };
+
+        let conflict = find("port-conflict", "Host port 47123 conflicts between: billing, orders").unwrap();
+        assert!(conflict["context"]["file"].as_str().unwrap().ends_with(".yml"));
+
+        let dependency = find("missing-dependency", "orders depends on unknown service ledger").unwrap();
+        assert!(dependency["context"]["file"].as_str().unwrap().ends_with("orders.yml"));
+    }
+
+    #[tokio::test]
+    async fn test_nested_template_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("content/search")).unwrap();
+        std::fs::create_dir_all(dir.path().join("data")).unwrap();
+        std::fs::create_dir_all(dir.path().join(".git")).unwrap();
+
+        std::fs::write(dir.path().join("api.yml"), "image: example/api:1.0\n").unwrap();
+        std::fs::write(dir.path().join("content/repo.yml"), "image: example/repo:2.0\n").unwrap();
+        std::fs::write(dir.path().join("content/search/query.yaml"), "image: example/query:3.0\n").unwrap();
+        std::fs::write(dir.path().join("content/search/notes.txt"), "not a template").unwrap();
+        std::fs::write(dir.path().join("data/db.yml"), "image: mysql:5.7\n").unwrap();
+        std::fs::write(dir.path().join(".git/config.yml"), "image: example/git:1.0\n").unwrap();
+
+        let map = ComposeServiceMap::new(dir.path().to_str().unwrap(), None, &Warnings::default()).await.unwrap();
+
+        let mut names = map.templates.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(vec!["api", "db", "query", "repo"], names);
+        assert_eq!("mysql:5.7", map.get_service_fragment("db").unwrap().fragment().image);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_service_names() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("data")).unwrap();
+        std::fs::write(dir.path().join("api.yml"), "image: example/api:1.0\n").unwrap();
+        std::fs::write(dir.path().join("api.yaml"), "image: example/api:2.0\n").unwrap();
+
+        let err = ComposeServiceMap::new(dir.path().to_str().unwrap(), None, &Warnings::default()).await.unwrap_err();
+        match &err {
+            TemplateError::DuplicateService { name, files } => {
+                assert_eq!("api", name);
+                assert_eq!(2, files.len());
+                assert!(files[0].ends_with("api.yaml") && files[1].ends_with("api.yml"), "{:?}", files);
+            }
+            e => panic!("Unexpected error: {}", e),
+        }
+
+        std::fs::remove_file(dir.path().join("api.yaml")).unwrap();
+        std::fs::write(dir.path().join("data/api.yml"), "image: example/api:2.0\n").unwrap();
+
+        let err = ComposeServiceMap::new(dir.path().to_str().unwrap(), None, &Warnings::default()).await.unwrap_err();
+        assert!(matches!(err, TemplateError::DuplicateService { .. }), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_parallel_loading_matches_sequential() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for i in 0..200 {
+            let contents = format!("image: example/svc-{}:1.0.{}\nports:\n  - {}:80\n", i, i, 9000 + i % 7);
+            std::fs::write(dir.path().join(format!("svc-{:03}.yml", i)), contents).unwrap();
+        }
+        std::fs::write(dir.path().join("ReadMe.md"), "not a template").unwrap();
+
+        let map = ComposeServiceMap::new(dir.path().to_str().unwrap(), None, &Warnings::default()).await.unwrap();
+
+        let mut paths = std::fs::read_dir(dir.path()).unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|e| e == "yml"))
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        assert_eq!(200, map.templates.len());
+
+        for p in &paths {
+            let stem = p.file_stem().unwrap().to_str().unwrap();
+            let expected = load_template(stem, p, &Warnings::default()).unwrap();
+            let actual = map.get_service_fragment(stem).unwrap();
+            assert_eq!(expected.image(), actual.image());
+            assert_eq!(
+                serde_yaml::to_string(expected.fragment()).unwrap(),
+                serde_yaml::to_string(actual.fragment()).unwrap()
+            );
+        }
+
+        let sequential = paths.iter()
+            .map(|p| load_template(p.file_stem().unwrap().to_str().unwrap(), p, &Warnings::default()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(host_ports(sequential.iter()), host_ports(map.templates.values()));
+    }
+
+    #[test]
+    fn test_deploy_restart_policy_and_update_config() {
+        let t = r#"
+image: example/api:1.0
+deploy:
+  mode: replicated
+  replicas: 2
+  restart_policy:
+    condition: on-failure
+    delay: 5s
+    max_attempts: 3
+    window: 120s
+  update_config:
+    parallelism: 1
+    delay: 10s
+    order: start-first
+"#;
+        let frag: ComposeServiceFragment = serde_yaml::from_str(t).unwrap();
+        let deploy = frag.deploy.as_ref().unwrap();
+
+        let restart = deploy.restart_policy.as_ref().unwrap();
+        assert_eq!(Some("on-failure"), restart.condition.as_deref());
+        assert_eq!(Some(3), restart.max_attempts);
+        assert_eq!(Some("120s"), restart.window.as_deref());
+
+        let update = deploy.update_config.as_ref().unwrap();
+        assert_eq!(Some(1), update.parallelism);
+        assert_eq!(Some("start-first"), update.order.as_deref());
+
+        let yaml = serde_yaml::to_string(deploy).unwrap();
+        let round_trip: serde_yaml::Value = serde_yaml::from_str(&yaml).unwrap();
+        let original: serde_yaml::Value = serde_yaml::from_str(t).unwrap();
+        assert_eq!(original["deploy"], round_trip);
+    }
+
+    #[test]
+    fn test_deploy_without_sub_sections() {
+        let frag: ComposeServiceFragment =
+            serde_yaml::from_str("image: example/api:1.0\ndeploy:\n  replicas: 1\n").unwrap();
+
+        let yaml = serde_yaml::to_string(frag.deploy.as_ref().unwrap()).unwrap();
+        assert_eq!("replicas: 1\n", yaml);
+    }
+
+    #[test]
+    fn test_depends_on_forms() {
+        let list: ComposeServiceFragment =
+            serde_yaml::from_str("image: example/api:1.0\ndepends_on:\n  - db\n  - cache").unwrap();
+        let api = ComposeService::new("api", "example/api", &list);
+        assert_eq!(vec!["db", "cache"], api.get_dependencies());
+        assert!(matches!(list.depends_on, Some(DependsOn::List(_))));
+
+        let t = r#"image: example/api:1.0
+depends_on:
+  db:
+    condition: service_healthy
+  cache:
+    condition: service_started
+    restart: true
+"#;
+        let map: ComposeServiceFragment = serde_yaml::from_str(t).unwrap();
+        let api = ComposeService::new("api", "example/api", &map);
+        assert_eq!(vec!["cache", "db"], api.get_dependencies());
+
+        let yaml = serde_yaml::to_string(&map).unwrap();
+        let round_trip: serde_yaml::Value = serde_yaml::from_str(&yaml).unwrap();
+        let original: serde_yaml::Value = serde_yaml::from_str(t).unwrap();
+        assert_eq!(original["depends_on"], round_trip["depends_on"]);
+    }
+
+    #[test]
+    fn test_missing_dependencies() {
+        let api: ComposeServiceFragment =
+            serde_yaml::from_str("image: example/api:1.0\ndepends_on:\n  - db\n  - cache").unwrap();
+        let db: ComposeServiceFragment = serde_yaml::from_str("image: mysql:5.7").unwrap();
+
+        let templates = ComposeServiceMap::from_services(vec![
+            ComposeService::new("api", "example/api", &api),
+            ComposeService::new("db", "mysql", &db),
+        ]);
+
+        let services = [
+            templates.get_service_fragment("api").unwrap(),
+            templates.get_service_fragment("db").unwrap(),
+        ];
+
+        let missing = templates.missing_dependencies(&services);
+        assert_eq!(1, missing.len());
+        assert_eq!("api", missing[0].0.name());
+        assert_eq!("cache", missing[0].1);
+    }
+
+    #[test]
+    fn test_empty_image() {
+        assert!(matches!(
+            ImageVersion::new(""),
+            Err(TemplateError::RepositoryFormat { .. })
+        ));
+    }
+
+    #[test]
+    fn test_image_with_only_version() {
+        assert!(matches!(
+            ImageVersion::new(":latest"),
+            Err(TemplateError::RepositoryFormat { .. })
+        ));
+    }
+
+    #[test]
+    fn test_image_with_only_separator() {
+        assert!(matches!(
+            ImageVersion::new("/"),
+            Err(TemplateError::ServiceName { .. })
+        ));
+    }
+
     #[test]
     fn test_fragment_deserialisation() {
         let t = r#"
@@ -525,4 +1516,222 @@ ports:
         assert!(frag.platform.is_some());
         assert_eq!("amd64", frag.platform.unwrap());
     }
+
+    #[test]
+    fn test_port_protocols() {
+        let t = r#"
+image: dns
+ports:
+    - 53:53/udp
+    - 8080/tcp
+    - 9090:90
+"#;
+        let frag: ComposeServiceFragment = serde_yaml::from_str(t).unwrap();
+        let ports = frag.ports.as_ref().unwrap();
+
+        assert_eq!(Some(53), ports[0].source);
+        assert_eq!(53, ports[0].target);
+        assert_eq!(Some("udp"), ports[0].protocol.as_deref());
+
+        assert_eq!(None, ports[1].source);
+        assert_eq!(8080, ports[1].target);
+        assert_eq!(Some("tcp"), ports[1].protocol.as_deref());
+
+        assert_eq!(None, ports[2].protocol);
+
+        let yaml = serde_yaml::to_string(ports).unwrap();
+        assert_eq!("- 53:53/udp\n- 8080/tcp\n- 9090:90\n", yaml);
+    }
+
+    #[test]
+    fn test_port_protocol_case_and_sctp() {
+        let t = r#"
+image: dns
+ports:
+    - 53:53/UDP
+    - 8080:80/sctp
+    - 53:53
+"#;
+        let frag: ComposeServiceFragment = serde_yaml::from_str(t).unwrap();
+        let ports = frag.ports.as_ref().unwrap();
+
+        assert_eq!(Some("udp"), ports[0].protocol.as_deref());
+        assert_eq!(Some("sctp"), ports[1].protocol.as_deref());
+
+        let yaml = serde_yaml::to_string(ports).unwrap();
+        assert_eq!("- 53:53/udp\n- 8080:80/sctp\n- 53:53\n", yaml);
+
+        // UDP and TCP 53 don't conflict, whatever the case of the protocol.
+        let svcs = [ComposeService::new("dns", "dns", &frag)];
+        assert!(port_conflicts(&host_ports(svcs.iter())).is_empty());
+    }
+
+    #[test]
+    fn test_port_mapping_rejects_unmatched_text() {
+        let parse = |p: &str| serde_yaml::from_str::<ComposeServiceFragment>(&format!("image: web\nports: [ '{}' ]", p));
+
+        assert!(parse("9000-9002:9000-9002").is_err());
+        assert!(parse("9000-9002").is_err());
+        assert!(parse("8080:80/icmp").is_err());
+        assert!(parse("8080:80 ").is_err());
+        assert!(parse("8080:80:90").is_err());
+        assert!(parse("99999:80").is_err());
+    }
+
+    #[test]
+    fn test_port_host_ip() {
+        let t = r#"
+image: web
+ports:
+    - 127.0.0.1:8080:80
+    - "[::1]:8443:443/tcp"
+    - 9090:90
+    - "90"
+"#;
+        let frag: ComposeServiceFragment = serde_yaml::from_str(t).unwrap();
+        let ports = frag.ports.as_ref().unwrap();
+
+        assert_eq!(Some("127.0.0.1"), ports[0].host_ip.as_deref());
+        assert_eq!(Some(8080), ports[0].source);
+        assert_eq!(80, ports[0].target);
+        assert_eq!(Some("[::1]"), ports[1].host_ip.as_deref());
+        assert_eq!(Some(8443), ports[1].source);
+        assert_eq!(None, ports[2].host_ip);
+        assert_eq!(None, ports[3].source);
+        assert_eq!(90, ports[3].target);
+
+        let yaml = serde_yaml::to_string(ports).unwrap();
+        assert_eq!("- 127.0.0.1:8080:80\n- '[::1]:8443:443/tcp'\n- 9090:90\n- '90'\n", yaml);
+
+        assert!(serde_yaml::from_str::<ComposeServiceFragment>("image: web\nports: [ '127.0.0.1:80' ]").is_err());
+
+        // Conflicts are keyed by the host port and protocol, not the interface.
+        let other: ComposeServiceFragment = serde_yaml::from_str("image: other\nports: [ '8080:81', '9090:90/udp' ]").unwrap();
+        let svcs = [
+            ComposeService::new("web", "web", &frag),
+            ComposeService::new("other", "other", &other),
+        ];
+        let conflicts = port_conflicts(&host_ports(svcs.iter()));
+        let tcp = |port| HostPort { port, protocol: "tcp".to_string() };
+        assert_eq!(Some(&vec!["other".to_string(), "web".to_string()]), conflicts.get(&tcp(8080)));
+        assert_eq!(vec![&tcp(8080)], conflicts.keys().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_reassign_conflicting_ports() {
+        let frag = |ports: &str| -> ComposeServiceFragment {
+            serde_yaml::from_str(&format!("image: foo\nports: {}", ports)).unwrap()
+        };
+
+        let templates = [
+            ComposeService::new("web", "web", &frag("[ '8080:80' ]")),
+            ComposeService::new("api", "api", &frag("[ '8080:81', '9000:9000' ]")),
+            ComposeService::new("admin", "admin", &frag("[ '8080:82', '9000:90' ]")),
+            ComposeService::new("dns", "dns", &frag("[ '53:53/tcp', '53:53/udp' ]")),
+        ];
+
+        let (services, reassigned) = reassign_ports(&templates.iter().collect::<Vec<_>>(), (8999, 9010));
+
+        // admin keeps each port as the first by name, 9000 is already in use, and dns publishing
+        // 53 for both tcp and udp doesn't conflict with itself.
+        let tcp = |port| HostPort { port, protocol: "tcp".to_string() };
+        assert_eq!(
+            vec![
+                ("api".to_string(), tcp(8080), 8999),
+                ("web".to_string(), tcp(8080), 9001),
+                ("api".to_string(), tcp(9000), 9002),
+            ],
+            reassigned
+        );
+        assert!(port_conflicts(&host_ports(services.iter())).is_empty());
+        assert_eq!(
+            "- 8999:81\n- 9002:9000\n",
+            serde_yaml::to_string(&services[1].fragment.ports).unwrap()
+        );
+
+        // Only the given services are considered, a conflict with any other template is ignored.
+        let (services, reassigned) = reassign_ports(&[&templates[0], &templates[3]], (8999, 9010));
+        assert!(reassigned.is_empty());
+        assert_eq!("- 8080:80\n", serde_yaml::to_string(&services[0].fragment.ports).unwrap());
+    }
+
+    #[test]
+    fn test_env_file_with_environment() {
+        let t = r#"
+image: api
+env_file:
+  - ./common.env
+  - ./api.env
+environment:
+  LOG_LEVEL: debug
+"#;
+        let frag: ComposeServiceFragment = serde_yaml::from_str(t).unwrap();
+        let yaml = serde_yaml::to_string(&frag).unwrap();
+        let parsed: ComposeServiceFragment = serde_yaml::from_str(&yaml).unwrap();
+
+        assert_eq!(Some(vec!["./common.env".to_string(), "./api.env".to_string()]), parsed.env_file);
+        assert_eq!("debug", parsed.environment.as_ref().unwrap()["LOG_LEVEL"]);
+
+        let single: ComposeServiceFragment = serde_yaml::from_str("image: api\nenv_file: ./api.env").unwrap();
+        assert_eq!(Some(vec!["./api.env".to_string()]), single.env_file);
+
+        let none: ComposeServiceFragment = serde_yaml::from_str("image: api").unwrap();
+        assert!(!serde_yaml::to_string(&none).unwrap().contains("env_file"));
+    }
+
+    #[test]
+    fn test_labels_forms() {
+        let map: ComposeServiceFragment = serde_yaml::from_str(
+            "image: api\nlabels:\n  traefik.enable: 'true'\n  com.example.team: search\n",
+        )
+        .unwrap();
+        let list: ComposeServiceFragment = serde_yaml::from_str(
+            "image: api\nlabels:\n  - traefik.enable=true\n  - com.example.team=search\n",
+        )
+        .unwrap();
+
+        assert_eq!(map.labels, list.labels);
+        assert_eq!("search", list.labels.as_ref().unwrap()["com.example.team"]);
+
+        for frag in [map, list] {
+            let yaml = serde_yaml::to_string(&frag).unwrap();
+            assert!(yaml.contains("labels:\n  "), "{}", yaml);
+
+            let parsed: ComposeServiceFragment = serde_yaml::from_str(&yaml).unwrap();
+            assert_eq!(frag.labels, parsed.labels);
+        }
+
+        let none: ComposeServiceFragment = serde_yaml::from_str("image: api").unwrap();
+        assert_eq!(None, none.labels);
+        assert!(!serde_yaml::to_string(&none).unwrap().contains("labels"));
+    }
+
+    #[test]
+    fn test_restart_on_failure_with_retries() {
+        let frag: ComposeServiceFragment =
+            serde_yaml::from_str("image: foo\nrestart: on-failure:5").unwrap();
+        let restart = frag.restart.as_ref().unwrap();
+        assert_eq!("on-failure", restart.policy);
+        assert_eq!(Some(5), restart.max_retries);
+        assert_eq!(
+            "image: foo\nrestart: on-failure:5\n",
+            serde_yaml::to_string(&frag).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_restart_on_failure() {
+        let frag: ComposeServiceFragment =
+            serde_yaml::from_str("image: foo\nrestart: on-failure").unwrap();
+        let restart = frag.restart.unwrap();
+        assert_eq!("on-failure", restart.policy);
+        assert_eq!(None, restart.max_retries);
+    }
+
+    #[test]
+    fn test_restart_retries_rejected_for_other_policies() {
+        assert!(serde_yaml::from_str::<ComposeServiceFragment>("image: foo\nrestart: always:3").is_err());
+        assert!(serde_yaml::from_str::<ComposeServiceFragment>("image: foo\nrestart: sometimes").is_err());
+        assert!(serde_yaml::from_str::<ComposeServiceFragment>("image: foo\nrestart: on-failure:x").is_err());
+    }
 }
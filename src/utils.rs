@@ -31,6 +31,33 @@ pub enum Error {
         file: String,
         source: std::io::Error,
     },
+
+    #[snafu(display(r#"Unable to set the permissions of the file: {}\n{}"#, file, source))]
+    SetFileMode {
+        file: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display(r#"Unable to write: {} as its directory doesn't exist: {}"#, file, dir))]
+    OutputDirMissing { file: String, dir: String },
+
+    #[snafu(display(r#"Unable to write: {} as permission to write to the directory: {} was denied, is it a read-only mount?"#, file, dir))]
+    OutputPermissionDenied { file: String, dir: String },
+
+    #[snafu(display(r#"Unable to write: {} as it is a directory"#, file))]
+    OutputIsDirectory { file: String },
+
+    #[snafu(display(r#"Unable to write to the directory: {}\n{}"#, dir, source))]
+    OutputNotWritable {
+        dir: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display(r#"Unable to load the environment file: {}\n{}"#, file, source))]
+    LoadEnvFile {
+        file: String,
+        source: dotenvy::Error,
+    },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
@@ -57,6 +84,7 @@ pub fn read_file_contents(path: &Path) -> Result<String> {
     Ok(s)
 }
 
+/// Writes the contents to the file, appending a trailing newline if it is missing.
 pub fn write_str_to_file(path: &Path, contents: &str) -> Result<()> {
     let display = path.display().to_string();
 
@@ -73,5 +101,196 @@ pub fn write_str_to_file(path: &Path, contents: &str) -> Result<()> {
     );
 
     file.write_all(contents.as_bytes())
-        .context(WriteFile { file: &display })
+        .context(WriteFile { file: &display })?;
+
+    if !contents.ends_with('\n') {
+        file.write_all(b"\n").context(WriteFile { file: &display })?;
+    }
+
+    Ok(())
+}
+
+/// Parses an octal file mode such as `0644`.
+pub fn parse_file_mode(mode: &str) -> Option<u32> {
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+
+    match u32::from_str_radix(digits, 8) {
+        Ok(m) if m <= 0o7777 => Some(m),
+        _ => None,
+    }
+}
+
+/// Parses a timeout such as `30s`, `5m` or `1.5h`, using the units of `--since` but with a
+/// number without a unit being seconds.  A zero timeout is rejected as every run would
+/// immediately time out.
+pub fn parse_timeout(timeout: &str) -> Option<std::time::Duration> {
+    crate::images::parse_duration(timeout, "s")
+        .ok()
+        .and_then(|d| d.to_std().ok())
+        .filter(|d| !d.is_zero())
+}
+
+/// Sets the permissions of a written file, which is only supported on unix.
+#[cfg(unix)]
+pub fn set_file_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).context(SetFileMode {
+        file: path.display().to_string(),
+    })
+}
+
+#[cfg(not(unix))]
+pub fn set_file_mode(path: &Path, _mode: u32) -> Result<()> {
+    warn!(
+        "{} - ignoring the file mode of {} as it is only supported on unix",
+        module_path!(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// The path made absolute against the current directory for display, e.g. within errors.
+pub fn resolved_display(path: &Path) -> String {
+    std::path::absolute(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .display()
+        .to_string()
+}
+
+/// Loads KEY=VALUE pairs from a dotenv style file into the process environment.  Variables
+/// which are already set are left untouched unless `override_existing` is set.
+pub fn load_env_file(path: &Path, override_existing: bool) -> Result<()> {
+    let display = path.display().to_string();
+
+    debug!("{} - loading environment file {}", module_path!(), &display);
+
+    match override_existing {
+        true => dotenvy::from_path_override(path),
+        false => dotenvy::from_path(path),
+    }
+    .context(LoadEnvFile { file: &display })
+}
+
+/// Checks that the given file could be written, i.e. its directory exists and is writable and
+/// the path isn't itself a directory, so that a run fails early with an actionable error.
+pub fn preflight_output_path(path: &Path) -> Result<()> {
+    let file = path.display().to_string();
+
+    if path.is_dir() {
+        return Err(Error::OutputIsDirectory { file });
+    }
+
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let dir_display = dir.display().to_string();
+
+    if !dir.is_dir() {
+        return Err(Error::OutputDirMissing {
+            file,
+            dir: dir_display,
+        });
+    }
+
+    match tempfile::NamedTempFile::new_in(dir) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            Err(Error::OutputPermissionDenied {
+                file,
+                dir: dir_display,
+            })
+        }
+        Err(e) => Err(e).context(OutputNotWritable { dir: dir_display }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_appends_trailing_newline_only_when_absent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("docker-compose.yml");
+
+        write_str_to_file(&path, "version: '3'").unwrap();
+        assert_eq!("version: '3'\n", std::fs::read_to_string(&path).unwrap());
+
+        write_str_to_file(&path, "version: '3'\n").unwrap();
+        assert_eq!("version: '3'\n", std::fs::read_to_string(&path).unwrap());
+    }
+
+    #[test]
+    fn test_parse_file_mode() {
+        assert_eq!(Some(0o644), parse_file_mode("0644"));
+        assert_eq!(Some(0o600), parse_file_mode("0o600"));
+        assert_eq!(None, parse_file_mode("0968"));
+        assert_eq!(None, parse_file_mode("rw-r--r--"));
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        use std::time::Duration;
+
+        assert_eq!(Some(Duration::from_secs(30)), parse_timeout("30"));
+        assert_eq!(Some(Duration::from_secs(30)), parse_timeout("30s"));
+        assert_eq!(Some(Duration::from_secs(300)), parse_timeout("5m"));
+        assert_eq!(Some(Duration::from_secs(5400)), parse_timeout("1.5h"));
+        assert_eq!(None, parse_timeout("0"));
+        assert_eq!(None, parse_timeout("0s"));
+        assert_eq!(None, parse_timeout("2024-01-01"));
+        assert_eq!(None, parse_timeout("30x"));
+        assert_eq!(None, parse_timeout("m"));
+        assert_eq!(None, parse_timeout(""));
+    }
+
+    #[test]
+    fn test_preflight_writable() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(preflight_output_path(&dir.path().join("docker-compose.yml")).is_ok());
+    }
+
+    #[test]
+    fn test_preflight_missing_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing").join("docker-compose.yml");
+
+        assert!(matches!(
+            preflight_output_path(&path),
+            Err(Error::OutputDirMissing { .. })
+        ));
+    }
+
+    #[test]
+    fn test_preflight_is_directory() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert!(matches!(
+            preflight_output_path(dir.path()),
+            Err(Error::OutputIsDirectory { .. })
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_preflight_permission_denied() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let read_only = dir.path().join("read-only");
+        std::fs::create_dir(&read_only).unwrap();
+        std::fs::set_permissions(&read_only, std::fs::Permissions::from_mode(0o555)).unwrap();
+
+        // Permissions aren't enforced for root, e.g. within some CI containers.
+        if tempfile::NamedTempFile::new_in(&read_only).is_ok() {
+            return;
+        }
+
+        assert!(matches!(
+            preflight_output_path(&read_only.join("docker-compose.yml")),
+            Err(Error::OutputPermissionDenied { .. })
+        ));
+    }
 }
@@ -0,0 +1,191 @@
+use log::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::hooks;
+
+/// Resolves a service's version from the latest git tag carrying its prefix, e.g.
+/// `contentrepo-v1.2.3` for monorepo style workflows.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct GitTagSource {
+    /// The repository whose tags are listed, the current directory by default.
+    repository: Option<String>,
+    /// The tag prefix, where `{service}` is replaced by the service name.
+    #[serde(default = "default_tag_prefix")]
+    prefix: String,
+    /// Prefixes for individual services overriding `prefix`.
+    #[serde(default)]
+    services: HashMap<String, String>,
+}
+
+fn default_tag_prefix() -> String {
+    "{service}-v".to_string()
+}
+
+impl GitTagSource {
+    fn prefix_for(&self, service: &str) -> String {
+        self.services
+            .get(service)
+            .unwrap_or(&self.prefix)
+            .replace("{service}", service)
+    }
+
+    /// Maps each of the given services to the version of its latest tag, services without a
+    /// matching tag are left out.  Any failure to run git is logged and treated as no tags.
+    pub async fn versions(&self, services: &[String]) -> HashMap<String, String> {
+        let dir = Path::new(self.repository.as_deref().unwrap_or("."));
+        let mut versions = HashMap::new();
+
+        for service in services {
+            let prefix = self.prefix_for(service);
+            let pattern = format!("{}*", prefix);
+
+            let tags = match git_output(dir, &["tag", "--list", &pattern]).await {
+                Some(t) => t,
+                None => {
+                    warn!("Warning: Unable to list the git tags of {}", dir.display());
+                    return versions;
+                }
+            };
+
+            if let Some(v) = latest_tag_version(tags.lines(), &prefix) {
+                versions.insert(service.clone(), v);
+            }
+        }
+
+        versions
+    }
+}
+
+/// Selects the highest semantic version among the tags with the given prefix, returning the
+/// remainder of the tag, e.g. `1.2.3` of `contentrepo-v1.2.3`.  Tags which aren't a semantic
+/// version once a leading `v` is removed are ignored.
+fn latest_tag_version<'a>(tags: impl Iterator<Item = &'a str>, prefix: &str) -> Option<String> {
+    tags.filter_map(|t| t.trim().strip_prefix(prefix))
+        .filter_map(|v| {
+            let version = semver::Version::parse(v.strip_prefix('v').unwrap_or(v)).ok()?;
+            Some((version, v))
+        })
+        .max_by(|a, b| a.0.cmp(&b.0))
+        .map(|(_, v)| v.to_string())
+}
+
+/// How git sees a generated file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VcsStatus {
+    /// git isn't available or the file isn't within a git work tree.
+    NotARepository,
+    Untracked,
+    /// The file matches a .gitignore pattern.
+    Ignored,
+    /// The file is committed or staged and isn't ignored, so regenerating it causes churn.
+    Tracked,
+}
+
+fn git_spec(dir: &Path, args: &[&str]) -> Vec<String> {
+    let mut spec = vec![
+        "git".to_string(),
+        "-C".to_string(),
+        dir.to_string_lossy().to_string(),
+    ];
+    spec.extend(args.iter().map(|a| a.to_string()));
+    spec
+}
+
+async fn git_succeeds(dir: &Path, args: &[&str]) -> bool {
+    matches!(hooks::run_captured(&git_spec(dir, args)).await, Ok(Some(o)) if o.status.success())
+}
+
+/// The standard output of a successful git command.
+async fn git_output(dir: &Path, args: &[&str]) -> Option<String> {
+    match hooks::run_captured(&git_spec(dir, args)).await {
+        Ok(Some(o)) if o.status.success() => Some(String::from_utf8_lossy(&o.stdout).to_string()),
+        _ => None,
+    }
+}
+
+/// Determines whether the given file is tracked or ignored by git by running git in the file's
+/// directory.  Any failure to run git is treated as the file not being in a repository.
+pub async fn status(path: &Path) -> VcsStatus {
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+
+    let file = match path.file_name() {
+        Some(f) => f.to_string_lossy().to_string(),
+        None => return VcsStatus::NotARepository,
+    };
+
+    if !git_succeeds(dir, &["rev-parse", "--is-inside-work-tree"]).await {
+        return VcsStatus::NotARepository;
+    }
+
+    if git_succeeds(dir, &["check-ignore", "-q", "--no-index", &file]).await {
+        return VcsStatus::Ignored;
+    }
+
+    match git_succeeds(dir, &["ls-files", "--error-unmatch", &file]).await {
+        true => VcsStatus::Tracked,
+        false => VcsStatus::Untracked,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = std::process::Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_latest_tag_version() {
+        let tags = [
+            "contentrepo-v1.2.3",
+            "contentrepo-v1.10.0",
+            "contentrepo-v1.10.0-rc.1",
+            "contentrepo-vnext",
+            "contentrepo-api-v9.0.0",
+            "search-v2.0.0",
+        ];
+
+        assert_eq!(
+            Some("1.10.0".to_string()),
+            latest_tag_version(tags.iter().copied(), "contentrepo-v")
+        );
+        assert_eq!(Some("v2.0.0".to_string()), latest_tag_version(tags.iter().copied(), "search-"));
+        assert_eq!(None, latest_tag_version(tags.iter().copied(), "mysql-v"));
+
+        let source: GitTagSource =
+            serde_yaml::from_str("services:\n  contentrepo: content-repo/").unwrap();
+        assert_eq!("search-v", source.prefix_for("search"));
+        assert_eq!("content-repo/", source.prefix_for("contentrepo"));
+    }
+
+    #[tokio::test]
+    async fn test_vcs_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let compose = dir.path().join("docker-compose.yml");
+        std::fs::write(&compose, "version: '3'\n").unwrap();
+
+        assert_eq!(VcsStatus::NotARepository, status(&compose).await);
+
+        git(dir.path(), &["init", "-q"]);
+        assert_eq!(VcsStatus::Untracked, status(&compose).await);
+
+        git(dir.path(), &["add", "docker-compose.yml"]);
+        assert_eq!(VcsStatus::Tracked, status(&compose).await);
+
+        std::fs::write(dir.path().join(".gitignore"), "docker-compose.yml\n").unwrap();
+        assert_eq!(VcsStatus::Ignored, status(&compose).await);
+    }
+}
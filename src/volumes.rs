@@ -1,8 +1,12 @@
 use log::*;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::Write;
-use std::path::{Path, PathBuf};
+use std::io::{Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
 
+use glob::Pattern;
+use indicatif::{ProgressBar, ProgressStyle};
+use sha2::{Digest, Sha256};
 use http::Uri;
 
 use aws_config::meta::region::RegionProviderChain;
@@ -11,6 +15,9 @@ use s3::config::Region;
 
 use snafu::{ResultExt, Snafu};
 
+use crate::templates::ComposeService;
+use crate::warnings::{Category, Warnings};
+
 #[derive(Debug, Snafu)]
 pub enum Error {
     #[snafu(display(
@@ -29,6 +36,9 @@ pub enum Error {
     #[snafu(display("Unable to create temporary file\n{}", source))]
     CreateTmpFile { source: std::io::Error },
 
+    #[snafu(display("Unable to open the archive: {}\n{}", file, source))]
+    OpenArchive { file: String, source: std::io::Error },
+
     #[snafu(display("Unable to persist temporary file.\n{}", source))]
     PersistTmpFile { source: tempfile::PersistError },
 
@@ -56,16 +66,126 @@ pub enum Error {
         source: zip::result::ZipError,
     },
 
+    #[snafu(display(
+        "Unable to extract tar archive for volume: {} source: {}.\n{}",
+        name,
+        volume_source,
+        source
+    ))]
+    ExtractTar {
+        name: String,
+        volume_source: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display(
+        "Invalid include pattern for volume: {} pattern: {}.\n{}",
+        name,
+        pattern,
+        source
+    ))]
+    InvalidIncludePattern {
+        name: String,
+        pattern: String,
+        source: glob::PatternError,
+    },
+
+    #[snafu(display(
+        "Unable to extract entry {} from the archive for volume: {}.\n{}",
+        entry,
+        name,
+        source
+    ))]
+    ExtractEntry {
+        name: String,
+        entry: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Unable to resolve a variable for volume: {} in: {}", name, value))]
+    UnresolvedVariable { name: String, value: String },
+
     #[snafu(display("Unable download object from S3.\n{}", source))]
     S3GetObject {
         #[snafu(source(from(s3::error::SdkError<s3::operation::get_object::GetObjectError>, Box::new)))]
         source: Box<s3::error::SdkError<s3::operation::get_object::GetObjectError>>,
     },
 
+    #[snafu(display(
+        "Volume targets overlap, extraction of one would clobber the other: {} ({}) and {} ({})",
+        first,
+        first_target,
+        second,
+        second_target
+    ))]
+    OverlappingTargets {
+        first: String,
+        first_target: String,
+        second: String,
+        second_target: String,
+    },
+
+    #[snafu(display("Unable to download the archive for volume: {} from: {}.\n{}", name, url, source))]
+    HttpDownload {
+        name: String,
+        url: String,
+        source: reqwest::Error,
+    },
+
+    #[snafu(display("Unable to download the archive for volume: {} from: {}, the server responded with {}", name, url, status))]
+    HttpDownloadStatus {
+        name: String,
+        url: String,
+        status: reqwest::StatusCode,
+    },
+
+    #[snafu(display(
+        "The archive for volume: {} doesn't match its sha256, expected: {} actual: {}",
+        name,
+        expected,
+        actual
+    ))]
+    ChecksumMismatch {
+        name: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[snafu(display("Unable to read the archive for volume: {} to verify its sha256.\n{}", name, source))]
+    ReadChecksum { name: String, source: std::io::Error },
+
+    #[snafu(display("Unable to write the downloaded archive to a temporary file.\n{}", source))]
+    WriteTmpFile { source: std::io::Error },
+
+    #[snafu(display(
+        "Refusing to clear the target: {} of volume: {}, as it isn't within the volume root: {}, or is the home or current directory, or one of their parents",
+        target,
+        name,
+        root
+    ))]
+    UnsafeTarget {
+        name: String,
+        target: String,
+        root: String,
+    },
+
+    #[snafu(display("Unable to clear the target: {} of volume: {}.\n{}", target, name, source))]
+    ClearTarget {
+        name: String,
+        target: String,
+        source: std::io::Error,
+    },
+
     #[snafu(display("Error occurred streaming object from S3\n{}", source))]
     S3GetBytes {
         source: s3::primitives::ByteStreamError
     },
+
+    #[snafu(display("The extraction of volume: {} did not complete.\n{}", name, source))]
+    ExtractTask {
+        name: String,
+        source: tokio::task::JoinError,
+    },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
@@ -75,6 +195,36 @@ pub struct VolumeInitializer {
     pub name: String,
     pub source: String,
     pub target: String,
+    pub include: Option<Vec<String>>,
+    /// Extract an S3 archive as it is downloaded rather than storing it in a temporary file first.
+    #[serde(default)]
+    pub stream: bool,
+    /// The expected SHA-256 of the archive, as hex, verified before it is extracted.
+    pub sha256: Option<String>,
+}
+
+/// The format of a volume's archive, as given by the extension of its source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveKind {
+    /// Sources without a recognised extension are assumed to be zip archives.  The query of an
+    /// http url, e.g. a signature, is ignored.
+    fn from_source(source: &str) -> ArchiveKind {
+        let source = source.split('?').next().unwrap_or(source).to_lowercase();
+
+        if source.ends_with(".tar.gz") || source.ends_with(".tgz") {
+            ArchiveKind::TarGz
+        } else if source.ends_with(".tar") {
+            ArchiveKind::Tar
+        } else {
+            ArchiveKind::Zip
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -83,56 +233,123 @@ struct S3Location {
     key: String,
 }
 
+/// Options controlling how volumes are expanded and initialised.
+#[derive(Debug, Default, Clone)]
+pub struct VolumeOptions {
+    /// Variables available to volume sources and targets in addition to the process
+    /// environment, e.g. HANDEL_ENV.  These take precedence over the environment.
+    pub variables: HashMap<String, String>,
+    /// Treat volumes referencing unresolvable variables as an error rather than skipping them.
+    pub strict: bool,
+    /// Where S3 archives are cached between runs, no cache being used if None.
+    pub cache_dir: Option<PathBuf>,
+    /// Clear and re-extract volumes whose target directory isn't empty rather than skipping them.
+    pub force: bool,
+    /// The directory a target must be within for it to be cleared, the current directory if None.
+    pub root: Option<String>,
+    /// Show a progress bar while archives are downloaded.
+    pub progress: bool,
+    /// Collects the warnings raised while the volumes are expanded.
+    pub warnings: Warnings,
+}
+
+impl VolumeOptions {
+    /// Builds the variables available to volume definitions from those provided by handel
+    /// (HANDEL_ENV, HANDEL_SCENARIO) and the config file's `variables` section.  The handel
+    /// provided values win over the config file.
+    pub fn new(
+        config_variables: &Option<HashMap<String, String>>,
+        env: &str,
+        scenario: &str,
+        strict: bool,
+    ) -> VolumeOptions {
+        let mut variables = config_variables.clone().unwrap_or_default();
+        variables.insert("HANDEL_ENV".to_string(), env.to_string());
+        variables.insert("HANDEL_SCENARIO".to_string(), scenario.to_string());
+
+        VolumeOptions {
+            variables,
+            strict,
+            cache_dir: None,
+            force: false,
+            root: None,
+            progress: false,
+            warnings: Warnings::default(),
+        }
+    }
+}
+
+/// The environment variable which overrides the directory S3 archives are cached in.
+pub const CACHE_DIR_VARIABLE: &str = "HANDEL_CACHE_DIR";
+
+/// The directory S3 archives are cached in: HANDEL_CACHE_DIR if set, otherwise handel within the
+/// XDG cache directory, by default `~/.cache/handel`.
+pub fn default_cache_dir() -> Option<PathBuf> {
+    match std::env::var_os(CACHE_DIR_VARIABLE) {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => std::env::var_os("XDG_CACHE_HOME")
+            .filter(|d| !d.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| home::home_dir().map(|h| h.join(".cache")))
+            .map(|d| d.join("handel")),
+    }
+}
+
 pub struct Volumes {}
 
+/// Lists the named volumes used by the given services which no `volume-init` entry is named
+/// after, and so would be created empty, along with the service using each.
+pub fn unseeded_named_volumes<'a>(
+    services: &[&'a ComposeService],
+    volumes: &Option<Vec<VolumeInitializer>>,
+) -> Vec<(&'a ComposeService, String)> {
+    let seeded = volumes.iter().flatten().map(|v| v.name.as_str()).collect::<Vec<_>>();
+
+    let mut unseeded = services
+        .iter()
+        .flat_map(|s| s.fragment().named_volumes().into_iter().map(move |v| (*s, v)))
+        .filter(|(_, v)| !seeded.contains(v))
+        .map(|(s, v)| (s, v.to_string()))
+        .collect::<Vec<_>>();
+
+    unseeded.sort_by(|a, b| (a.0.name(), &a.1).cmp(&(b.0.name(), &b.1)));
+    unseeded.dedup_by(|a, b| a.0.name() == b.0.name() && a.1 == b.1);
+    unseeded
+}
+
 impl Volumes {
-    pub async fn initialise(volumes: &Option<Vec<VolumeInitializer>>) -> Result<()> {
-
-        let vols = volumes.as_ref()
-            .unwrap_or(&Vec::new())
-            .iter()
-            .filter_map(|v| {
-                debug!("{} - considering volume {:?}", module_path!(), &v);
-                let s = shellexpand::env(&v.source).ok();
-                let t = shellexpand::env(&v.target).ok();
-
-                debug!("{} - expanded source {:?}", module_path!(), &s);
-                debug!("{} - expanded target {:?}", module_path!(), &t);
-
-                if s.is_none() {
-                    warn!(
-                        "{} - Source for volume: {} is invalid: {}",
-                        module_path!(),
-                        &v.name,
-                        &v.source
-                    );
-                    return None;
-                }
+    /// Expands the configured volumes and validates them without extracting anything.
+    pub fn validate(volumes: &Option<Vec<VolumeInitializer>>, options: &VolumeOptions) -> Result<()> {
+        check_overlapping_targets(&expand_volumes(volumes, options)?)
+    }
 
-                if t.is_none() {
-                    warn!(
-                        "{} - Target for volume: {} is invalid: {}",
-                        module_path!(),
-                        &v.name,
-                        &v.target
-                    );
-                    return None;
-                }
+    pub async fn initialise(
+        volumes: &Option<Vec<VolumeInitializer>>,
+        options: &VolumeOptions,
+    ) -> Result<()> {
 
-                let target_dir = t.unwrap().to_string();
+        let vols = expand_volumes(volumes, options)?;
 
-                if !target_dir_valid(&target_dir) {
-                    println!("Skipping volume target: {}", &target_dir);
-                    return None;
-                }
+        check_overlapping_targets(&vols)?;
 
-                Some(VolumeInitializer {
-                    source: s.unwrap().to_string(),
-                    target: target_dir,
-                    name: v.name.clone(),
-                })
-            })
-            .collect::<Vec<_>>();
+        let root = normalise_path(options.root.as_deref().unwrap_or("."));
+
+        let mut pending = Vec::new();
+        for v in vols {
+            if target_dir_valid(&v.target) {
+                pending.push(v);
+            } else if options.force {
+                println!("Clearing volume: {} target: {}", &v.name, &v.target);
+                clear_target_dir(&v, &root)?;
+                pending.push(v);
+            } else {
+                println!(
+                    "Skipping volume: {}, target: {} is not empty (use --force-volumes to re-extract it)",
+                    &v.name, &v.target
+                );
+            }
+        }
+        let vols = pending;
 
         if vols.is_empty() {
             return Ok(());
@@ -142,10 +359,14 @@ impl Volumes {
 
         for v in &vols {
             println!("Processing volume: {}", &v.name);
-            match v.source.to_lowercase().starts_with("s3://") {
-                true => unzip_file_from_s3(v).await?,
-                false => unzip_local_file(v)?,
-            };
+            let source = v.source.to_lowercase();
+            if source.starts_with("s3://") {
+                unzip_file_from_s3(v, options).await?;
+            } else if source.starts_with("http://") || source.starts_with("https://") {
+                unzip_file_from_http(v, options.progress).await?;
+            } else {
+                unzip_local_file(v)?;
+            }
 
             info!("Finished volumes: {}", &v.name);
         }
@@ -156,31 +377,333 @@ impl Volumes {
     }
 }
 
+/// Looks up a variable referenced by a volume source or target, preferring the variables
+/// provided by handel over those of the process environment.
+fn lookup_variable(
+    variables: &HashMap<String, String>,
+    name: &str,
+) -> std::result::Result<Option<String>, std::env::VarError> {
+    match variables.get(name) {
+        Some(v) => Ok(Some(v.clone())),
+        None => std::env::var(name).map(Some),
+    }
+}
+
+fn expand_volumes(
+    volumes: &Option<Vec<VolumeInitializer>>,
+    options: &VolumeOptions,
+) -> Result<Vec<VolumeInitializer>> {
+    let mut expanded = Vec::new();
+
+    for v in volumes.iter().flatten() {
+        debug!("{} - considering volume {:?}", module_path!(), &v);
+        let lookup = |name: &str| lookup_variable(&options.variables, name);
+        let s = shellexpand::env_with_context(&v.source, lookup).ok();
+        let t = shellexpand::env_with_context(&v.target, lookup).ok();
+
+        debug!("{} - expanded source {:?}", module_path!(), &s);
+        debug!("{} - expanded target {:?}", module_path!(), &t);
+
+        let (s, t) = match (s, t) {
+            (Some(s), Some(t)) => (s, t),
+            (s, _) => {
+                let (kind, value) = match s {
+                    None => ("Source", &v.source),
+                    Some(_) => ("Target", &v.target),
+                };
+
+                if options.strict {
+                    return Err(Error::UnresolvedVariable {
+                        name: v.name.to_string(),
+                        value: value.to_string(),
+                    });
+                }
+
+                options.warnings.warn(
+                    Category::Volume,
+                    &format!("{} for volume: {} is invalid: {}", kind, &v.name, value),
+                    None,
+                    None,
+                );
+                continue;
+            }
+        };
+
+        expanded.push(VolumeInitializer {
+            source: s.to_string(),
+            target: t.to_string(),
+            name: v.name.clone(),
+            include: v.include.clone(),
+            stream: v.stream,
+            sha256: v.sha256.clone(),
+        });
+    }
+
+    Ok(expanded)
+}
+
 fn unzip_local_file(volume: &VolumeInitializer) -> Result<()> {
     let from = PathBuf::from(&volume.source);
     let to = PathBuf::from(&volume.target);
 
     info!(
-        "{} - Extracting zip for volume: {} to dir: {} ....",
+        "{} - Extracting archive for volume: {} to dir: {} ....",
         module_path!(),
         &volume.name,
         &volume.target
     );
 
-    let file = File::open(from).context(CreateTmpFile)?;
+    let mut file = File::open(from).context(CreateTmpFile)?;
+    verify_checksum(&mut file, volume)?;
+
+    extract_archive(file, volume, &to)
+}
+
+/// Compares the SHA-256 of the whole file with that configured for the volume, if any, leaving
+/// the file positioned at its start.
+fn verify_checksum(file: &mut File, volume: &VolumeInitializer) -> Result<()> {
+    let expected = match &volume.sha256 {
+        Some(e) => e,
+        None => return Ok(()),
+    };
+
+    let read_error = || ReadChecksum {
+        name: volume.name.to_string(),
+    };
+
+    let mut hasher = Sha256::new();
+    file.rewind().context(read_error())?;
+    std::io::copy(file, &mut hasher).context(read_error())?;
+    file.rewind().context(read_error())?;
+
+    let actual = format!("{:x}", hasher.finalize());
+    match actual.eq_ignore_ascii_case(expected.trim()) {
+        true => Ok(()),
+        false => Err(Error::ChecksumMismatch {
+            name: volume.name.to_string(),
+            expected: expected.to_string(),
+            actual,
+        }),
+    }
+}
+
+/// Extracts the zip, tar or gzipped tar archive of the volume into the target directory.
+fn extract_archive(file: File, volume: &VolumeInitializer, to: &Path) -> Result<()> {
+    match ArchiveKind::from_source(&volume.source) {
+        ArchiveKind::Zip => extract_zip(file, volume, to),
+        ArchiveKind::Tar => extract_tar(file, volume, to),
+        ArchiveKind::TarGz => extract_tar(flate2::read::GzDecoder::new(file), volume, to),
+    }
+}
+
+/// Extracts an archive of the volume which is read forwards only, e.g. as it is downloaded.
+fn extract_archive_stream<R: Read>(reader: R, volume: &VolumeInitializer, to: &Path) -> Result<()> {
+    match ArchiveKind::from_source(&volume.source) {
+        ArchiveKind::Zip => extract_zip_stream(reader, volume, to),
+        ArchiveKind::Tar => extract_tar(reader, volume, to),
+        ArchiveKind::TarGz => extract_tar(flate2::read::GzDecoder::new(reader), volume, to),
+    }
+}
+
+/// Extracts the regular files of a tar archive into the target directory, skipping any entry
+/// whose path would escape it.  If the volume has include patterns then only those entries whose
+/// path matches one of them are written.
+fn extract_tar<R: Read>(reader: R, volume: &VolumeInitializer, to: &Path) -> Result<()> {
+    let tar_error = || ExtractTar {
+        name: volume.name.to_string(),
+        volume_source: volume.source.to_string(),
+    };
+
+    let patterns = include_patterns(volume)?;
+    let mut archive = tar::Archive::new(reader);
+
+    for entry in archive.entries().context(tar_error())? {
+        let mut entry = entry.context(tar_error())?;
+
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let entry_path = entry.path().context(tar_error())?.to_path_buf();
+        if !entry_path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+            warn!("{} - skipping archive entry outside the target {:?}", module_path!(), &entry_path);
+            continue;
+        }
+
+        if let Some(patterns) = &patterns {
+            if !patterns.iter().any(|p| p.matches_path(&entry_path)) {
+                trace!("{} - skipping archive entry {:?}", module_path!(), &entry_path);
+                continue;
+            }
+        }
+
+        write_entry(&mut entry, &entry_path, to, volume)?;
+    }
+
+    Ok(())
+}
+
+/// Extracts the archive into the target directory.  If the volume has include patterns then
+/// only those entries whose path matches one of them are written.
+pub(crate) fn extract_zip(file: File, volume: &VolumeInitializer, to: &Path) -> Result<()> {
     let mut archive = zip::ZipArchive::new(file).context(ZipArchive {
         name: volume.name.to_string(),
         volume_source: volume.source.to_string(),
     })?;
 
-    archive.extract(to).context(ExtractZip {
+    let patterns = match include_patterns(volume)? {
+        Some(p) => p,
+        None => {
+            return archive.extract(to).context(ExtractZip {
+                name: volume.name.to_string(),
+                volume_source: volume.source.to_string(),
+            });
+        }
+    };
+
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i).context(ExtractZip {
+            name: volume.name.to_string(),
+            volume_source: volume.source.to_string(),
+        })?;
+
+        let entry_path = match entry.enclosed_name() {
+            Some(p) if !entry.is_dir() => p.to_path_buf(),
+            _ => continue,
+        };
+
+        if !patterns.iter().any(|p| p.matches_path(&entry_path)) {
+            trace!("{} - skipping archive entry {:?}", module_path!(), &entry_path);
+            continue;
+        }
+
+        write_entry(&mut entry, &entry_path, to, volume)?;
+    }
+
+    Ok(())
+}
+
+/// Extracts an archive which is read forwards only, e.g. as it is downloaded, using the local
+/// header of each entry.  Archives written by streaming tools, which record the size of an
+/// entry after its data, can't be read this way.
+pub(crate) fn extract_zip_stream<R: Read>(
+    mut reader: R,
+    volume: &VolumeInitializer,
+    to: &Path,
+) -> Result<()> {
+    let patterns = include_patterns(volume)?;
+
+    while let Some(mut entry) = zip::read::read_zipfile_from_stream(&mut reader).context(ExtractZip {
         name: volume.name.to_string(),
         volume_source: volume.source.to_string(),
+    })? {
+        let entry_path = match entry.enclosed_name() {
+            Some(p) if !entry.is_dir() => p.to_path_buf(),
+            _ => continue,
+        };
+
+        if let Some(patterns) = &patterns {
+            if !patterns.iter().any(|p| p.matches_path(&entry_path)) {
+                trace!("{} - skipping archive entry {:?}", module_path!(), &entry_path);
+                continue;
+            }
+        }
+
+        write_entry(&mut entry, &entry_path, to, volume)?;
+    }
+
+    Ok(())
+}
+
+fn include_patterns(volume: &VolumeInitializer) -> Result<Option<Vec<Pattern>>> {
+    volume
+        .include
+        .as_ref()
+        .map(|include| {
+            include
+                .iter()
+                .map(|p| {
+                    Pattern::new(p).context(InvalidIncludePattern {
+                        name: volume.name.to_string(),
+                        pattern: p.to_string(),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()
+        })
+        .transpose()
+}
+
+fn write_entry(
+    entry: &mut impl Read,
+    entry_path: &Path,
+    to: &Path,
+    volume: &VolumeInitializer,
+) -> Result<()> {
+    let out_path = to.join(entry_path);
+    let entry_name = entry_path.to_string_lossy().to_string();
+
+    if let Some(parent) = out_path.parent() {
+        std::fs::create_dir_all(parent).context(ExtractEntry {
+            name: volume.name.to_string(),
+            entry: entry_name.to_string(),
+        })?;
+    }
+
+    let mut out = File::create(&out_path).context(ExtractEntry {
+        name: volume.name.to_string(),
+        entry: entry_name.to_string(),
+    })?;
+
+    std::io::copy(entry, &mut out).context(ExtractEntry {
+        name: volume.name.to_string(),
+        entry: entry_name,
     })?;
 
     Ok(())
 }
 
+/// Presents the chunks sent by an async task, e.g. an S3 download, as a blocking reader.
+struct ChunkReader<T> {
+    chunks: tokio::sync::mpsc::Receiver<T>,
+    current: Option<T>,
+    position: usize,
+}
+
+impl<T> ChunkReader<T> {
+    fn new(chunks: tokio::sync::mpsc::Receiver<T>) -> ChunkReader<T> {
+        ChunkReader {
+            chunks,
+            current: None,
+            position: 0,
+        }
+    }
+}
+
+impl<T: AsRef<[u8]>> Read for ChunkReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(chunk) = &self.current {
+                let remaining = &chunk.as_ref()[self.position..];
+
+                if !remaining.is_empty() {
+                    let n = remaining.len().min(buf.len());
+                    buf[..n].copy_from_slice(&remaining[..n]);
+                    self.position += n;
+                    return Ok(n);
+                }
+            }
+
+            match self.chunks.blocking_recv() {
+                Some(chunk) => {
+                    self.current = Some(chunk);
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
 fn target_dir_valid(dir: &str) -> bool {
     let path_buf = PathBuf::from(dir);
     let path = path_buf.as_path();
@@ -193,6 +716,105 @@ fn target_dir_valid(dir: &str) -> bool {
     dir_is_empty(path)
 }
 
+/// Whether a target directory may be cleared for re-extraction.  It must be strictly within the
+/// volume root, and clearing the home or current directory, or one of their parents, would be
+/// too destructive to be intended whatever the root.
+fn target_safe_to_clear(target: &Path, root: &Path, home: Option<&Path>, current: &Path) -> bool {
+    let protected = home.into_iter().chain(std::iter::once(current));
+
+    target != root
+        && target.starts_with(root)
+        && !protected.into_iter().any(|p| p.starts_with(target))
+}
+
+/// Removes the contents of the volume's target directory, keeping the directory itself as it
+/// may e.g. be a mount point.  The root is expected to be normalised already.
+fn clear_target_dir(volume: &VolumeInitializer, root: &Path) -> Result<()> {
+    let target = normalise_path(&volume.target);
+    let current = normalise_path(".");
+
+    if !target_safe_to_clear(&target, root, home::home_dir().as_deref(), &current) {
+        return Err(Error::UnsafeTarget {
+            name: volume.name.to_string(),
+            target: volume.target.to_string(),
+            root: root.display().to_string(),
+        });
+    }
+
+    let clear_error = || ClearTarget {
+        name: volume.name.to_string(),
+        target: volume.target.to_string(),
+    };
+
+    for entry in std::fs::read_dir(&target).context(clear_error())? {
+        let path = entry.context(clear_error())?.path();
+        match path.is_dir() && !path.is_symlink() {
+            true => std::fs::remove_dir_all(&path),
+            false => std::fs::remove_file(&path),
+        }
+        .context(clear_error())?;
+    }
+
+    Ok(())
+}
+
+/// Ensures that no two volumes extract into the same directory, or into a directory nested
+/// within another volume's target.
+fn check_overlapping_targets(vols: &[VolumeInitializer]) -> Result<()> {
+    let targets = vols
+        .iter()
+        .map(|v| normalise_path(&v.target))
+        .collect::<Vec<_>>();
+
+    for (i, a) in targets.iter().enumerate() {
+        for (j, b) in targets.iter().enumerate().skip(i + 1) {
+            if a.starts_with(b) || b.starts_with(a) {
+                return Err(Error::OverlappingTargets {
+                    first: vols[i].name.to_string(),
+                    first_target: vols[i].target.to_string(),
+                    second: vols[j].name.to_string(),
+                    second_target: vols[j].target.to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Produces an absolute path with any `.` or `..` components removed.  The targets may not
+/// exist yet, so the longest existing ancestor is canonicalized, resolving any symlinks, and
+/// the missing components are appended to it.  Paths which do and don't exist are then
+/// comparable.
+fn normalise_path(dir: &str) -> PathBuf {
+    let path = PathBuf::from(dir);
+
+    let path = match path.is_absolute() {
+        true => path,
+        false => std::env::current_dir().unwrap_or_default().join(path),
+    };
+
+    let (base, rest) = path
+        .ancestors()
+        .find_map(|a| {
+            let base = a.canonicalize().ok()?;
+            Some((base, path.strip_prefix(a).ok()?))
+        })
+        .unwrap_or((PathBuf::new(), &path));
+
+    rest.components()
+        .fold(base, |mut acc, c| {
+            match c {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    acc.pop();
+                }
+                _ => acc.push(c.as_os_str()),
+            };
+            acc
+        })
+}
+
 fn dir_is_empty(path: &Path) -> bool {
     path.read_dir().map_or(false, |mut i| i.next().is_none())
 }
@@ -219,15 +841,19 @@ fn parse_uri_as_bucket_and_key(path: &str) -> Result<S3Location> {
     extract_bucket_and_key(&uri)
 }
 
-async fn unzip_file_from_s3(volume: &VolumeInitializer) -> Result<()> {
-
+async fn s3_client() -> Client {
     let region_provider = RegionProviderChain::default_provider()
         .or_else(Region::new("us-east-1"));
     let shared_config = aws_config::defaults(aws_config::BehaviorVersion::latest())
                                                  .region(region_provider)
                                                  .load()
                                                  .await;
-    let client = Client::new(&shared_config);
+    Client::new(&shared_config)
+}
+
+/// Starts the download of the S3 object, giving its body along with its length if known.
+async fn get_s3_object(volume: &VolumeInitializer) -> Result<(s3::primitives::ByteStream, Option<u64>)> {
+    let client = s3_client().await;
 
     let s3loc = parse_uri_as_bucket_and_key(&volume.source)?;
 
@@ -237,8 +863,6 @@ async fn unzip_file_from_s3(volume: &VolumeInitializer) -> Result<()> {
         &s3loc
     );
 
-    let mut file = tempfile::tempfile().context(CreateTmpFile)?;
-
     let resp = client
         .get_object()
         .bucket(&s3loc.bucket)
@@ -249,11 +873,176 @@ async fn unzip_file_from_s3(volume: &VolumeInitializer) -> Result<()> {
 
     debug!("{} - got s3 object resp {:?}", module_path!(), &resp);
 
-    let mut data = resp.body;
+    let length = resp.content_length().and_then(|l| u64::try_from(l).ok());
+    Ok((resp.body, length))
+}
+
+/// A progress bar of the bytes downloaded for a volume and the transfer rate, or a spinner when
+/// the total isn't known.  It is hidden unless progress is to be shown.
+fn download_progress(volume: &VolumeInitializer, length: Option<u64>, progress: bool) -> ProgressBar {
+    if !progress {
+        return ProgressBar::hidden();
+    }
+
+    let (bar, template) = match length {
+        Some(l) => (
+            ProgressBar::new(l),
+            "{msg} [{bar:30}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+        ),
+        None => (ProgressBar::new_spinner(), "{spinner} {msg} {bytes} ({bytes_per_sec})"),
+    };
+
+    let style = ProgressStyle::with_template(template)
+        .expect("Internal error: invalid progress bar template")
+        .progress_chars("=> ");
+
+    bar.set_style(style);
+    bar.set_message(volume.name.to_string());
+    bar
+}
+
+/// The number of downloaded chunks which may be waiting to be extracted.
+const STREAM_BUFFER_CHUNKS: usize = 16;
+
+/// Extracts the archive on a blocking thread as its chunks are downloaded, so that it never
+/// needs to be stored as a whole.
+async fn stream_zip_from_s3(volume: &VolumeInitializer) -> Result<()> {
+    let (mut data, _) = get_s3_object(volume).await?;
+
+    let (sender, receiver) = tokio::sync::mpsc::channel(STREAM_BUFFER_CHUNKS);
+
+    let extract_volume = volume.clone();
+    let extraction = tokio::task::spawn_blocking(move || {
+        let target_path = PathBuf::from(&extract_volume.target);
+        extract_archive_stream(ChunkReader::new(receiver), &extract_volume, &target_path)
+    });
+
+    while let Some(bytes) = data.try_next().await.context(S3GetBytes)? {
+        // The extraction has stopped, either at the end of the archive or with an error.
+        if sender.send(bytes).await.is_err() {
+            break;
+        }
+    }
+
+    drop(sender);
+
+    extraction.await.context(ExtractTask {
+        name: volume.name.to_string(),
+    })?
+}
+
+/// The cached copy of an S3 archive, named after a hash of its bucket and key followed by its
+/// ETag, so that a changed archive is downloaded again.
+fn cache_file(dir: &Path, location: &S3Location, etag: &str) -> PathBuf {
+    let key = Sha256::digest(format!("{}/{}", location.bucket, location.key).as_bytes());
+    let etag = etag.chars().filter(|c| c.is_ascii_alphanumeric() || *c == '-').collect::<String>();
+
+    dir.join(format!("{:x}-{}", key, etag))
+}
+
+/// Finds where the current version of the volume's S3 archive is, or would be, cached.  The
+/// cache is only an optimisation, so None is given should the ETag not be available.
+async fn cached_archive(dir: &Path, volume: &VolumeInitializer) -> Option<PathBuf> {
+    let location = parse_uri_as_bucket_and_key(&volume.source).ok()?;
+
+    let head = s3_client()
+        .await
+        .head_object()
+        .bucket(&location.bucket)
+        .key(&location.key)
+        .send()
+        .await;
+
+    match head {
+        Ok(h) => h.e_tag().map(|etag| cache_file(dir, &location, etag)),
+        Err(e) => {
+            warn!("{} - Unable to get the ETag of {}, it won't be cached: {}", module_path!(), &volume.source, e);
+            None
+        }
+    }
+}
+
+/// Copies a downloaded archive into the cache, replacing any earlier version of it.  Failures
+/// are only logged as the archive can still be extracted.
+fn store_in_cache(file: &mut File, path: &Path) {
+    let mut store = || -> std::io::Result<()> {
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        std::fs::create_dir_all(dir)?;
+
+        let mut cached = tempfile::NamedTempFile::new_in(dir)?;
+        file.rewind()?;
+        std::io::copy(file, &mut cached)?;
+        cached.persist(path).map_err(|e| e.error)?;
+
+        // Earlier versions of the archive share the prefix of the hashed bucket and key.
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let prefix = name.split('-').next().unwrap_or_default();
+        for entry in std::fs::read_dir(dir)?.flatten() {
+            let other = entry.file_name().to_string_lossy().to_string();
+            if other != name && other.starts_with(&format!("{}-", prefix)) {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+
+        Ok(())
+    };
+
+    match store() {
+        Ok(()) => info!("{} - Cached the archive at {}", module_path!(), path.display()),
+        Err(e) => warn!("{} - Unable to cache the archive at {}: {}", module_path!(), path.display(), e),
+    }
+}
+
+async fn unzip_file_from_s3(volume: &VolumeInitializer, options: &VolumeOptions) -> Result<()> {
+    let target_path = PathBuf::from(&volume.target);
+
+    let cached = match &options.cache_dir {
+        Some(dir) => cached_archive(dir, volume).await,
+        None => None,
+    };
+
+    if let Some(path) = cached.as_ref().filter(|p| p.is_file()) {
+        println!("Using the cached archive of volume: {}", &volume.name);
+        info!("{} - Extracting {} from the cache at {}", module_path!(), &volume.source, path.display());
+
+        let mut file = File::open(path).context(OpenArchive {
+            file: path.display().to_string(),
+        })?;
+        verify_checksum(&mut file, volume)?;
+        return extract_archive(file, volume, &target_path);
+    }
+
+    // A streamed archive would already be extracted by the time its checksum is known.
+    if volume.stream && volume.sha256.is_some() {
+        info!(
+            "{} - Downloading volume: {} before extracting it to verify its sha256",
+            module_path!(),
+            &volume.name
+        );
+    } else if volume.stream {
+        match stream_zip_from_s3(volume).await {
+            Ok(()) => {
+                info!("{} - Extracted {} from {} as it was downloaded", module_path!(), &volume.name, &volume.source);
+                return Ok(());
+            }
+            Err(e) => warn!(
+                "{} - Unable to extract volume: {} as it was downloaded, downloading it first instead.\n{}",
+                module_path!(),
+                &volume.name,
+                e
+            ),
+        }
+    }
+
+    let mut file = tempfile::tempfile().context(CreateTmpFile)?;
+
+    let (mut data, length) = get_s3_object(volume).await?;
+    let bar = download_progress(volume, length, options.progress);
 
     let mut bytes_downloaded: usize = 0;
     while let Some(bytes) = data.try_next().await.context(S3GetBytes)? {
         bytes_downloaded += bytes.len();
+        bar.inc(bytes.len() as u64);
         trace!(
             "{} - got {} bytes from source {}",
             module_path!(),
@@ -268,7 +1057,6 @@ async fn unzip_file_from_s3(volume: &VolumeInitializer) -> Result<()> {
                     bytes_downloaded,
                     &volume.source
                 );
-                //print!(".")
             }
             Err(e) => {
                 error!(
@@ -281,26 +1069,24 @@ async fn unzip_file_from_s3(volume: &VolumeInitializer) -> Result<()> {
         }
     }
 
+    bar.finish_and_clear();
+
     info!(
         "\nDownloaded {:?} bytes for {} from {}",
         bytes_downloaded, &volume.name, &volume.source
     );
 
-    let file = file;
+    verify_checksum(&mut file, volume)?;
 
-    let mut archive = zip::ZipArchive::new(file).context(ZipArchive {
-        name: volume.name.to_string(),
-        volume_source: volume.source.to_string(),
-    })?;
+    if let Some(path) = &cached {
+        store_in_cache(&mut file, path);
+    }
 
-    let target_path = PathBuf::from(&volume.target);
-    archive.extract(target_path).context(ExtractZip {
-        name: volume.name.to_string(),
-        volume_source: volume.source.to_string(),
-    })?;
+    file.rewind().context(CreateTmpFile)?;
+    extract_archive(file, volume, &target_path)?;
 
     info!(
-        "\n{} - Extracted zip file of {:?} bytes from {} to {}", module_path!(),
+        "\n{} - Extracted archive of {:?} bytes from {} to {}", module_path!(),
         bytes_downloaded,
         &volume.source,
         &volume.target
@@ -308,3 +1094,453 @@ async fn unzip_file_from_s3(volume: &VolumeInitializer) -> Result<()> {
 
     Ok(())
 }
+
+/// Downloads the archive to a temporary file, as for S3, and extracts it from there.
+async fn unzip_file_from_http(volume: &VolumeInitializer, progress: bool) -> Result<()> {
+    let download_error = || HttpDownload {
+        name: volume.name.to_string(),
+        url: volume.source.to_string(),
+    };
+
+    let mut response = reqwest::get(&volume.source).await.context(download_error())?;
+
+    if !response.status().is_success() {
+        return Err(Error::HttpDownloadStatus {
+            name: volume.name.to_string(),
+            url: volume.source.to_string(),
+            status: response.status(),
+        });
+    }
+
+    let mut file = tempfile::tempfile().context(CreateTmpFile)?;
+    let bar = download_progress(volume, response.content_length(), progress);
+    let mut bytes_downloaded: usize = 0;
+
+    while let Some(bytes) = response.chunk().await.context(download_error())? {
+        bytes_downloaded += bytes.len();
+        bar.inc(bytes.len() as u64);
+        file.write_all(&bytes).context(WriteTmpFile)?;
+    }
+
+    bar.finish_and_clear();
+
+    info!(
+        "Downloaded {:?} bytes for {} from {}",
+        bytes_downloaded, &volume.name, &volume.source
+    );
+
+    verify_checksum(&mut file, volume)?;
+    file.rewind().context(CreateTmpFile)?;
+    extract_archive(file, volume, &PathBuf::from(&volume.target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn volume(name: &str, target: &str) -> VolumeInitializer {
+        VolumeInitializer {
+            name: name.to_string(),
+            source: format!("/tmp/{}.zip", name),
+            target: target.to_string(),
+            include: None,
+            stream: false,
+            sha256: None,
+        }
+    }
+
+    #[test]
+    fn test_overlapping_targets() {
+        let vols = [
+            volume("a", "/tmp/handel-test/data"),
+            volume("b", "/tmp/handel-test/other"),
+            volume("c", "/tmp/handel-test/other/../data/mysql"),
+        ];
+
+        match check_overlapping_targets(&vols) {
+            Err(Error::OverlappingTargets { first, second, .. }) => {
+                assert_eq!("a", first);
+                assert_eq!("c", second);
+            }
+            r => panic!("Expected overlapping targets error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_overlapping_targets_through_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("real");
+        std::fs::create_dir_all(real.join("data")).unwrap();
+        std::os::unix::fs::symlink(&real, dir.path().join("link")).unwrap();
+
+        let at = |p: &str| dir.path().join(p).to_string_lossy().to_string();
+
+        // An existing target compared with a missing one reached through a symlink.
+        let vols = [volume("a", &at("real/data")), volume("b", &at("link/data/mysql"))];
+        assert!(matches!(
+            check_overlapping_targets(&vols),
+            Err(Error::OverlappingTargets { .. })
+        ));
+
+        // And through a missing directory and `..` within the symlinked one.
+        let vols = [volume("a", &at("link/missing/../data")), volume("b", &at("real/data"))];
+        assert!(matches!(
+            check_overlapping_targets(&vols),
+            Err(Error::OverlappingTargets { .. })
+        ));
+
+        let vols = [volume("a", &at("link/data/mysql")), volume("b", &at("real/other"))];
+        assert!(check_overlapping_targets(&vols).is_ok());
+    }
+
+    #[test]
+    fn test_unseeded_named_volumes() {
+        let frag: crate::templates::ComposeServiceFragment = serde_yaml::from_str(
+            r#"
+image: postgres
+volumes:
+  - pgdata:/var/lib/postgresql/data
+  - cache:/cache
+  - ./init:/docker-entrypoint-initdb.d
+  - /var/run/docker.sock:/var/run/docker.sock
+  - /anonymous
+"#,
+        )
+        .unwrap();
+        let db = ComposeService::new("db", "postgres", &frag);
+
+        let unseeded = unseeded_named_volumes(&[&db], &Some(vec![volume("cache", "/tmp/handel-test/cache")]));
+
+        assert_eq!(
+            vec![("db".to_string(), "pgdata".to_string())],
+            unseeded.iter().map(|(s, v)| (s.name(), v.clone())).collect::<Vec<_>>()
+        );
+        assert_eq!(2, unseeded_named_volumes(&[&db], &None).len());
+    }
+
+    #[test]
+    fn test_distinct_targets() {
+        let vols = [
+            volume("a", "/tmp/handel-test/data"),
+            volume("b", "/tmp/handel-test/data-b"),
+        ];
+
+        assert!(check_overlapping_targets(&vols).is_ok());
+    }
+
+    #[test]
+    fn test_extract_included_entries_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive_path = dir.path().join("mixed.zip");
+
+        {
+            let mut zip = zip::ZipWriter::new(File::create(&archive_path).unwrap());
+            let options = zip::write::FileOptions::default();
+            for (name, contents) in [
+                ("schema.sql", "create table a;"),
+                ("ReadMe.txt", "not wanted"),
+                ("dumps/data.sql", "insert into a;"),
+                ("dumps/image.png", "not wanted"),
+            ] {
+                zip.start_file(name, options).unwrap();
+                zip.write_all(contents.as_bytes()).unwrap();
+            }
+            zip.finish().unwrap();
+        }
+
+        let target = dir.path().join("target");
+        let volume = VolumeInitializer {
+            source: archive_path.to_string_lossy().to_string(),
+            include: Some(vec!["*.sql".to_string()]),
+            ..volume("mixed", &target.to_string_lossy())
+        };
+
+        unzip_local_file(&volume).unwrap();
+
+        assert!(target.join("schema.sql").exists());
+        assert!(target.join("dumps/data.sql").exists());
+        assert!(!target.join("ReadMe.txt").exists());
+        assert!(!target.join("dumps/image.png").exists());
+    }
+
+    #[test]
+    fn test_extract_tar_gz() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive_path = dir.path().join("seed.tar.gz");
+
+        {
+            let encoder = flate2::write::GzEncoder::new(
+                File::create(&archive_path).unwrap(),
+                flate2::Compression::default(),
+            );
+            let mut tar = tar::Builder::new(encoder);
+            for (name, contents) in [
+                ("schema.sql", "create table a;"),
+                ("ReadMe.txt", "not wanted"),
+                ("dumps/data.sql", "insert into a;"),
+            ] {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(contents.len() as u64);
+                header.set_mode(0o644);
+                header.set_cksum();
+                tar.append_data(&mut header, name, contents.as_bytes()).unwrap();
+            }
+            tar.into_inner().unwrap().finish().unwrap();
+        }
+
+        let target = dir.path().join("target");
+        let volume = VolumeInitializer {
+            source: archive_path.to_string_lossy().to_string(),
+            include: Some(vec!["*.sql".to_string()]),
+            ..volume("seed", &target.to_string_lossy())
+        };
+
+        unzip_local_file(&volume).unwrap();
+
+        assert_eq!("create table a;", std::fs::read_to_string(target.join("schema.sql")).unwrap());
+        assert!(target.join("dumps/data.sql").exists());
+        assert!(!target.join("ReadMe.txt").exists());
+
+        assert_eq!(ArchiveKind::TarGz, ArchiveKind::from_source("s3://bucket/SEED.TGZ"));
+        assert_eq!(ArchiveKind::Tar, ArchiveKind::from_source("/srv/seed.tar"));
+        assert_eq!(ArchiveKind::Zip, ArchiveKind::from_source("/srv/seed.zip"));
+    }
+
+    #[test]
+    fn test_clear_target_dir() {
+        let home = Path::new("/home/dev");
+        let current = Path::new("/home/dev/project");
+
+        assert!(target_safe_to_clear(Path::new("/home/dev/project/data/db"), current, Some(home), current));
+        assert!(target_safe_to_clear(Path::new("/srv/volumes/db"), Path::new("/srv/volumes"), Some(home), current));
+        assert!(!target_safe_to_clear(Path::new("/srv/volumes"), Path::new("/srv/volumes"), Some(home), current));
+        assert!(!target_safe_to_clear(Path::new("/srv/other/db"), Path::new("/srv/volumes"), Some(home), current));
+        assert!(!target_safe_to_clear(Path::new("/etc"), current, Some(home), current));
+        assert!(!target_safe_to_clear(Path::new("/var/lib"), current, Some(home), current));
+        assert!(!target_safe_to_clear(Path::new("/"), current, Some(home), current));
+        assert!(!target_safe_to_clear(Path::new("/home/dev"), Path::new("/"), None, current));
+        assert!(!target_safe_to_clear(Path::new("/home/dev/project"), Path::new("/home"), Some(home), current));
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = normalise_path(&dir.path().to_string_lossy());
+        let target = dir.path().join("target");
+        std::fs::create_dir_all(target.join("nested")).unwrap();
+        std::fs::write(target.join("old.sql"), "stale").unwrap();
+        std::fs::write(target.join("nested/old.sql"), "stale").unwrap();
+
+        clear_target_dir(&volume("db", &target.to_string_lossy()), &root).unwrap();
+        assert!(target.exists());
+        assert!(dir_is_empty(&target));
+
+        let outside = volume("db", "/etc");
+        assert!(matches!(clear_target_dir(&outside, &root), Err(Error::UnsafeTarget { .. })));
+    }
+
+    #[test]
+    fn test_download_progress() {
+        let seed = volume("seed", "/tmp/seed");
+
+        assert!(download_progress(&seed, Some(1024), false).is_hidden());
+
+        let bar = download_progress(&seed, Some(1024), true);
+        assert_eq!(Some(1024), bar.length());
+        assert_eq!("seed", bar.message());
+
+        assert_eq!(None, download_progress(&seed, None, true).length());
+    }
+
+    #[test]
+    fn test_volume_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = parse_uri_as_bucket_and_key("s3://bucket/volume/seed.zip").unwrap();
+
+        let first = cache_file(dir.path(), &location, "\"abc123\"");
+        let second = cache_file(dir.path(), &location, "\"def456-2\"");
+        let other = cache_file(dir.path(), &parse_uri_as_bucket_and_key("s3://bucket/other.zip").unwrap(), "\"abc123\"");
+        assert!(first.file_name().unwrap().to_string_lossy().ends_with("-abc123"));
+        assert!(second.file_name().unwrap().to_string_lossy().ends_with("-def456-2"));
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"archive").unwrap();
+
+        store_in_cache(&mut file, &first);
+        store_in_cache(&mut file, &other);
+        assert_eq!("archive", std::fs::read_to_string(&first).unwrap());
+
+        // A new version of the archive replaces the old one, but not those of other archives.
+        store_in_cache(&mut file, &second);
+        assert!(!first.exists());
+        assert!(second.exists());
+        assert!(other.exists());
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"hello").unwrap();
+
+        let mut volume = volume("seed", "/tmp/seed");
+        assert!(verify_checksum(&mut file, &volume).is_ok());
+
+        volume.sha256 = Some("2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824".to_string());
+        assert!(verify_checksum(&mut file, &volume).is_ok());
+        assert_eq!(0, file.stream_position().unwrap());
+
+        volume.sha256 = Some("0".repeat(64));
+        match verify_checksum(&mut file, &volume) {
+            Err(Error::ChecksumMismatch { actual, .. }) => assert_eq!(
+                "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+                actual
+            ),
+            r => panic!("Expected a checksum mismatch, got {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unzip_file_from_http() {
+        let mut archive = Vec::new();
+        {
+            let mut tar = tar::Builder::new(&mut archive);
+            let contents = "create table a;";
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, "schema.sql", contents.as_bytes()).unwrap();
+            tar.finish().unwrap();
+        }
+
+        // Serves the archive, or a 404 for any other path, to each connection.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0u8; 1024];
+                let read = stream.read(&mut request).unwrap();
+                let request = String::from_utf8_lossy(&request[..read]);
+
+                let response = match request.starts_with("GET /seed.tar?sig=abc ") {
+                    true => [
+                        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", archive.len())
+                            .into_bytes(),
+                        archive.clone(),
+                    ]
+                    .concat(),
+                    false => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec(),
+                };
+                stream.write_all(&response).unwrap();
+            }
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        let mut volume = VolumeInitializer {
+            source: format!("http://{}/seed.tar?sig=abc", address),
+            ..volume("seed", &target.to_string_lossy())
+        };
+
+        unzip_file_from_http(&volume, false).await.unwrap();
+        assert_eq!("create table a;", std::fs::read_to_string(target.join("schema.sql")).unwrap());
+
+        volume.source = format!("http://{}/missing.tar", address);
+        assert!(matches!(
+            unzip_file_from_http(&volume, false).await,
+            Err(Error::HttpDownloadStatus { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_extract_zip_stream() {
+        let mut archive = std::io::Cursor::new(Vec::new());
+        {
+            let mut zip = zip::ZipWriter::new(&mut archive);
+            let options = zip::write::FileOptions::default();
+            for (name, contents) in [
+                ("schema.sql", "create table a;"),
+                ("ReadMe.txt", "not wanted"),
+                ("dumps/data.sql", "insert into a;"),
+            ] {
+                zip.start_file(name, options).unwrap();
+                zip.write_all(contents.as_bytes()).unwrap();
+            }
+            zip.finish().unwrap();
+        }
+
+        // Deliver the archive in small chunks, as a download would.
+        let (sender, receiver) = tokio::sync::mpsc::channel(1024);
+        for chunk in archive.get_ref().chunks(7) {
+            sender.send(chunk.to_vec()).await.unwrap();
+        }
+        drop(sender);
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("target");
+        let volume = VolumeInitializer {
+            source: "s3://bucket/streamed.zip".to_string(),
+            include: Some(vec!["*.sql".to_string()]),
+            stream: true,
+            ..volume("streamed", &target.to_string_lossy())
+        };
+
+        let extract_target = target.clone();
+        tokio::task::spawn_blocking(move || {
+            extract_zip_stream(ChunkReader::new(receiver), &volume, &extract_target)
+        })
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(
+            "insert into a;",
+            std::fs::read_to_string(target.join("dumps/data.sql")).unwrap()
+        );
+        assert!(target.join("schema.sql").exists());
+        assert!(!target.join("ReadMe.txt").exists());
+    }
+
+    #[test]
+    fn test_expand_handel_variables() {
+        let volumes = Some(vec![volume("a", "/tmp/handel-test/${HANDEL_ENV}/data")]);
+        let mut options = VolumeOptions::default();
+        options.variables.insert("HANDEL_ENV".to_string(), "staging".to_string());
+
+        let expanded = expand_volumes(&volumes, &options).unwrap();
+
+        assert_eq!("/tmp/handel-test/staging/data", expanded[0].target);
+    }
+
+    #[test]
+    fn test_expand_unresolved_variable() {
+        let volumes = Some(vec![volume("a", "/tmp/${HANDEL_TEST_UNSET_VARIABLE}/data")]);
+
+        let expanded = expand_volumes(&volumes, &VolumeOptions::default()).unwrap();
+        assert!(expanded.is_empty());
+
+        let options = VolumeOptions {
+            strict: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            expand_volumes(&volumes, &options),
+            Err(Error::UnresolvedVariable { .. })
+        ));
+    }
+
+    #[test]
+    fn test_expand_from_env_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_file = dir.path().join("handel.env");
+        std::fs::write(&env_file, "HANDEL_TEST_ENV_FILE_SRC=/srv/archives\n").unwrap();
+
+        crate::utils::load_env_file(&env_file, false).unwrap();
+
+        let mut v = volume("a", "/tmp/handel-test/a");
+        v.source = "${HANDEL_TEST_ENV_FILE_SRC}/a.zip".to_string();
+
+        let expanded = expand_volumes(&Some(vec![v]), &VolumeOptions::default()).unwrap();
+
+        assert_eq!("/srv/archives/a.zip", expanded[0].source);
+    }
+}
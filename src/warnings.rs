@@ -0,0 +1,167 @@
+use serde::Serialize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Controls whether warnings are additionally emitted in a machine readable form.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WarningFormat {
+    #[default]
+    Human,
+    Github,
+}
+
+/// The kind of problem a warning reports, for aggregating warnings across runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Category {
+    PortConflict,
+    MissingDependency,
+    DependencyCycle,
+    Template,
+    DefaultVersion,
+    Reference,
+    LocalImages,
+    Volume,
+    Unsupported,
+    Vcs,
+    Config,
+}
+
+/// Where a warning arose, when known.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WarningContext {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Warning {
+    pub category: Category,
+    pub message: String,
+    pub context: WarningContext,
+}
+
+/// Collects the warnings of a run, written out by `--warnings-file`, emitting each as it is
+/// recorded.  Clones share the collected warnings, so one can be handed to each phase of the run.
+#[derive(Debug, Clone, Default)]
+pub struct Warnings {
+    format: WarningFormat,
+    collected: Arc<Mutex<Vec<Warning>>>,
+}
+
+impl Warnings {
+    pub fn new(format: WarningFormat) -> Warnings {
+        Warnings {
+            format,
+            collected: Default::default(),
+        }
+    }
+
+    /// Prints a warning to stderr, emitting a GitHub Actions `::warning` workflow command for it
+    /// as well when that format is enabled, and records it for `--warnings-file`.
+    pub fn warn(&self, category: Category, message: &str, file: Option<&str>, line: Option<usize>) {
+        eprintln!("{}", highlight(category, message, crate::colors::stderr_enabled()));
+
+        if self.format == WarningFormat::Github {
+            println!("{}", github_annotation(message, file, line));
+        }
+
+        if let Ok(mut collected) = self.collected.lock() {
+            collected.push(Warning {
+                category,
+                message: message.to_string(),
+                context: WarningContext {
+                    file: file.map(|f| f.to_string()),
+                    line,
+                },
+            });
+        }
+    }
+
+    pub fn collected(&self) -> Vec<Warning> {
+        self.collected.lock().map(|c| c.clone()).unwrap_or_default()
+    }
+
+    /// Writes the warnings recorded so far to the given file as a JSON array.
+    pub fn write_file(&self, path: &Path) -> Result<(), crate::utils::Error> {
+        let json = serde_json::to_string_pretty(&self.collected())
+            .expect("Internal error: warnings are always serialisable");
+        crate::utils::write_str_to_file(path, &json)
+    }
+}
+
+/// Port conflicts are highlighted in red and every other warning in yellow.
+fn highlight(category: Category, message: &str, colored: bool) -> String {
+    let message = format!("Warning - {}", message);
+
+    match category {
+        Category::PortConflict => crate::colors::paint_conflict(&message, colored),
+        _ => crate::colors::paint_warning(&message, colored),
+    }
+}
+
+fn github_annotation(message: &str, file: Option<&str>, line: Option<usize>) -> String {
+    let mut properties = Vec::new();
+
+    if let Some(f) = file {
+        properties.push(format!("file={}", escape_property(f)));
+        if let Some(l) = line {
+            properties.push(format!("line={}", l));
+        }
+    }
+
+    match properties.is_empty() {
+        true => format!("::warning::{}", escape_data(message)),
+        false => format!("::warning {}::{}", properties.join(","), escape_data(message)),
+    }
+}
+
+fn escape_data(s: &str) -> String {
+    s.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn escape_property(s: &str) -> String {
+    escape_data(s).replace(':', "%3A").replace(',', "%2C")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highlight_by_category() {
+        assert_eq!(
+            "Warning - Host port 9092 conflicts between: kafka, other",
+            highlight(Category::PortConflict, "Host port 9092 conflicts between: kafka, other", false)
+        );
+
+        let conflict = highlight(Category::PortConflict, "conflict", true);
+        let volume = highlight(Category::Volume, "unseeded", true);
+        assert!(conflict.starts_with("\u{1b}[31m"));
+        assert!(volume.starts_with("\u{1b}[33m"));
+    }
+
+    #[test]
+    fn test_plain_annotation() {
+        assert_eq!(
+            "::warning::Unable to read local images%0Adocker not found",
+            github_annotation("Unable to read local images\ndocker not found", None, None)
+        );
+    }
+
+    #[test]
+    fn test_file_annotation() {
+        assert_eq!(
+            "::warning file=data/templates/kafka.yml,line=3::Host port 9092 conflicts with: kafka, other",
+            github_annotation(
+                "Host port 9092 conflicts with: kafka, other",
+                Some("data/templates/kafka.yml"),
+                Some(3)
+            )
+        );
+    }
+}